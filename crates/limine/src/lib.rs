@@ -211,8 +211,10 @@ loose_enum! {
         /// The memory region is reserved and cannot be used.
         const RESERVED = 1;
 
-        // Not sure what this is.
+        /// The memory region stores ACPI tables. It is usable once those tables are no longer
+        /// needed.
         const ACPI_RECLAIMABLE = 2;
+        /// The memory region is used by the firmware and must be preserved.
         const ACPI_NVS = 3;
 
         /// The memory region is not usable.
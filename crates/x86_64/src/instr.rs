@@ -120,6 +120,17 @@ pub fn sti() {
     }
 }
 
+/// Enables interrupts and halts the CPU until the next interrupt is received.
+///
+/// Because the STI instruction only takes effect after the instruction that follows it, no
+/// interrupt can be missed between enabling interrupts and halting the CPU.
+#[inline]
+pub fn sti_hlt() {
+    unsafe {
+        asm!("sti", "hlt", options(nomem, nostack));
+    }
+}
+
 /// Writes a byte to the provided I/O port.
 ///
 /// # Safety
@@ -264,3 +275,37 @@ pub fn invlpg(addr: VirtAddr) {
         );
     }
 }
+
+/// Executes the STAC instruction, allowing the kernel to access userspace memory when SMAP is
+/// enabled.
+///
+/// # Safety
+///
+/// The CPU must support SMAP. Otherwise, the instruction is undefined.
+#[inline]
+pub unsafe fn stac() {
+    unsafe {
+        asm!("stac", options(nomem, nostack));
+    }
+}
+
+/// Executes the CLAC instruction, preventing the kernel from accessing userspace memory when
+/// SMAP is enabled.
+///
+/// # Safety
+///
+/// The CPU must support SMAP. Otherwise, the instruction is undefined.
+#[inline]
+pub unsafe fn clac() {
+    unsafe {
+        asm!("clac", options(nomem, nostack));
+    }
+}
+
+pub use core::arch::x86_64::CpuidResult;
+
+/// Executes the CPUID instruction with the provided leaf and sub-leaf.
+#[inline]
+pub fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid_count(leaf, sub_leaf) }
+}
@@ -101,6 +101,16 @@ bitflags! {
         /// Whether the page can be accessed by code running at ring 3.
         const USER_ACCESSIBLE = 1 << 2;
 
+        /// The first bit used to select the memory type of the page in the PAT.
+        ///
+        /// With the default PAT, this makes the page write-through.
+        const WRITE_THROUGH = 1 << 3;
+
+        /// The second bit used to select the memory type of the page in the PAT.
+        ///
+        /// With the default PAT, this makes the page uncached.
+        const CACHE_DISABLE = 1 << 4;
+
         /// Whether the page can be executed.
         const NO_EXECUTE = 1 << 63;
 
@@ -108,13 +108,43 @@ pub const LSTAR: u32 = 0xC000_0082;
 /// The STAR MSR address.
 pub const STAR: u32 = 0xC000_0081;
 
+/// The FS_BASE MSR address.
+///
+/// This MSR holds the base address of the FS segment.
+pub const FS_BASE: u32 = 0xC000_0100;
+
+/// The GS_BASE MSR address.
+///
+/// This MSR holds the base address of the GS segment.
+pub const GS_BASE: u32 = 0xC000_0101;
+
+/// The PAT MSR address.
+///
+/// This MSR holds the eight memory types that page table entries can select with their
+/// `WRITE_THROUGH` and `CACHE_DISABLE` bits (and the PAT bit, for leaf entries).
+pub const PAT: u32 = 0x277;
+
+/// The SFMASK MSR address.
+///
+/// The bits set in this MSR are cleared from the RFLAGS register when the **SYSCALL**
+/// instruction is executed.
+pub const SFMASK: u32 = 0xC000_0084;
+
 bitflags! {
     /// The RFLAGS registers.
     #[derive(Default, Debug, Clone, Copy)]
     #[repr(transparent)]
     pub struct RFlags: u64 {
+        /// Whether single-step mode is enabled.
+        const TRAP = 1 << 8;
         /// Whether the CPU is currently able to receive hardware interrupts.
         const INTERRUPTS = 1 << 9;
+        /// Whether string instructions decrement their index registers.
+        const DIRECTION = 1 << 10;
+        /// Whether alignment checking is enabled in userspace.
+        ///
+        /// When SMAP is enabled, this flag also allows the kernel to access userspace memory.
+        const ALIGNMENT_CHECK = 1 << 18;
     }
 }
 
@@ -144,6 +174,51 @@ impl RFlags {
     }
 }
 
+bitflags! {
+    /// The flags of the CR4 register.
+    #[derive(Default, Debug, Clone, Copy)]
+    #[repr(transparent)]
+    pub struct Cr4: u64 {
+        /// Enables the physical address extension. This is always set in long mode.
+        const PAE = 1 << 5;
+        /// Enables global pages.
+        const GLOBAL_PAGES = 1 << 7;
+        /// Prevents userspace from executing the SGDT, SIDT, SLDT, SMSW and STR instructions.
+        const UMIP = 1 << 11;
+        /// Prevents the kernel from executing code located in userspace pages.
+        const SMEP = 1 << 20;
+        /// Prevents the kernel from accessing userspace pages, unless the `ALIGNMENT_CHECK` flag
+        /// of the RFLAGS register is set.
+        const SMAP = 1 << 21;
+    }
+}
+
+impl Cr4 {
+    /// Reads the content of the CR4 register.
+    #[inline]
+    pub fn read() -> Self {
+        let r: u64;
+
+        unsafe {
+            asm!("mov {}, cr4", out(reg) r, options(nomem, nostack, preserves_flags));
+        }
+
+        Self::from_bits_retain(r)
+    }
+
+    /// Writes to the CR4 register.
+    ///
+    /// # Safety
+    ///
+    /// Writing arbitrary values to the CR4 register can compromise memory safety.
+    #[inline]
+    pub unsafe fn write(self) {
+        unsafe {
+            asm!("mov cr4, {}", in(reg) self.bits(), options(nostack, preserves_flags));
+        }
+    }
+}
+
 /// Reads the content of the CR2 register.
 #[inline]
 pub fn read_cr2() -> u64 {
@@ -155,3 +230,28 @@ pub fn read_cr2() -> u64 {
 
     cr2
 }
+
+/// Reads the content of the CR3 register.
+#[inline]
+pub fn read_cr3() -> u64 {
+    let cr3: u64;
+
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+
+    cr3
+}
+
+/// Writes to the CR3 register, switching the active address space.
+///
+/// # Safety
+///
+/// The provided physical address must point to a valid L4 page table that maps the code
+/// currently being executed.
+#[inline]
+pub unsafe fn write_cr3(cr3: u64) {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    }
+}
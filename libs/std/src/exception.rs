@@ -0,0 +1,56 @@
+//! Userspace exception handling.
+//!
+//! A process may register an exception handler that's invoked by the kernel when one of its
+//! threads triggers a page fault, a breakpoint or an arithmetic fault. The handler receives the
+//! state of the thread and may fix things up before resuming it with [`resume`].
+
+use sys::SysResult;
+
+pub use sys::{Context, Exception, ExceptionContext};
+
+use crate::Result;
+
+/// The signature of an exception handler.
+///
+/// The handler receives the context of the thread that triggered the exception. It must never
+/// return; instead, it should either call [`resume`] or terminate the thread.
+pub type Handler = extern "C" fn(&mut ExceptionContext) -> !;
+
+/// Registers the exception handler of the current process, along with the stack on which the
+/// current thread runs it.
+///
+/// See [`sys::set_exception_handler`] for more information.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of a memory region that's reserved for the exception handler, and
+/// that's large enough for it to run. The kernel writes to that region whenever an exception is
+/// delivered.
+pub unsafe fn set_handler(handler: Handler, stack_top: *mut u8) -> Result<()> {
+    match sys::set_exception_handler(handler as usize, stack_top) {
+        SysResult::SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Unregisters the exception handler of the current process, if any, as well as the handler
+/// stack of the current thread.
+///
+/// Exceptions triggered after this function returns terminate the process.
+pub fn clear_handler() -> Result<()> {
+    match sys::set_exception_handler(0, core::ptr::null_mut()) {
+        SysResult::SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Resumes the current thread with the provided state.
+///
+/// # Returns
+///
+/// This function only returns if the kernel rejected the provided context. See
+/// [`sys::resume_context`] for more information.
+#[inline]
+pub fn resume(context: &Context) -> SysResult {
+    sys::resume_context(context)
+}
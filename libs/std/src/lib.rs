@@ -7,22 +7,38 @@ pub use sys::SysResult;
 /// The result type of the crate.
 pub type Result<T> = core::result::Result<T, SysResult>;
 
+#[cfg(feature = "exception")]
+pub mod exception;
 #[cfg(feature = "framebuffer")]
 pub mod framebuffer;
+#[cfg(feature = "memory")]
+pub mod memory;
 #[cfg(feature = "process")]
 pub mod process;
 #[cfg(feature = "sleep")]
 pub mod sleep;
+#[cfg(feature = "thread")]
+pub mod thread;
 #[cfg(feature = "values")]
 pub mod values;
 
 pub extern crate sys;
 
-/// Despawns the current process.
+/// Despawns the current process with the provided exit code.
+///
+/// The exit code is reported to the processes waiting for the current process to terminate.
 #[inline]
-pub fn despawn() -> ! {
+pub fn exit(exit_code: usize) -> ! {
     unsafe {
-        let _ = sys::despawn_process(sys::ProcessId::MAX);
+        let _ = sys::despawn_process(sys::ProcessId::MAX, exit_code);
         core::hint::unreachable_unchecked();
     }
 }
+
+/// Despawns the current process.
+///
+/// This is equivalent to calling [`exit`] with an exit code of `0`.
+#[inline]
+pub fn despawn() -> ! {
+    exit(0)
+}
@@ -0,0 +1,153 @@
+//! Management of the memory of the current process.
+
+use core::mem::MaybeUninit;
+
+use sys::SysResult;
+
+pub use sys::{CachePolicy, DmaFlags, MappingKind, MemoryMapping, ProtectionFlags, SharedMemoryId};
+
+use crate::Result;
+
+/// Maps `size` bytes of zeroed memory into the address space of the current process.
+///
+/// See [`sys::map_memory`] for more information.
+pub fn map(size: usize, flags: ProtectionFlags) -> Result<*mut u8> {
+    let mut addr = core::ptr::null_mut();
+    match sys::map_memory(core::ptr::null_mut(), size, flags, &mut addr) {
+        SysResult::SUCCESS => Ok(addr),
+        err => Err(err),
+    }
+}
+
+/// Unmaps the `size` bytes of memory starting at `addr` from the address space of the current
+/// process.
+///
+/// See [`sys::unmap_memory`] for more information.
+///
+/// # Safety
+///
+/// The memory must not be referenced anymore.
+pub unsafe fn unmap(addr: *mut u8, size: usize) -> Result<()> {
+    match sys::unmap_memory(addr, size) {
+        SysResult::SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Changes the protection of the `size` bytes of memory starting at `addr`.
+///
+/// See [`sys::protect_memory`] for more information.
+///
+/// # Safety
+///
+/// Removing permissions from memory that's still referenced with those permissions (e.g.
+/// making a `&mut T` read-only) may cause the process to fault.
+pub unsafe fn protect(addr: *mut u8, size: usize, flags: ProtectionFlags) -> Result<()> {
+    match sys::protect_memory(addr, size, flags) {
+        SysResult::SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Lists the memory mappings of the current process, sorted by address.
+///
+/// The mappings are written to `buf`, and the initialized part of it is returned along with the
+/// total number of mappings. When `buf` is too small, only the first mappings are written.
+///
+/// See [`sys::list_mappings`] for more information.
+pub fn mappings(buf: &mut [MaybeUninit<MemoryMapping>]) -> (&mut [MemoryMapping], usize) {
+    let mut count = buf.len();
+    let _ret = sys::list_mappings(buf.as_mut_ptr() as *mut MemoryMapping, &mut count);
+    debug_assert_eq!(_ret, SysResult::SUCCESS);
+
+    let written = count.min(buf.len());
+    let mappings =
+        unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut MemoryMapping, written) };
+
+    (mappings, count)
+}
+
+/// Creates a new shared memory object of `size` bytes and maps it into the address space of the
+/// current process.
+///
+/// # Returns
+///
+/// The ID of the object, which other processes can use to map it with [`map_shared`] once they
+/// have been granted access to it with [`grant_shared`], along with the address at which it was
+/// mapped.
+///
+/// See [`sys::create_shared_memory`] for more information.
+pub fn create_shared(size: usize, flags: ProtectionFlags) -> Result<(SharedMemoryId, *mut u8)> {
+    let mut id = 0;
+    let mut addr = core::ptr::null_mut();
+    match sys::create_shared_memory(size, flags, &mut id, &mut addr) {
+        SysResult::SUCCESS => Ok((id, addr)),
+        err => Err(err),
+    }
+}
+
+/// Maps the shared memory object with the provided ID into the address space of the current
+/// process.
+///
+/// # Returns
+///
+/// The address at which the object was mapped, along with its size in bytes.
+///
+/// See [`sys::map_shared_memory`] for more information.
+pub fn map_shared(id: SharedMemoryId, flags: ProtectionFlags) -> Result<(*mut u8, usize)> {
+    let mut addr = core::ptr::null_mut();
+    let mut size = 0;
+    match sys::map_shared_memory(id, flags, &mut addr, &mut size) {
+        SysResult::SUCCESS => Ok((addr, size)),
+        err => Err(err),
+    }
+}
+
+/// Allows the process with the provided ID to map the shared memory object with the provided
+/// ID.
+///
+/// See [`sys::grant_shared_memory`] for more information.
+pub fn grant_shared(id: SharedMemoryId, process_id: sys::ProcessId) -> Result<()> {
+    match sys::grant_shared_memory(id, process_id) {
+        SysResult::SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Maps the `size` bytes of device memory starting at the physical address `phys` into the
+/// address space of the current process.
+///
+/// This requires the [`MAP_DEVICE_MEMORY`](sys::Capabilities::MAP_DEVICE_MEMORY) capability.
+///
+/// See [`sys::map_device_memory`] for more information.
+pub fn map_device(
+    phys: u64,
+    size: usize,
+    flags: ProtectionFlags,
+    cache: CachePolicy,
+) -> Result<*mut u8> {
+    let mut addr = core::ptr::null_mut();
+    match sys::map_device_memory(phys, size, flags, cache, &mut addr) {
+        SysResult::SUCCESS => Ok(addr),
+        err => Err(err),
+    }
+}
+
+/// Allocates `size` bytes of physically contiguous memory that devices can access directly, and
+/// maps it into the address space of the current process.
+///
+/// This requires the [`ALLOCATE_DMA_MEMORY`](sys::Capabilities::ALLOCATE_DMA_MEMORY) capability.
+///
+/// # Returns
+///
+/// The address at which the memory was mapped, along with its physical address.
+///
+/// See [`sys::allocate_dma_memory`] for more information.
+pub fn allocate_dma(size: usize, prot: ProtectionFlags, flags: DmaFlags) -> Result<(*mut u8, u64)> {
+    let mut virt = core::ptr::null_mut();
+    let mut phys = 0;
+    match sys::allocate_dma_memory(size, prot, flags, &mut virt, &mut phys) {
+        SysResult::SUCCESS => Ok((virt, phys)),
+        err => Err(err),
+    }
+}
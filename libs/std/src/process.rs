@@ -1,5 +1,9 @@
+use core::mem::MaybeUninit;
+
 use sys::SysResult;
 
+pub use sys::Capabilities;
+
 use crate::Result;
 
 /// Represents a process.
@@ -15,13 +19,65 @@ impl ProcessId {
     /// The special ID used to represent the current process.
     pub const SELF: Self = Self(sys::ProcessId::MAX);
 
-    /// Attempts to despawns the process.
+    /// Attempts to despawns the process with the provided exit code.
     ///
     /// See [`sys::despawn_process`] for more information.
-    pub fn despawn(self) -> Result<()> {
-        match sys::despawn_process(self.0) {
+    pub fn despawn(self, exit_code: usize) -> Result<()> {
+        match sys::despawn_process(self.0, exit_code) {
             SysResult::SUCCESS => Ok(()),
             err => Err(err),
         }
     }
 }
+
+/// Spawns a new process from the provided executable image.
+///
+/// The `image` must be aligned to 8 bytes. The `cmdline` is passed to the new process, which
+/// gets the requested `capabilities` that the current process also has.
+///
+/// See [`sys::spawn_process`] for more information.
+pub fn spawn(image: &[u8], cmdline: &[u8], capabilities: Capabilities) -> Result<ProcessId> {
+    let mut id = MaybeUninit::uninit();
+
+    match sys::spawn_process(
+        image.as_ptr(),
+        image.len(),
+        cmdline.as_ptr(),
+        cmdline.len(),
+        capabilities,
+        id.as_mut_ptr(),
+    ) {
+        SysResult::SUCCESS => Ok(ProcessId(unsafe { id.assume_init() })),
+        err => Err(err),
+    }
+}
+
+/// Duplicates the current process.
+///
+/// The new process resumes from this function, sharing the memory of the current process
+/// until one of them writes to it. `None` is returned in the new process, while the current
+/// process gets the ID of the new one.
+///
+/// See [`sys::duplicate_process`] for more information.
+pub fn duplicate() -> Result<Option<ProcessId>> {
+    let mut id = MaybeUninit::uninit();
+
+    match sys::duplicate_process(id.as_mut_ptr()) {
+        SysResult::SUCCESS => match unsafe { id.assume_init() } {
+            sys::ProcessId::MAX => Ok(None),
+            id => Ok(Some(ProcessId(id))),
+        },
+        err => Err(err),
+    }
+}
+
+/// Removes the provided capabilities from the current process.
+///
+/// Dropped capabilities can never be regained, and are not passed to the processes created
+/// afterwards.
+///
+/// See [`sys::drop_capabilities`] for more information.
+pub fn drop_capabilities(capabilities: Capabilities) {
+    let _ret = sys::drop_capabilities(capabilities);
+    debug_assert_eq!(_ret, SysResult::SUCCESS);
+}
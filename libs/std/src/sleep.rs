@@ -1,34 +1,53 @@
+use core::time::Duration;
+
 /// A trait for types that can be transmuted into a [`sys::WakeUp`].
 ///
 /// # Safety
 ///
 /// Implementors of this trait must be able to be transmuted into a [`sys::WakeUp`] instance
-/// safely.
-pub unsafe trait WakeUp {
+/// safely. In particular, they must have the same size as [`sys::WakeUp`], otherwise the
+/// [`sleep!`] macro won't be able to compute the number of wake-ups it passes to the kernel.
+pub unsafe trait WakeUp: Sized {
     /// A default instance of the type.
     const DEFAULT: Self;
+
+    /// Returns whether this condition caused the process to be woken up during the last call
+    /// to [`sleep!`].
+    #[inline]
+    fn triggered(&self) -> bool {
+        // SAFETY:
+        //  Implementors can be transmuted into a `sys::WakeUp`.
+        unsafe { (*(self as *const Self as *const sys::WakeUp)).triggered() }
+    }
 }
 
 /// A [`WakeUp`] implementation that requests the kernel to wake the process up immediately without
 /// blocking.
-pub struct Now(sys::WakeUpNow);
+#[repr(transparent)]
+pub struct Now(sys::WakeUp);
 
 unsafe impl WakeUp for Now {
-    const DEFAULT: Self =
-        Self(sys::WakeUpNow {
+    const DEFAULT: Self = Self(sys::WakeUp {
+        now: sys::WakeUpNow {
             tag: sys::WakeUpTag::NOW,
-        });
+            triggered: sys::Bool::FALSE,
+        },
+    });
 }
 
 /// A [`WakeUp`] implementation that requests the kernel to wake the process up when the PS/2
 /// keyboard has sent some data.
-pub struct PS2Keyboard(sys::WakeUpPS2Keyboard);
+#[repr(transparent)]
+pub struct PS2Keyboard(sys::WakeUp);
 
 unsafe impl WakeUp for PS2Keyboard {
-    const DEFAULT: Self = Self(sys::WakeUpPS2Keyboard {
-        tag: sys::WakeUpTag::PS2_KEYBOARD,
-        length: 0,
-        scancodes: [0; sys::WakeUpPS2Keyboard::SIZE],
+    const DEFAULT: Self = Self(sys::WakeUp {
+        ps2_keyboard: sys::WakeUpPS2Keyboard {
+            tag: sys::WakeUpTag::PS2_KEYBOARD,
+            triggered: sys::Bool::FALSE,
+            length: 0,
+            scancodes: [0; sys::WakeUpPS2Keyboard::SIZE],
+        },
     });
 }
 
@@ -36,6 +55,14 @@ impl PS2Keyboard {
     /// The maximum number of bytes that can be received by the program during a single quantum.
     pub const SIZE: usize = sys::WakeUpPS2Keyboard::SIZE;
 
+    /// Returns the underlying [`sys::WakeUpPS2Keyboard`] instance.
+    #[inline]
+    fn raw(&self) -> &sys::WakeUpPS2Keyboard {
+        // SAFETY:
+        //  The tag of the union is always `PS2_KEYBOARD`.
+        unsafe { &self.0.ps2_keyboard }
+    }
+
     /// Returns the total number of bytes that have been received by the application since the last
     /// time the buffer was read.
     ///
@@ -43,7 +70,7 @@ impl PS2Keyboard {
     /// have been dropped.
     #[inline]
     pub fn total_length(&self) -> usize {
-        self.0.length as usize
+        self.raw().length as usize
     }
 
     /// Returns the number of bytes that have been dropped since the last time the buffer was read.
@@ -65,7 +92,7 @@ impl PS2Keyboard {
     #[inline]
     pub fn as_slice(&self) -> &[u8] {
         let len = self.total_length().min(Self::SIZE);
-        unsafe { core::slice::from_raw_parts(self.0.scancodes.as_ptr(), len) }
+        unsafe { core::slice::from_raw_parts(self.raw().scancodes.as_ptr(), len) }
     }
 
     /// Returns an iterator over the scan-codes that have been received by the application since
@@ -85,22 +112,34 @@ impl PS2Keyboard {
 
 /// A [`WakeUp`] implementation that requests the kernel to wake the process up when the PS/2
 /// mouse has sent some data.
-pub struct PS2Mouse(sys::WakeUpPS2Mouse);
+#[repr(transparent)]
+pub struct PS2Mouse(sys::WakeUp);
 
 unsafe impl WakeUp for PS2Mouse {
-    const DEFAULT: Self = Self(sys::WakeUpPS2Mouse {
-        tag: sys::WakeUpTag::PS2_MOUSE,
-        dx: 0,
-        dy: 0,
-        flags: sys::WakeUpPS2MouseFlags::empty(),
+    const DEFAULT: Self = Self(sys::WakeUp {
+        ps2_mouse: sys::WakeUpPS2Mouse {
+            tag: sys::WakeUpTag::PS2_MOUSE,
+            triggered: sys::Bool::FALSE,
+            dx: 0,
+            dy: 0,
+            flags: sys::WakeUpPS2MouseFlags::empty(),
+        },
     });
 }
 
 impl PS2Mouse {
+    /// Returns the underlying [`sys::WakeUpPS2Mouse`] instance.
+    #[inline]
+    fn raw(&self) -> &sys::WakeUpPS2Mouse {
+        // SAFETY:
+        //  The tag of the union is always `PS2_MOUSE`.
+        unsafe { &self.0.ps2_mouse }
+    }
+
     /// Returns whether the left mouse button is currently being pressed.
     #[inline]
     pub fn left_pressed(&self) -> bool {
-        self.0
+        self.raw()
             .flags
             .intersects(sys::WakeUpPS2MouseFlags::LEFT_BUTTON)
     }
@@ -108,7 +147,7 @@ impl PS2Mouse {
     /// Returns whether the right mouse button is currently being pressed.
     #[inline]
     pub fn right_pressed(&self) -> bool {
-        self.0
+        self.raw()
             .flags
             .intersects(sys::WakeUpPS2MouseFlags::RIGHT_BUTTON)
     }
@@ -116,7 +155,7 @@ impl PS2Mouse {
     /// Returns whether the middle mouse button is currently being pressed.
     #[inline]
     pub fn middle_pressed(&self) -> bool {
-        self.0
+        self.raw()
             .flags
             .intersects(sys::WakeUpPS2MouseFlags::MIDDLE_BUTTON)
     }
@@ -124,7 +163,7 @@ impl PS2Mouse {
     /// Returns whether the fourth mouse button is currently being pressed.
     #[inline]
     pub fn fourth_pressed(&self) -> bool {
-        self.0
+        self.raw()
             .flags
             .intersects(sys::WakeUpPS2MouseFlags::FOURTH_BUTTON)
     }
@@ -132,7 +171,7 @@ impl PS2Mouse {
     /// Returns whether the fifth mouse button is currently being pressed.
     #[inline]
     pub fn fifth_pressed(&self) -> bool {
-        self.0
+        self.raw()
             .flags
             .intersects(sys::WakeUpPS2MouseFlags::FIFTH_BUTTON)
     }
@@ -140,7 +179,7 @@ impl PS2Mouse {
     /// Returns whether the mouse has moved.
     #[inline]
     pub fn mouse_moved(&self) -> bool {
-        self.0.dx != 0 || self.0.dy != 0
+        self.raw().dx != 0 || self.raw().dy != 0
     }
 
     /// Returns the amount of movement of the mouse on the horizontal axis since the last time the
@@ -150,7 +189,7 @@ impl PS2Mouse {
     /// moved right.
     #[inline]
     pub fn delta_x(&self) -> i8 {
-        self.0.dx
+        self.raw().dx
     }
 
     /// Returns the amount of movement of the mouse on the vertical axis since the last time the
@@ -160,23 +199,227 @@ impl PS2Mouse {
     /// moved down.
     #[inline]
     pub fn delta_y(&self) -> i8 {
-        self.0.dy
+        self.raw().dy
     }
 
     /// Returns whether the mouse has moved or any of the buttons have changed since the last time
     /// the process read the buffer.
     #[inline]
     pub fn changed(&self) -> bool {
-        self.0.flags.intersects(sys::WakeUpPS2MouseFlags::CHANGED)
+        self.raw()
+            .flags
+            .intersects(sys::WakeUpPS2MouseFlags::CHANGED)
+    }
+}
+
+/// A [`WakeUp`] implementation that requests the kernel to wake the process up when another
+/// process terminates.
+///
+/// The [`DEFAULT`](WakeUp::DEFAULT) instance does not reference any existing process, meaning
+/// that [`ProcessExit::new`] must be used to initialize it (see the [`sleep!`] macro).
+#[repr(transparent)]
+pub struct ProcessExit(sys::WakeUp);
+
+unsafe impl WakeUp for ProcessExit {
+    const DEFAULT: Self = Self::new(sys::ProcessId::MAX);
+}
+
+impl ProcessExit {
+    /// Creates a new [`ProcessExit`] instance waiting for the process with the provided ID to
+    /// terminate.
+    #[inline]
+    pub const fn new(process_id: sys::ProcessId) -> Self {
+        Self(sys::WakeUp {
+            process_exit: sys::WakeUpProcessExit {
+                tag: sys::WakeUpTag::PROCESS_EXIT,
+                triggered: sys::Bool::FALSE,
+                process_id,
+                exit_code: 0,
+            },
+        })
+    }
+
+    /// Returns the underlying [`sys::WakeUpProcessExit`] instance.
+    #[inline]
+    fn raw(&self) -> &sys::WakeUpProcessExit {
+        // SAFETY:
+        //  The tag of the union is always `PROCESS_EXIT`.
+        unsafe { &self.0.process_exit }
+    }
+
+    /// Returns the ID of the process being waited on.
+    #[inline]
+    pub fn process_id(&self) -> sys::ProcessId {
+        self.raw().process_id
+    }
+
+    /// Returns the exit code of the process being waited on.
+    ///
+    /// This is only meaningful if the process has actually terminated. Otherwise, `0` is
+    /// returned.
+    #[inline]
+    pub fn exit_code(&self) -> usize {
+        self.raw().exit_code
+    }
+}
+
+/// Creates a new [`sys::WakeUp`] instance waiting for the provided deadline.
+#[inline]
+const fn deadline(unit: sys::DeadlineUnit, deadline: u64) -> sys::WakeUp {
+    sys::WakeUp {
+        deadline: sys::WakeUpDeadline {
+            tag: sys::WakeUpTag::DEADLINE,
+            triggered: sys::Bool::FALSE,
+            unit,
+            deadline,
+        },
+    }
+}
+
+/// Returns whether the provided deadline has been reached.
+fn is_reached(deadline: &sys::WakeUpDeadline) -> bool {
+    match deadline.unit {
+        sys::DeadlineUnit::UPTICKS => upticks() >= deadline.deadline,
+        _ => uptime_ns() >= deadline.deadline,
+    }
+}
+
+/// Returns the number of ticks since the system was booted.
+fn upticks() -> u64 {
+    let mut result = 0u64;
+    let _ret = sys::read_value(sys::Value::UPTICKS, &mut result as *mut _ as *mut u8);
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+}
+
+/// Returns the number of nanoseconds that elapsed since the system was booted.
+fn uptime_ns() -> u64 {
+    let mut result = sys::Duration::ZERO;
+    let _ret = sys::read_value(sys::Value::UPTIME, &mut result as *mut _ as *mut u8);
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+        .seconds
+        .saturating_mul(1_000_000_000)
+        .saturating_add(result.nanoseconds)
+}
+
+/// A [`WakeUp`] implementation that requests the kernel to wake the process up once an absolute
+/// deadline has been reached.
+///
+/// The [`DEFAULT`](WakeUp::DEFAULT) instance is a deadline that has always been reached already.
+#[repr(transparent)]
+pub struct Deadline(sys::WakeUp);
+
+unsafe impl WakeUp for Deadline {
+    const DEFAULT: Self = Self::from_upticks(0);
+}
+
+impl Deadline {
+    /// Creates a new [`Deadline`] that's reached once the system has ticked `upticks` times
+    /// since it was booted.
+    ///
+    /// See [`sys::Value::UPTICKS`] for more information.
+    #[inline]
+    pub const fn from_upticks(upticks: u64) -> Self {
+        Self(deadline(sys::DeadlineUnit::UPTICKS, upticks))
     }
+
+    /// Creates a new [`Deadline`] that's reached once `uptime` has elapsed since the system was
+    /// booted.
+    #[inline]
+    pub const fn from_uptime(uptime: Duration) -> Self {
+        let ns = uptime.as_nanos();
+        let ns = if ns > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            ns as u64
+        };
+
+        Self(deadline(sys::DeadlineUnit::NANOSECONDS, ns))
+    }
+
+    /// Returns whether the deadline has been reached.
+    #[inline]
+    pub fn is_reached(&self) -> bool {
+        // SAFETY:
+        //  The tag of the union is always `DEADLINE`.
+        is_reached(unsafe { &self.0.deadline })
+    }
+}
+
+/// A [`WakeUp`] implementation that requests the kernel to wake the process up once some amount
+/// of time has elapsed.
+///
+/// The duration is measured from the moment the [`Timeout`] is created, not from the moment the
+/// process goes to sleep.
+///
+/// The [`DEFAULT`](WakeUp::DEFAULT) instance is a timeout that has always expired already.
+#[repr(transparent)]
+pub struct Timeout(sys::WakeUp);
+
+unsafe impl WakeUp for Timeout {
+    const DEFAULT: Self = Self(deadline(sys::DeadlineUnit::NANOSECONDS, 0));
+}
+
+impl Timeout {
+    /// Creates a new [`Timeout`] that expires once `duration` has elapsed.
+    pub fn new(duration: Duration) -> Self {
+        let duration = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Self(deadline(
+            sys::DeadlineUnit::NANOSECONDS,
+            uptime_ns().saturating_add(duration),
+        ))
+    }
+
+    /// Returns whether the timeout has expired.
+    #[inline]
+    pub fn has_expired(&self) -> bool {
+        // SAFETY:
+        //  The tag of the union is always `DEADLINE`.
+        is_reached(unsafe { &self.0.deadline })
+    }
+}
+
+/// An error returned by the [`sleep!`] macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepError {
+    /// The error returned by the kernel.
+    pub error: sys::SysResult,
+    /// The index of the wake-up that was rejected by the kernel, in the order in which they were
+    /// passed to the [`sleep!`] macro.
+    ///
+    /// This is [`None`] when the error is not caused by a specific wake-up.
+    pub invalid_index: Option<usize>,
+}
+
+/// Puts the current process to sleep for at least the provided duration.
+pub fn sleep_for(duration: Duration) {
+    crate::sleep!(_ret; timeout: Timeout = Timeout::new(duration));
+    debug_assert_eq!(_ret, Ok(()));
+    let _ = timeout;
 }
 
 /// Sleeps until any of the [`WakeUp`] implementations completes.
+///
+/// Each wake-up is initialized with its [`DEFAULT`](WakeUp::DEFAULT) value, unless an initializer
+/// is provided after an `=` sign.
+///
+/// Once the process is woken up, [`WakeUp::triggered`] can be used on each of the named
+/// wake-ups to determine which of them fired.
+///
+/// The result of the operation is stored in `$result`, as a `Result<(), SleepError>`. When the
+/// kernel rejects one of the wake-ups, the [`SleepError`] indicates which one.
+///
+/// # Examples
+///
+/// ```ignore
+/// sleep!(result; keyboard: PS2Keyboard, child: ProcessExit = ProcessExit::new(child_id));
+/// ```
 #[macro_export]
 macro_rules! sleep {
     (
         $result:ident ;
-        $($name:ident: $type:ty),* $(,)?
+        $($name:ident: $type:ty $(= $init:expr)?),* $(,)?
     ) => {
         #[repr(C)]
         struct __WakeUpStruct {
@@ -190,18 +433,39 @@ macro_rules! sleep {
             const fn __implements_WakeUp<T: $crate::sleep::WakeUp>() {}
             $(
                 const _: () = __implements_WakeUp::<$type>();
+                const _: () = assert!(
+                    core::mem::size_of::<$type>() == core::mem::size_of::<$crate::sys::WakeUp>()
+                );
             )*
 
             let mut contents = __WakeUpStruct {
-                $($name: <$type as $crate::sleep::WakeUp>::DEFAULT,)*
+                $($name: $crate::sleep!(@init $type $(, $init)?),)*
             };
 
             let wake_ups = &mut contents as *mut __WakeUpStruct as *mut $crate::sys::WakeUp;
             let len = core::mem::size_of::<__WakeUpStruct>() / core::mem::size_of::<$crate::sys::WakeUp>();
 
-            $result = $crate::sys::sleep(wake_ups, len);
+            let mut invalid_index = usize::MAX;
+
+            $result = match $crate::sys::sleep(wake_ups, len, &mut invalid_index) {
+                $crate::sys::SysResult::SUCCESS => Ok(()),
+                error => Err($crate::sleep::SleepError {
+                    error,
+                    invalid_index: if invalid_index < len {
+                        Some(invalid_index)
+                    } else {
+                        None
+                    },
+                }),
+            };
 
             contents
         };
     };
+    (@init $type:ty) => {
+        <$type as $crate::sleep::WakeUp>::DEFAULT
+    };
+    (@init $type:ty, $init:expr) => {
+        $init
+    };
 }
@@ -0,0 +1,175 @@
+use core::arch::asm;
+use core::mem::{align_of, size_of, MaybeUninit};
+
+use sys::{ProtectionFlags, SysResult, Sysno};
+
+use crate::Result;
+
+/// The default size of the stack allocated for new threads, in bytes.
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+/// Represents a thread of the current process.
+///
+/// # Remarks
+///
+/// This type does not guarantee that the thread is still alive, or even whether the ID it
+/// stores is actually valid.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadId(pub sys::ThreadId);
+
+impl ThreadId {
+    /// The special ID used to represent the current thread.
+    pub const SELF: Self = Self(sys::ThreadId::MAX);
+
+    /// Attempts to despawn the thread.
+    ///
+    /// See [`sys::despawn_thread`] for more information.
+    pub fn despawn(self) -> Result<()> {
+        match sys::despawn_thread(self.0) {
+            SysResult::SUCCESS => Ok(()),
+            err => Err(err),
+        }
+    }
+}
+
+/// Despawns the current thread.
+///
+/// If the current thread is the last thread of the process, the process is despawned as well.
+#[inline]
+pub fn exit() -> ! {
+    unsafe {
+        let _ = sys::despawn_thread(sys::ThreadId::MAX);
+        core::hint::unreachable_unchecked();
+    }
+}
+
+/// Spawns a new thread running the provided function, with a stack of
+/// [`DEFAULT_STACK_SIZE`] bytes.
+///
+/// See [`spawn_with_stack_size`] for more information.
+#[inline]
+pub fn spawn<F>(f: F) -> Result<ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_stack_size(f, DEFAULT_STACK_SIZE)
+}
+
+/// Spawns a new thread running the provided function.
+///
+/// The stack of the thread is allocated using [`sys::map_memory`], and `stack_size` is rounded
+/// up to the page size. The function itself is moved to the top of that stack before the thread
+/// is started. When the function returns, the stack is unmapped and the thread is despawned.
+///
+/// # Remarks
+///
+/// The stack is only released when the function returns. Threads that are despawned by other
+/// means (such as [`exit`] or [`ThreadId::despawn`]) leak their stack.
+///
+/// # Panics
+///
+/// This function panics if `f` does not fit on the stack.
+pub fn spawn_with_stack_size<F>(f: F, stack_size: usize) -> Result<ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack_size = (stack_size + 0xFFF) & !0xFFF;
+    let align = align_of::<Start<F>>().max(16);
+
+    assert!(
+        size_of::<Start<F>>() + align + 16 <= stack_size,
+        "the function does not fit on the stack of the thread",
+    );
+
+    let mut stack = core::ptr::null_mut();
+    match sys::map_memory(
+        core::ptr::null_mut(),
+        stack_size,
+        ProtectionFlags::READ | ProtectionFlags::WRITE,
+        &mut stack,
+    ) {
+        SysResult::SUCCESS => (),
+        err => return Err(err),
+    }
+
+    // Move the function to the top of the stack.
+    let top = stack as usize + stack_size;
+    let func = (top - size_of::<Start<F>>()) & !(align - 1);
+    unsafe {
+        (func as *mut Start<F>).write(Start {
+            f,
+            stack,
+            stack_size,
+        })
+    };
+
+    // The stack must be aligned to 16 bytes before the return address is pushed. The new thread
+    // starts as if it had been called, meaning that the return address must be accounted for.
+    let stack_pointer = (func & !0xF) - 8;
+
+    let mut id = MaybeUninit::uninit();
+    match sys::spawn_thread(
+        entry::<F> as extern "C" fn(*mut Start<F>) -> ! as usize,
+        stack_pointer as *mut u8,
+        func,
+        id.as_mut_ptr(),
+    ) {
+        SysResult::SUCCESS => Ok(ThreadId(unsafe { id.assume_init() })),
+        err => {
+            unsafe {
+                drop((func as *mut Start<F>).read());
+                let _ = sys::unmap_memory(stack, stack_size);
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// The data moved to the top of the stack of the threads spawned by [`spawn_with_stack_size`].
+struct Start<F> {
+    /// The function that the thread runs.
+    f: F,
+    /// The base address of the stack of the thread.
+    stack: *mut u8,
+    /// The size of the stack of the thread, in bytes.
+    stack_size: usize,
+}
+
+/// The entry point of the threads spawned by [`spawn_with_stack_size`].
+extern "C" fn entry<F: FnOnce()>(start: *mut Start<F>) -> ! {
+    let Start {
+        f,
+        stack,
+        stack_size,
+    } = unsafe { start.read() };
+
+    f();
+
+    unsafe { unmap_stack_and_exit(stack, stack_size) }
+}
+
+/// Unmaps the stack of the current thread and despawns it.
+///
+/// # Safety
+///
+/// The current thread must be running on the provided stack, and nothing must reference it
+/// anymore. The stack is not touched between the two system calls.
+unsafe fn unmap_stack_and_exit(stack: *mut u8, stack_size: usize) -> ! {
+    // The number of the second system call is kept in `r13`, which is preserved by the kernel
+    // across system calls.
+    unsafe {
+        asm!(
+            "syscall",
+            "mov rax, r13",
+            "mov rdi, -1",
+            "syscall",
+            "ud2",
+            in("rax") Sysno::UnmapMemory as usize,
+            in("rdi") stack,
+            in("rsi") stack_size,
+            in("r13") Sysno::DespawnThread as usize,
+            options(noreturn, nostack),
+        );
+    }
+}
@@ -38,3 +38,47 @@ pub fn uptime() -> Duration {
     debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
     Duration::new(result.seconds, result.nanoseconds as u32)
 }
+
+/// Returns the total number of 4KiB pages of usable physical memory managed by the kernel.
+///
+/// See [`Value::TOTAL_PAGES`] for more information.
+pub fn total_pages() -> usize {
+    let mut result = 0;
+    let _ret = sys::read_value(sys::Value::TOTAL_PAGES, &mut result as *mut _ as *mut u8);
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+}
+
+/// Returns the number of 4KiB pages of physical memory that are currently free.
+///
+/// See [`Value::FREE_PAGES`] for more information.
+pub fn free_pages() -> usize {
+    let mut result = 0;
+    let _ret = sys::read_value(sys::Value::FREE_PAGES, &mut result as *mut _ as *mut u8);
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+}
+
+/// Returns the number of 4KiB pages of physical memory used as page tables.
+///
+/// See [`Value::PAGE_TABLE_PAGES`] for more information.
+pub fn page_table_pages() -> usize {
+    let mut result = 0;
+    let _ret = sys::read_value(
+        sys::Value::PAGE_TABLE_PAGES,
+        &mut result as *mut _ as *mut u8,
+    );
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+}
+
+/// Returns the number of 4KiB pages of physical memory mapped in the address space of the
+/// current process.
+///
+/// See [`Value::RESIDENT_PAGES`] for more information.
+pub fn resident_pages() -> usize {
+    let mut result = 0;
+    let _ret = sys::read_value(sys::Value::RESIDENT_PAGES, &mut result as *mut _ as *mut u8);
+    debug_assert_eq!(_ret, sys::SysResult::SUCCESS);
+    result
+}
@@ -59,16 +59,32 @@ impl From<Bool> for bool {
 /// The ID of a process.
 pub type ProcessId = usize;
 
+/// The ID of a thread.
+pub type ThreadId = usize;
+
+/// The ID of a shared memory object.
+///
+/// See [`create_shared_memory`].
+pub type SharedMemoryId = usize;
+
+/// The exit code reported for processes that were terminated by the kernel because they
+/// triggered a CPU exception (such as a page fault or a division by zero).
+pub const FAULT_EXIT_CODE: usize = usize::MAX;
+
 /// A condition that a process can wait on.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub union WakeUp {
     /// The tag of the [`WakeUp`], indicating which condition is being waited on.
     pub tag: WakeUpTag,
+    /// The fields that are common to all variants.
+    pub header: WakeUpHeader,
 
     pub now: WakeUpNow,
     pub ps2_keyboard: WakeUpPS2Keyboard,
     pub ps2_mouse: WakeUpPS2Mouse,
+    pub process_exit: WakeUpProcessExit,
+    pub deadline: WakeUpDeadline,
 }
 
 impl WakeUp {
@@ -79,6 +95,34 @@ impl WakeUp {
         //  The tag is in all variants of the union.
         unsafe { self.tag }
     }
+
+    /// Returns whether this [`WakeUp`] caused the process to be woken up.
+    #[inline]
+    pub fn triggered(&self) -> bool {
+        // SAFETY:
+        //  The header is in all variants of the union.
+        unsafe { self.header.triggered.as_bool() }
+    }
+
+    /// Sets the `triggered` flag of this [`WakeUp`].
+    #[inline]
+    pub fn set_triggered(&mut self, triggered: bool) {
+        self.header.triggered = Bool::from(triggered);
+    }
+}
+
+/// The fields that are common to all [`WakeUp`] variants.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct WakeUpHeader {
+    /// The tag of the [`WakeUp`], indicating which condition is being waited on.
+    pub tag: WakeUpTag,
+    /// Whether the condition was met when the process was woken up.
+    ///
+    /// The kernel clears this flag when the process goes to sleep, and sets it for every
+    /// condition that caused the process to wake up. Note that more than one condition may be
+    /// triggered at once.
+    pub triggered: Bool,
 }
 
 loose_enum! {
@@ -90,6 +134,10 @@ loose_enum! {
         const PS2_KEYBOARD = 1;
         /// The process is waiting for a byte of data to be available on the second PS/2 port.
         const PS2_MOUSE = 2;
+        /// See [`WakeUpProcessExit`].
+        const PROCESS_EXIT = 3;
+        /// See [`WakeUpDeadline`].
+        const DEADLINE = 4;
     }
 }
 
@@ -101,6 +149,10 @@ loose_enum! {
 pub struct WakeUpNow {
     /// Must be [`WakeUpTag::NOW`].
     pub tag: WakeUpTag,
+    /// Set by the kernel when this condition is the reason the process was woken up.
+    ///
+    /// See [`WakeUpHeader::triggered`].
+    pub triggered: Bool,
 }
 
 /// A [`WakeUp`] variant that requests the kernel to wake the process up when some data is
@@ -110,6 +162,10 @@ pub struct WakeUpNow {
 pub struct WakeUpPS2Keyboard {
     /// Must be [`WakeUpTag::PS2_KEYBOARD`].
     pub tag: WakeUpTag,
+    /// Set by the kernel when this condition is the reason the process was woken up.
+    ///
+    /// See [`WakeUpHeader::triggered`].
+    pub triggered: Bool,
     /// The number of bytes that have been written to the `scancodes` array.
     ///
     /// # Remarks
@@ -155,6 +211,10 @@ bitflags! {
 pub struct WakeUpPS2Mouse {
     /// Must be [`WakeUpTag::PS2_MOUSE`].
     pub tag: WakeUpTag,
+    /// Set by the kernel when this condition is the reason the process was woken up.
+    ///
+    /// See [`WakeUpHeader::triggered`].
+    pub triggered: Bool,
     /// Some flags associated with the mouse.
     pub flags: WakeUpPS2MouseFlags,
     /// The amount of movement of the mouse since the last time the process read the buffer.
@@ -163,6 +223,72 @@ pub struct WakeUpPS2Mouse {
     pub dy: i8,
 }
 
+/// A [`WakeUp`] variant that requests the kernel to wake the process up when another process
+/// terminates.
+///
+/// # Remarks
+///
+/// If the process referenced by `process_id` does not exist at the time the process goes to
+/// sleep, the [`sleep`] system call fails with `PROCESS_NOT_FOUND`.
+///
+/// The only exception is when the referenced process is a child of the current process that
+/// exited while none of the threads of the current process was waiting for it. In that case,
+/// its exit code is kept by the kernel until a thread of the current process waits for it, at
+/// which point the thread is woken up immediately. The kernel only keeps a limited number of
+/// such exit codes per process, dropping the oldest ones first.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct WakeUpProcessExit {
+    /// Must be [`WakeUpTag::PROCESS_EXIT`].
+    pub tag: WakeUpTag,
+    /// Set by the kernel when this condition is the reason the process was woken up.
+    ///
+    /// See [`WakeUpHeader::triggered`].
+    pub triggered: Bool,
+    /// The ID of the process to wait on.
+    pub process_id: ProcessId,
+    /// When the process is woken up because the process referenced by `process_id` has been
+    /// despawned, the exit code of that process is written here.
+    pub exit_code: usize,
+}
+
+loose_enum! {
+    /// The unit in which the deadline of a [`WakeUpDeadline`] is expressed.
+    pub struct DeadlineUnit: u8 {
+        /// The deadline is a number of ticks since the system was booted.
+        ///
+        /// See [`Value::UPTICKS`].
+        const UPTICKS = 0;
+        /// The deadline is a number of nanoseconds since the system was booted.
+        ///
+        /// See [`Value::UPTIME`].
+        const NANOSECONDS = 1;
+    }
+}
+
+/// A [`WakeUp`] variant that requests the kernel to wake the process up once a deadline has
+/// been reached.
+///
+/// # Remarks
+///
+/// The deadline is checked every time the system ticks, meaning that the process may be woken
+/// up up to a tick late (see [`Value::NANOSECONDS_PER_TICK`]). If the deadline has already been
+/// reached when the process goes to sleep, it is woken up immediately.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct WakeUpDeadline {
+    /// Must be [`WakeUpTag::DEADLINE`].
+    pub tag: WakeUpTag,
+    /// Set by the kernel when this condition is the reason the process was woken up.
+    ///
+    /// See [`WakeUpHeader::triggered`].
+    pub triggered: Bool,
+    /// The unit in which `deadline` is expressed.
+    pub unit: DeadlineUnit,
+    /// The deadline, relative to the moment the system was booted.
+    pub deadline: u64,
+}
+
 /// The verbosity level of a message logged through the logging system of the kernel.
 ///
 /// # Remarks
@@ -306,6 +432,33 @@ loose_enum! {
         /// [`UPTIME`]: Value::UPTIME
         /// [`UPTICKS`]: Value::UPTICKS
         const NANOSECONDS_PER_TICK = 2;
+
+        /// The total number of 4KiB pages of usable physical memory managed by the kernel.
+        ///
+        /// The result type associated with this value is a `usize`.
+        const TOTAL_PAGES = 3;
+
+        /// The number of 4KiB pages of physical memory that are currently free.
+        ///
+        /// The result type associated with this value is a `usize`.
+        const FREE_PAGES = 4;
+
+        /// The number of 4KiB pages of physical memory currently used as page tables, across
+        /// every process and the kernel.
+        ///
+        /// The page tables created while the kernel was booting are not included.
+        ///
+        /// The result type associated with this value is a `usize`.
+        const PAGE_TABLE_PAGES = 5;
+
+        /// The number of 4KiB pages of physical memory mapped in the address space of the current
+        /// process.
+        ///
+        /// This includes memory shared with other processes, as well as device memory. Lazily
+        /// mapped pages are only counted once they have been accessed.
+        ///
+        /// The result type associated with this value is a `usize`.
+        const RESIDENT_PAGES = 6;
     }
 }
 
@@ -353,5 +506,181 @@ bitflags! {
         const READ  = 1 << 1;
         /// Whether the page can be executed.
         const EXECUTE = 1 << 2;
+        /// Whether physical memory should only be allocated when the page is first accessed,
+        /// rather than when it is mapped.
+        const LAZY = 1 << 3;
+        /// Whether the page may be both writable and executable.
+        ///
+        /// Such pages allow a process to execute data it was tricked into writing, which is why
+        /// the kernel refuses to create them unless this flag is set.
+        const ALLOW_WRITE_EXECUTE = 1 << 4;
+    }
+}
+
+loose_enum! {
+    /// Describes what a [`MemoryMapping`] is used for.
+    pub struct MappingKind: u8 {
+        /// Memory allocated with [`map_memory`].
+        const ANONYMOUS = 0;
+        /// A framebuffer acquired with [`acquire_framebuffers`].
+        const FRAMEBUFFER = 1;
+        /// A segment of the executable image of the process.
+        const IMAGE = 2;
+        /// The stack allocated by the kernel for the first thread of the process.
+        const STACK = 3;
+        /// A shared memory object mapped with [`create_shared_memory`] or
+        /// [`map_shared_memory`].
+        const SHARED = 4;
+        /// The memory of a device mapped with [`map_device_memory`].
+        const DEVICE = 5;
+        /// Physically contiguous memory allocated with [`allocate_dma_memory`].
+        const DMA = 6;
+    }
+}
+
+bitflags! {
+    /// Some constraints on the physical memory allocated with [`allocate_dma_memory`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[repr(transparent)]
+    pub struct DmaFlags: u8 {
+        /// Whether the memory must be entirely located below 4 GiB, for devices that can only
+        /// address 32 bits of physical memory.
+        const BELOW_4GIB = 1 << 0;
+    }
+}
+
+loose_enum! {
+    /// The caching policy used for memory mapped with [`map_device_memory`].
+    pub struct CachePolicy: u8 {
+        /// Accesses are never cached, nor combined, and happen in program order.
+        ///
+        /// This is what most memory-mapped registers expect.
+        const UNCACHED = 0;
+        /// Accesses are not cached, but consecutive writes may be combined into larger
+        /// transactions and reordered.
+        ///
+        /// This is usually used for framebuffers and other large device buffers.
+        const WRITE_COMBINING = 1;
+    }
+}
+
+bitflags! {
+    /// The privileged operations that a process is allowed to perform.
+    ///
+    /// A process spawned with [`spawn_process`] only gets the capabilities requested by the
+    /// process that created it, limited to those that this process has. A process created with
+    /// [`duplicate_process`] keeps the capabilities of the original one. Capabilities can be
+    /// dropped with [`drop_capabilities`], but never regained.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    #[repr(transparent)]
+    pub struct Capabilities: u32 {
+        /// Whether the process can map the memory of devices in its address space using
+        /// [`map_device_memory`].
+        const MAP_DEVICE_MEMORY = 1 << 0;
+        /// Whether the process can allocate physically contiguous memory for devices to access
+        /// using [`allocate_dma_memory`].
+        const ALLOCATE_DMA_MEMORY = 1 << 1;
+        /// Whether the process can despawn processes that it did not spawn (directly or not)
+        /// using [`despawn_process`].
+        const DESPAWN_PROCESSES = 1 << 2;
+    }
+}
+
+/// A region of memory mapped in the address space of a process.
+///
+/// See [`list_mappings`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryMapping {
+    /// The address of the first byte of the region.
+    ///
+    /// This is always aligned to the page size.
+    pub address: usize,
+    /// The size of the region, in bytes.
+    ///
+    /// This is always a multiple of the page size.
+    pub size: usize,
+    /// The protection of the pages of the region.
+    pub protection: ProtectionFlags,
+    /// What the region is used for.
+    pub kind: MappingKind,
+}
+
+/// The state of a thread that was interrupted by an exception.
+///
+/// See [`set_exception_handler`] and [`resume_context`].
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Context {
+    pub rax: usize,
+    pub rbx: usize,
+    pub rcx: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rbp: usize,
+    pub rsp: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub r10: usize,
+    pub r11: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
+    /// The instruction pointer of the thread.
+    ///
+    /// For faults, this is the address of the faulting instruction. For traps (such as
+    /// breakpoints), this is the address of the instruction following it.
+    pub rip: usize,
+    /// The RFLAGS register of the thread.
+    pub rflags: usize,
+    /// The base address of the FS segment of the thread.
+    pub fs_base: usize,
+    /// The base address of the GS segment of the thread.
+    pub gs_base: usize,
+}
+
+loose_enum! {
+    /// An exception that can be delivered to a userspace exception handler.
+    ///
+    /// The values of the variants match the interrupt vectors of the corresponding CPU
+    /// exceptions.
+    pub struct Exception: usize {
+        /// The thread attempted to divide by zero, or the result of a division was too large
+        /// to be represented.
+        const DIVISION_ERROR = 0;
+        /// The thread executed an `int3` instruction.
+        const BREAKPOINT = 3;
+        /// The thread executed an `into` instruction while the overflow flag was set.
+        const OVERFLOW = 4;
+        /// The thread accessed a page that is not mapped, or accessed a page in a way that its
+        /// protection flags do not allow.
+        const PAGE_FAULT = 14;
+        /// An unmasked x87 floating-point exception occured.
+        const X87_FLOATING_POINT = 16;
+        /// An unmasked SIMD floating-point exception occured.
+        const SIMD_FLOATING_POINT = 19;
     }
 }
+
+/// Information about an exception that's passed to the userspace exception handler of a process.
+///
+/// See [`set_exception_handler`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionContext {
+    /// The exception that occured.
+    pub exception: Exception,
+    /// The error code pushed by the CPU for the exception, or zero if the exception does not
+    /// have one.
+    ///
+    /// For page faults, this describes the kind of access that caused the fault.
+    pub error_code: usize,
+    /// For page faults, the address that the thread attempted to access. Zero otherwise.
+    pub fault_address: usize,
+    /// The state of the thread when the exception occured.
+    ///
+    /// This can be modified and passed back to [`resume_context`] to resume the thread.
+    pub context: Context,
+}
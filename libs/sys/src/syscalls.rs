@@ -1,7 +1,8 @@
 use core::arch::asm;
 
 use crate::{
-    Framebuffer, PciDevice, ProcessId, ProtectionFlags, SysResult, Sysno, Value, Verbosity, WakeUp,
+    CachePolicy, Capabilities, Context, DmaFlags, Framebuffer, MemoryMapping, PciDevice, ProcessId,
+    ProtectionFlags, SharedMemoryId, SysResult, Sysno, ThreadId, Value, Verbosity, WakeUp,
 };
 
 /// Performs a system call with no arguments.
@@ -208,10 +209,17 @@ pub unsafe fn syscall6(
 /// - `process_id`: The ID of the process to despawn. The special value `ProcessId::MAX` is used
 ///   to refer to the current process.
 ///
+/// - `exit_code`: The exit code of the process. This value is reported to the processes waiting
+///   for the despawned process to terminate (see [`WakeUpProcessExit`](crate::WakeUpProcessExit)).
+///
 /// # Errors
 ///
 /// - `PROCESS_NOT_FOUND` if the `process_id` does not refer to an existing process.
 ///
+/// - `MISSING_CAPABILITY` if the process referenced by `process_id` is neither the current
+///   process nor one of its descendants, and the current process does not have the
+///   [`DESPAWN_PROCESSES`](Capabilities::DESPAWN_PROCESSES) capability.
+///
 /// Note that using `ProcessId::MAX` as the `process_id` will never fail. In that case, the
 /// function is guaranteed to never return control to the caller.
 ///
@@ -220,8 +228,144 @@ pub unsafe fn syscall6(
 /// Nothing; but this function diverges if `process_id` is `ProcessId::MAX` or the ID of the
 /// current process.
 #[inline]
-pub fn despawn_process(process_id: ProcessId) -> SysResult {
-    unsafe { SysResult::from_raw(syscall1(Sysno::DespawnProcess as usize, process_id)) }
+pub fn despawn_process(process_id: ProcessId, exit_code: usize) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall2(
+            Sysno::DespawnProcess as usize,
+            process_id,
+            exit_code,
+        ))
+    }
+}
+
+/// Spawns a new process from the provided executable image.
+///
+/// # Parameters
+///
+/// - `image`: A pointer to the executable image to load. This pointer must reference at least
+///   `image_len` bytes, and must be aligned to 8 bytes.
+///
+/// - `image_len`: The number of bytes in the `image`.
+///
+/// - `cmdline`: A pointer to the command line to pass to the new process. This pointer must
+///   reference at least `cmdline_len` bytes.
+///
+/// - `cmdline_len`: The number of bytes in the `cmdline`. This must be less than 4095.
+///
+/// - `capabilities`: The capabilities to give to the new process. Capabilities that the current
+///   process does not have are silently ignored.
+///
+/// - `process_id`: A pointer to a [`ProcessId`] that will be written with the ID of the new
+///   process.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if the format of the `image` is not supported, if the `image` is invalid
+///   or larger than 64 MiB, or if the `cmdline` is too large.
+///
+/// - `INVALID_POINTER` if `image` or `cmdline` does not reference readable memory, or if
+///   `process_id` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system is out of memory for the new process.
+///
+/// - `TOO_MANY_PROCESSES` if the maximum number of processes has been reached.
+///
+/// # Returns
+///
+/// The ID of the new process is written to `process_id`.
+///
+/// The image is copied into the memory of the new process, meaning that the caller is free to
+/// discard it once this function returns.
+#[inline]
+pub fn spawn_process(
+    image: *const u8,
+    image_len: usize,
+    cmdline: *const u8,
+    cmdline_len: usize,
+    capabilities: Capabilities,
+    process_id: *mut ProcessId,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall6(
+            Sysno::SpawnProcess as usize,
+            image as usize,
+            image_len,
+            cmdline as usize,
+            cmdline_len,
+            capabilities.bits() as usize,
+            process_id as usize,
+        ))
+    }
+}
+
+/// Spawns a new thread within the current process.
+///
+/// # Parameters
+///
+/// - `entry_point`: The address at which the new thread starts executing.
+///
+/// - `stack_pointer`: The initial value of the stack pointer of the new thread. The kernel uses
+///   this value as-is, meaning that the caller is responsible for aligning it properly.
+///
+/// - `arg`: A value passed to the new thread in its first argument register (`rdi`).
+///
+/// - `thread_id`: A pointer to a [`ThreadId`] that will be written with the ID of the new thread.
+///   This pointer may be null.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if `entry_point` or `stack_pointer` is not a userland address.
+///
+/// - `INVALID_POINTER` if `thread_id` is not null and does not reference writable memory.
+///
+/// - `TOO_MANY_THREADS` if the maximum number of threads has been reached.
+///
+/// # Returns
+///
+/// The ID of the new thread is written to `thread_id`.
+///
+/// The new thread shares the address space of the current process. Other than its stack pointer,
+/// instruction pointer and first argument, all its registers are initially zeroed.
+#[inline]
+pub fn spawn_thread(
+    entry_point: usize,
+    stack_pointer: *mut u8,
+    arg: usize,
+    thread_id: *mut ThreadId,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall4(
+            Sysno::SpawnThread as usize,
+            entry_point,
+            stack_pointer as usize,
+            arg,
+            thread_id as usize,
+        ))
+    }
+}
+
+/// Despawns (terminate) the specified thread.
+///
+/// # Parameters
+///
+/// - `thread_id`: The ID of the thread to despawn. The special value `ThreadId::MAX` is used to
+///   refer to the current thread.
+///
+/// # Errors
+///
+/// - `THREAD_NOT_FOUND` if the `thread_id` does not refer to an existing thread of the current
+///   process.
+///
+/// # Returns
+///
+/// Nothing; but this function diverges if `thread_id` is `ThreadId::MAX` or the ID of the current
+/// thread.
+///
+/// When the last thread of a process is despawned, the process itself is despawned with an exit
+/// code of `0`.
+#[inline]
+pub fn despawn_thread(thread_id: ThreadId) -> SysResult {
+    unsafe { SysResult::from_raw(syscall1(Sysno::DespawnThread as usize, thread_id)) }
 }
 
 /// Puts the current process to sleep until it is woken up when any of the specified wake-up events
@@ -234,16 +378,45 @@ pub fn despawn_process(process_id: ProcessId) -> SysResult {
 ///
 /// - `wake_up_len`: The number of items in the `wake_ups` array.
 ///
+/// - `invalid_index`: A pointer to a `usize` that will be written with the index of the offending
+///   wake-up event if one of them is rejected by the kernel. This pointer may be null.
+///
 /// # Errors
 ///
-/// - `INVALID_VALUE` if any of the wake-up events are invalid.
+/// - `INVALID_VALUE` if any of the wake-up events are invalid, or if `wake_up_len` is larger
+///   than 64.
+///
+/// - `INVALID_POINTER` if `wake_ups` does not reference writable memory, or if `invalid_index`
+///   is not null and does not reference writable memory. This error is also returned when the
+///   process is woken up if the wake-up events could not be written back because `wake_ups`
+///   was unmapped in the meantime.
+///
+/// - `OUT_OF_MEMORY` if the kernel could not allocate the memory required to keep track of the
+///   wake-up events.
+///
+/// - `PROCESS_NOT_FOUND` if a [`WakeUpProcessExit`](crate::WakeUpProcessExit) references a
+///   process that does not exist, and which is not an exited child of the current process whose
+///   exit code has not been collected yet.
+///
+/// The wake-up events are all validated before the process is put to sleep. When an error
+/// occurs, the process does not sleep at all.
+///
+/// # Returns
+///
+/// When the process is woken up, the `triggered` flag of every wake-up event that caused it to
+/// wake up is set (see [`WakeUpHeader`](crate::WakeUpHeader)). The flag is cleared for the
+/// other events.
+///
+/// The kernel works on a copy of the wake-up events while the process sleeps. Modifying the
+/// array in the meantime has no effect, and it is overwritten when the process wakes up.
 #[inline]
-pub fn sleep(wake_ups: *mut WakeUp, wake_up_len: usize) -> SysResult {
+pub fn sleep(wake_ups: *mut WakeUp, wake_up_len: usize, invalid_index: *mut usize) -> SysResult {
     unsafe {
-        SysResult::from_raw(syscall2(
+        SysResult::from_raw(syscall3(
             Sysno::Sleep as usize,
             wake_ups as usize,
             wake_up_len,
+            invalid_index as usize,
         ))
     }
 }
@@ -261,6 +434,9 @@ pub fn sleep(wake_ups: *mut WakeUp, wake_up_len: usize) -> SysResult {
 ///
 /// - `RESOURCE_BUSY` if the framebuffers are currently owned by another process.
 ///
+/// - `INVALID_POINTER` if `count` does not reference writable memory, or if `ret` is not null
+///   and does not reference writable memory.
+///
 /// - `OUT_OF_MEMORY` if the kernel is unable to allocate memory for bookkeeping (mainly to map
 ///   the framebuffers into the process's address space).
 ///
@@ -298,6 +474,8 @@ pub fn release_framebuffers() -> SysResult {
 ///
 /// - `INVALID_VALUE` if the `value` is invalid.
 ///
+/// - `INVALID_POINTER` if `result` does not reference writable memory.
+///
 /// # Returns
 ///
 /// The value of the requested kernel value.
@@ -323,6 +501,11 @@ pub fn read_value(value: Value, result: *mut u8) -> SysResult {
 /// - `count`: The maximum number of [`PciDevice`] instances that can be written by the kernel
 ///   at `devices`, and upon return, the number of PCI devices available on the system.
 ///
+/// # Errors
+///
+/// - `INVALID_POINTER` if `count` does not reference readable and writable memory, or if
+///   `devices` does not reference writable memory.
+///
 /// # Returns
 ///
 /// At most `count` PCI devices are written to `devices`. If `count` is zero, `devices` is not
@@ -355,7 +538,13 @@ pub fn enumerate_pci_devices(devices: *mut PciDevice, count: *mut usize) -> SysR
 /// # Errors
 ///
 /// - `INVALID_VALUE` if either the `count` or the `addr` provided is not aligned
-///   to the page size.
+///   to the page size, if `count` is zero, or if the requested region is not part of userland.
+///   Note that the last page of userland can never be mapped.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_POINTER` if `out` does not reference writable memory.
 ///
 /// - `OUT_OF_MEMORY` if the system is out of physical memory for the process.
 ///
@@ -367,6 +556,14 @@ pub fn enumerate_pci_devices(devices: *mut PciDevice, count: *mut usize) -> SysR
 ///
 /// The virtual address of the first page allocated.  If `addr` is not null, this
 /// is always equal to `addr`.
+///
+/// The allocated memory is always filled with zeros.
+///
+/// # Remarks
+///
+/// When `flags` contains [`ProtectionFlags::LAZY`], the region is only reserved. Each page is
+/// backed by physical memory the first time it is accessed. If the system runs out of memory
+/// at that point, the process is terminated.
 #[inline]
 pub fn map_memory(
     addr: *mut u8,
@@ -399,12 +596,12 @@ pub fn map_memory(
 /// - `INVALID_VALUE` if either the `count` or the `addr` provided is not aligned
 ///   to the page size.
 ///
-/// - `NOT_MAPPED` if any of the requested virtual addresses requested to be unmapped are not
-///   currently part of the process's virtual address space.
+/// - `INVALID_VALUE` if the region only covers part of a framebuffer, of a shared memory
+///   object, of device memory or of DMA memory, which can only be unmapped as a whole.
 ///
-/// # Remarks
-///
-/// This function ignores any pages that are not mapped into the process's address space.
+/// - `ALREADY_MAPPED` if any of the requested virtual addresses requested to be unmapped are
+///   not currently part of the process's virtual address space. In that case, nothing is
+///   unmapped.
 ///
 /// # Returns
 ///
@@ -431,6 +628,11 @@ pub fn unmap_memory(addr: *mut u8, count: usize) -> SysResult {
 ///
 /// - `INVALID_VALUE` if `verbosity` is not in the range `0..=3`. This cannot happen with the API
 ///   provided by this crate as it uses a Rust enumeration that is guaranteed to be in that range.
+///   Also returned if `data_len` is larger than 64 KiB.
+///
+/// - `INVALID_POINTER` if `data` does not reference readable memory.
+///
+/// - `OUT_OF_MEMORY` if the kernel is unable to copy the message.
 ///
 /// # Returns
 ///
@@ -446,3 +648,474 @@ pub fn kernel_log(verbosity: Verbosity, data: *const u8, data_len: usize) -> Sys
         ))
     }
 }
+
+/// Registers the exception handler of the current process, along with the stack on which the
+/// calling thread runs it.
+///
+/// When a thread of the process triggers one of the [`Exception`](crate::Exception)s, the kernel
+/// saves its state in an [`ExceptionContext`](crate::ExceptionContext) at the top of the handler
+/// stack of that thread and jumps to `entry_point`, passing a pointer to that context in its
+/// first argument register (`rdi`). The stack pointer is set right below the context, as if the
+/// handler had been called by a `call` instruction.
+///
+/// The handler is expected to either fix things up and resume the thread with
+/// [`resume_context`], or to terminate it.
+///
+/// # Parameters
+///
+/// - `entry_point`: The address of the exception handler. The handler is shared by all the
+///   threads of the process. The special value `0` unregisters the current handler, if any, as
+///   well as the handler stacks of all the threads of the process.
+///
+/// - `stack_top`: A pointer to the top of the stack used by the exception handler when it runs
+///   on the calling thread. Other threads must register their own stack by calling this function
+///   themselves.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if `entry_point` or `stack_top` is not a userland address.
+///
+/// # Remarks
+///
+/// Each thread handles one exception at a time. If a thread triggers an exception while it is
+/// running the handler (i.e. before it calls [`resume_context`]), if it never registered a
+/// handler stack, or if its handler stack is not mapped and writable, the process is
+/// terminated. The same happens for exceptions that cannot be delivered to userspace, or when
+/// no handler is registered.
+///
+/// The kernel only considers the handler done once it calls [`resume_context`]. A handler that
+/// leaves through other means (e.g. by jumping back into regular code) must call this function
+/// again to register its stack before the thread can handle another exception.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn set_exception_handler(entry_point: usize, stack_top: *mut u8) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall2(
+            Sysno::SetExceptionHandler as usize,
+            entry_point,
+            stack_top as usize,
+        ))
+    }
+}
+
+/// Resumes the execution of the current thread with the provided state.
+///
+/// This is meant to be called by the exception handler of the process (see
+/// [`set_exception_handler`]) once it has handled an exception.
+///
+/// # Parameters
+///
+/// - `context`: The state the thread should resume with.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if the instruction pointer, the stack pointer or the segment bases of the
+///   provided context are not userland addresses.
+///
+/// - `INVALID_POINTER` if `context` does not reference readable memory.
+///
+/// # Remarks
+///
+/// Privileged bits of the RFLAGS register (such as the I/O privilege level or the interrupt
+/// flag) cannot be modified by this function and are silently ignored.
+///
+/// # Returns
+///
+/// On success, this function does not return. The thread resumes with the provided state
+/// instead.
+#[inline]
+pub fn resume_context(context: *const Context) -> SysResult {
+    unsafe { SysResult::from_raw(syscall1(Sysno::ResumeContext as usize, context as usize)) }
+}
+
+/// Changes the protection of a memory region of the process's address space.
+///
+/// # Parameters
+///
+/// - `addr`: The virtual address of the first page to modify. This pointer must be aligned
+///   to the page size.
+///
+/// - `count`: The number of bytes to modify. This must be aligned to the page size.
+///
+/// - `flags`: The new protection of the pages. When none of [`ProtectionFlags::READ`],
+///   [`ProtectionFlags::WRITE`] and [`ProtectionFlags::EXECUTE`] are set, the pages remain
+///   mapped but any access to them triggers a page fault. [`ProtectionFlags::LAZY`] is ignored.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if either the `count` or the `addr` provided is not aligned
+///   to the page size, or if the region is not part of userland.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_VALUE` if part of the region is mapped by the kernel using pages larger than 4KiB,
+///   which may happen for device memory and framebuffers. In that case, none of the pages are
+///   modified.
+///
+/// - `ALREADY_MAPPED` if any of the pages of the region is not currently mapped. In that case,
+///   none of the pages are modified.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn protect_memory(addr: *mut u8, count: usize, flags: ProtectionFlags) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall3(
+            Sysno::ProtectMemory as usize,
+            addr as usize,
+            count,
+            flags.bits() as usize,
+        ))
+    }
+}
+
+/// Lists the memory regions mapped in the address space of the current process.
+///
+/// # Parameters
+///
+/// - `mappings`: An array of [`MemoryMapping`]s that the kernel fills with the mappings of the
+///   process, sorted by address. This pointer must reference at least `*count` items.
+///
+/// - `count`: On input, the number of mappings that the `mappings` array can hold. On output,
+///   the total number of mappings of the process. When that number is larger than the size of
+///   the array, only the first mappings are written.
+///
+/// # Errors
+///
+/// - `INVALID_POINTER` if `count` does not reference readable and writable memory, or if
+///   `mappings` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the kernel runs out of memory while collecting the mappings.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn list_mappings(mappings: *mut MemoryMapping, count: *mut usize) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall2(
+            Sysno::ListMappings as usize,
+            mappings as usize,
+            count as usize,
+        ))
+    }
+}
+
+/// Creates a new shared memory object and maps it into the process's address space.
+///
+/// The object can be mapped by other processes using [`map_shared_memory`], once they have been
+/// granted access to it with [`grant_shared_memory`]. It is destroyed once it is not mapped
+/// anywhere anymore.
+///
+/// # Parameters
+///
+/// - `count`: The size of the object, in bytes. This must be aligned to the page size.
+///
+/// - `flags`: The protection of the pages mapped in the process's address space.
+///   [`ProtectionFlags::LAZY`] is ignored.
+///
+/// - `id`: The ID of the created object. This is written by the kernel.
+///
+/// - `out`: The virtual address at which the object was mapped. This is written by the
+///   kernel.
+///
+/// # Errors
+///
+/// - `INVALID_VALUE` if `count` is zero or not aligned to the page size.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_POINTER` if `id` or `out` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system is out of physical memory, or if the process's address space
+///   has no room left for the object.
+///
+/// # Returns
+///
+/// Nothing. The memory of the object is initially filled with zeros.
+#[inline]
+pub fn create_shared_memory(
+    count: usize,
+    flags: ProtectionFlags,
+    id: *mut SharedMemoryId,
+    out: *mut *mut u8,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall4(
+            Sysno::CreateSharedMemory as usize,
+            count,
+            flags.bits() as usize,
+            id as usize,
+            out as usize,
+        ))
+    }
+}
+
+/// Maps an existing shared memory object into the process's address space.
+///
+/// The mapping can be removed with [`unmap_memory`], which must cover it entirely.
+///
+/// # Parameters
+///
+/// - `id`: The ID of the object, as returned by [`create_shared_memory`]. The current process
+///   must have created the object, or must have been granted access to it with
+///   [`grant_shared_memory`].
+///
+/// - `flags`: The protection of the mapped pages. [`ProtectionFlags::LAZY`] is ignored.
+///
+/// - `out`: The virtual address at which the object was mapped. This is written by the
+///   kernel.
+///
+/// - `size`: If not null, the size of the object, in bytes. This is written by the kernel.
+///
+/// # Errors
+///
+/// - `SHARED_MEMORY_NOT_FOUND` if the provided ID does not refer to an existing object, or if
+///   the current process has not been granted access to it.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_POINTER` if `out` does not reference writable memory, or if `size` is not null and
+///   does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system is out of physical memory, or if the process's address space
+///   has no room left for the object.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn map_shared_memory(
+    id: SharedMemoryId,
+    flags: ProtectionFlags,
+    out: *mut *mut u8,
+    size: *mut usize,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall4(
+            Sysno::MapSharedMemory as usize,
+            id,
+            flags.bits() as usize,
+            out as usize,
+            size as usize,
+        ))
+    }
+}
+
+/// Allows another process to map a shared memory object.
+///
+/// Access to an object is never revoked, but the object is still destroyed once it is not mapped
+/// anywhere anymore. Processes created with [`duplicate_process`] inherit the objects their
+/// parent has access to.
+///
+/// # Parameters
+///
+/// - `id`: The ID of the object. The current process must have access to it.
+///
+/// - `process_id`: The ID of the process that should be allowed to map the object.
+///
+/// # Errors
+///
+/// - `SHARED_MEMORY_NOT_FOUND` if the current process does not have access to the object.
+///
+/// - `PROCESS_NOT_FOUND` if `process_id` does not refer to an existing process.
+///
+/// - `OUT_OF_MEMORY` if the system is out of memory.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn grant_shared_memory(id: SharedMemoryId, process_id: ProcessId) -> SysResult {
+    unsafe { SysResult::from_raw(syscall2(Sysno::GrantSharedMemory as usize, id, process_id)) }
+}
+
+/// Creates a new process whose memory is a copy of the memory of the current process.
+///
+/// The new process starts with a single thread, which resumes right after the system call with
+/// the same registers as the calling thread. Other threads of the current process are not
+/// duplicated.
+///
+/// Memory is not copied eagerly. Instead, pages are shared between both processes until one of
+/// them writes to them. Shared memory objects remain shared between both processes, while
+/// framebuffers are not inherited. The exception handler of the current process is inherited,
+/// and the thread of the new process uses the same exception stack as the calling thread.
+///
+/// # Parameters
+///
+/// - `process_id`: A pointer to a [`ProcessId`] that will be written with the ID of the new
+///   process.
+///
+/// # Errors
+///
+/// - `INVALID_POINTER` if `process_id` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system is out of memory for the new process.
+///
+/// - `TOO_MANY_PROCESSES` if the maximum number of processes has been reached.
+///
+/// # Returns
+///
+/// In the current process, the ID of the new process is written to `process_id`. In the new
+/// process, `process_id` contains [`ProcessId::MAX`].
+#[inline]
+pub fn duplicate_process(process_id: *mut ProcessId) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall1(
+            Sysno::DuplicateProcess as usize,
+            process_id as usize,
+        ))
+    }
+}
+
+/// Maps a range of physical memory belonging to a device into the process's address space.
+///
+/// Only memory that the kernel knows belongs to a device can be mapped this way. This includes
+/// the memory regions described by the base address registers of PCI devices (see
+/// [`enumerate_pci_devices`]), as well as the regions that the firmware reports as reserved.
+///
+/// The mapping can be removed with [`unmap_memory`], which must cover it entirely. It is not
+/// inherited by processes created with [`duplicate_process`].
+///
+/// # Parameters
+///
+/// - `phys`: The physical address of the first byte to map. This must be aligned to the page
+///   size.
+///
+/// - `count`: The number of bytes to map. This must be aligned to the page size.
+///
+/// - `flags`: The protection of the mapped pages. [`ProtectionFlags::LAZY`] is ignored.
+///
+/// - `cache`: The caching policy to use for the mapped pages.
+///
+/// - `out`: The virtual address at which the memory was mapped. This is written by the kernel.
+///
+/// # Errors
+///
+/// - `MISSING_CAPABILITY` if the current process does not have the
+///   [`Capabilities::MAP_DEVICE_MEMORY`] capability.
+///
+/// - `INVALID_VALUE` if `phys` or `count` are not aligned to the page size, if `count` is zero,
+///   if `cache` is not a valid [`CachePolicy`], or if the requested range is not entirely part
+///   of the memory of a single device.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_POINTER` if `out` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system is out of physical memory, or if the process's address space
+///   has no room left for the mapping.
+///
+/// # Returns
+///
+/// Nothing.
+#[inline]
+pub fn map_device_memory(
+    phys: u64,
+    count: usize,
+    flags: ProtectionFlags,
+    cache: CachePolicy,
+    out: *mut *mut u8,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall5(
+            Sysno::MapDeviceMemory as usize,
+            phys as usize,
+            count,
+            flags.bits() as usize,
+            cache.as_raw() as usize,
+            out as usize,
+        ))
+    }
+}
+
+/// Removes capabilities from the current process.
+///
+/// Dropped capabilities can never be regained, and are not passed to the processes that the
+/// current process creates afterwards.
+///
+/// # Parameters
+///
+/// - `capabilities`: The capabilities to drop. Capabilities that the process does not have are
+///   ignored.
+///
+/// # Returns
+///
+/// Nothing. This function always succeeds.
+#[inline]
+pub fn drop_capabilities(capabilities: Capabilities) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall1(
+            Sysno::DropCapabilities as usize,
+            capabilities.bits() as usize,
+        ))
+    }
+}
+
+/// Allocates physically contiguous memory that devices can access directly, and maps it into
+/// the process's address space.
+///
+/// The memory remains at the same physical address for as long as it is mapped. It is released
+/// when it is unmapped with [`unmap_memory`] (which must cover it entirely), or when the process
+/// exits. It is not inherited by processes created with [`duplicate_process`].
+///
+/// # Parameters
+///
+/// - `count`: The number of bytes to allocate. This must be aligned to the page size.
+///
+/// - `prot`: The protection of the mapped pages. [`ProtectionFlags::LAZY`] is ignored.
+///
+/// - `flags`: Constraints on the physical memory to allocate.
+///
+/// - `virt`: The virtual address at which the memory was mapped. This is written by the kernel.
+///
+/// - `phys`: The physical address of the memory, which can be given to devices. This is written
+///   by the kernel.
+///
+/// # Errors
+///
+/// - `MISSING_CAPABILITY` if the current process does not have the
+///   [`Capabilities::ALLOCATE_DMA_MEMORY`] capability.
+///
+/// - `INVALID_VALUE` if `count` is not aligned to the page size, or if it is zero.
+///
+/// - `INVALID_VALUE` if the pages would be both writable and executable while
+///   [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is not set.
+///
+/// - `INVALID_POINTER` if `virt` or `phys` does not reference writable memory.
+///
+/// - `OUT_OF_MEMORY` if the system has no physical memory that respects the constraints, or if
+///   the process's address space has no room left for the mapping.
+///
+/// # Returns
+///
+/// Nothing.
+///
+/// The allocated memory is always filled with zeros.
+#[inline]
+pub fn allocate_dma_memory(
+    count: usize,
+    prot: ProtectionFlags,
+    flags: DmaFlags,
+    virt: *mut *mut u8,
+    phys: *mut u64,
+) -> SysResult {
+    unsafe {
+        SysResult::from_raw(syscall5(
+            Sysno::AllocateDmaMemory as usize,
+            count,
+            prot.bits() as usize,
+            flags.bits() as usize,
+            virt as usize,
+            phys as usize,
+        ))
+    }
+}
@@ -19,4 +19,32 @@ pub enum Sysno {
     UnmapMemory,
     /// See [`kernel_log`](crate::kernel_log).
     KernelLog,
+    /// See [`spawn_process`](crate::spawn_process).
+    SpawnProcess,
+    /// See [`spawn_thread`](crate::spawn_thread).
+    SpawnThread,
+    /// See [`despawn_thread`](crate::despawn_thread).
+    DespawnThread,
+    /// See [`set_exception_handler`](crate::set_exception_handler).
+    SetExceptionHandler,
+    /// See [`resume_context`](crate::resume_context).
+    ResumeContext,
+    /// See [`protect_memory`](crate::protect_memory).
+    ProtectMemory,
+    /// See [`list_mappings`](crate::list_mappings).
+    ListMappings,
+    /// See [`create_shared_memory`](crate::create_shared_memory).
+    CreateSharedMemory,
+    /// See [`map_shared_memory`](crate::map_shared_memory).
+    MapSharedMemory,
+    /// See [`duplicate_process`](crate::duplicate_process).
+    DuplicateProcess,
+    /// See [`map_device_memory`](crate::map_device_memory).
+    MapDeviceMemory,
+    /// See [`drop_capabilities`](crate::drop_capabilities).
+    DropCapabilities,
+    /// See [`allocate_dma_memory`](crate::allocate_dma_memory).
+    AllocateDmaMemory,
+    /// See [`grant_shared_memory`](crate::grant_shared_memory).
+    GrantSharedMemory,
 }
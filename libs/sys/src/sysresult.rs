@@ -106,8 +106,33 @@ define_error_codes! {
     /// The requested memory address is already mapped to another physical page
     /// and can therefore not be mapped again.
     ///
-    /// This error is also returned when a request to unmap a page is made, but the page is not
-    /// mapped to physical memory.
+    /// This error is also returned when a request to unmap a page (or to change its protection)
+    /// is made, but the page is not mapped to physical memory.
     "address already mapped"
     const ALREADY_MAPPED = 6;
+
+    /// The maximum number of processes that can run concurrently on the system has been
+    /// reached.
+    "too many processes"
+    const TOO_MANY_PROCESSES = 7;
+
+    /// The maximum number of threads that can run concurrently on the system has been reached.
+    "too many threads"
+    const TOO_MANY_THREADS = 8;
+
+    /// A thread was used as an argument to a system call, but that thread was not found
+    /// (i.e. it does not exist, it has exited, or it belongs to another process).
+    "thread not found"
+    const THREAD_NOT_FOUND = 9;
+
+    /// A shared memory object was used as an argument to a system call, but that object was not
+    /// found (i.e. it never existed, or it has been destroyed because it was not mapped anymore).
+    "shared memory object not found"
+    const SHARED_MEMORY_NOT_FOUND = 10;
+
+    /// A pointer passed to a system call references memory that the process cannot access in the
+    /// required way (i.e. the memory is not mapped, is not writable while the kernel needs to
+    /// write to it, or is not part of userland).
+    "invalid pointer"
+    const INVALID_POINTER = 11;
 }
@@ -1,36 +1,20 @@
 //! Provides ways to load the init process into memory.
 
+use crate::boot::oom;
 use crate::hcf::die;
 use crate::log;
-use crate::process::Process;
-
-#[cfg(feature = "init-elf")]
-mod elf;
-
-/// A possible file type, used to determine how to load the file into memory.
-enum FileType {
-    /// The type of the file is unknown.
-    Unknown,
-    /// The file is an ELF file.
-    Elf,
-}
-
-impl FileType {
-    /// Attempts to determine the type of the provided file.
-    pub fn of(file: &[u8]) -> Self {
-        if file.starts_with(b"\x7fELF") {
-            Self::Elf
-        } else {
-            Self::Unknown
-        }
-    }
-}
+use crate::process::{LoadError, Process, Registers};
 
 /// Loads a process from the provided file.
-pub fn load_any(file: &[u8], cmdline: &[u8]) -> Process {
-    match FileType::of(file) {
-        FileType::Elf => elf::load(file, cmdline),
-        FileType::Unknown => {
+///
+/// The registers the main thread of the process must start with are returned along with it.
+pub fn load_any(file: &[u8], cmdline: &[u8]) -> (Process, Registers) {
+    log::trace!("Loading the init process...");
+
+    match Process::load(file, cmdline) {
+        Ok(loaded) => loaded,
+        Err(LoadError::OutOfMemory) => oom(),
+        Err(LoadError::UnknownFormat) => {
             log::error!(
                 "\
                 The type of the init process could not be determined.\n\
@@ -42,5 +26,18 @@ pub fn load_any(file: &[u8], cmdline: &[u8]) -> Process {
             );
             die();
         }
+        Err(LoadError::Invalid(reason)) => {
+            log::error!("The init process is invalid: {}", reason);
+            die();
+        }
+        Err(LoadError::CommandLineTooLarge) => {
+            log::error!(
+                "\
+                The provided command line is larger than 4096 bytes. Larger command-line are not\n\
+                supported by the kernel due to the laziness of the kernel developers.\
+                ",
+            );
+            die();
+        }
     }
 }
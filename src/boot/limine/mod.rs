@@ -10,21 +10,22 @@
 
 use core::alloc::Layout;
 use core::arch::asm;
-use core::mem::size_of;
 use core::sync::atomic::AtomicU64;
 
 use limine::{File, FramebufferMemoryModel, MemmapEntry, MemmapType};
-use ruel_sys::{Framebuffer, FramebufferFormat};
-use x86_64::{sti, Efer, PageTable, PageTableEntry, PhysAddr, VirtAddr};
+use ruel_sys::{Capabilities, Framebuffer, FramebufferFormat};
+use x86_64::{sti, write_cr3, wrmsr, Efer, PageTable, PageTableEntry, PhysAddr, VirtAddr, PAT};
 
 use crate::boot::{handle_mapping_error, oom};
 use crate::cpu::paging::{
-    AddressSpace, AddressSpaceContext, HhdmToken, FOUR_KIB, HHDM_OFFSET, KERNEL_BIT, NOT_OWNED_BIT,
+    AddressSpace, AddressSpaceContext, HhdmToken, FOUR_KIB, HHDM_OFFSET, KERNEL_BIT,
+    KERNEL_HEAP_START, NOT_OWNED_BIT, PAT_LAYOUT,
 };
-use crate::global::{Framebuffers, Global, MemoryAllocator, OutOfMemory, Processes};
+use crate::cpu::trap::TrapFrame;
+use crate::global::{Framebuffers, Global, MemoryAllocator, OutOfMemory, Processes, SharedMemory};
 use crate::hcf::die;
+use crate::io::DeviceMemory;
 use crate::log;
-use crate::process::Registers;
 use crate::sync::Mutex;
 use crate::utility::array_vec::ArrayVec;
 use crate::utility::{BumpAllocator, HumanByteCount, PhysBumpAllocator};
@@ -113,7 +114,11 @@ unsafe extern "C" fn main() -> ! {
     }
 
     let mut usable_memory = ArrayVec::new_array();
-    validate_and_find_usable_segments(memory_map, &mut usable_memory);
+    let mut reclaimable_memory = ArrayVec::new_array();
+    validate_and_find_usable_segments(memory_map, &mut usable_memory, &mut reclaimable_memory);
+
+    let mut reserved_memory = ArrayVec::new_array();
+    find_reserved_segments(memory_map, &mut reserved_memory);
 
     let bootloader_hhdm = token
         .hhdm()
@@ -249,6 +254,9 @@ unsafe extern "C" fn main() -> ! {
     // Make sure that the NO_EXECUTE bit on pages is available.
     Efer::read().union(Efer::NO_EXECUTE).write();
 
+    // Make the write-combining memory type available to page tables.
+    unsafe { wrmsr(PAT, PAT_LAYOUT) };
+
     // Create the kernel's address space.
     let address_space =
         unsafe {
@@ -284,6 +292,8 @@ unsafe extern "C" fn main() -> ! {
                 kernel_stack_top,
                 usable_framebuffers,
                 usable_memory,
+                reclaimable_memory,
+                reserved_memory,
                 kernel_physical_base: kernel_address.physical_base,
                 init_process: core::slice::from_raw_parts(
                     (init_program_phys_addr as usize + HHDM_OFFSET) as *const u8,
@@ -321,6 +331,9 @@ unsafe extern "C" fn main() -> ! {
 ///
 /// Because virtual-memory references are invalidated, we need to copy everything we need
 /// from the bootloader's stack to the kernel's stack (or save their physical addresses).
+///
+/// Nothing in this structure may reference the bootloader reclaimable memory, as it is given to
+/// the global allocator once the kernel has finished booting.
 struct ToNewStack {
     /// The allocator that's being used to allocate memory during the booting process.
     bootstrap_allocator: PhysBumpAllocator,
@@ -333,6 +346,16 @@ struct ToNewStack {
     /// Those segments do include the segment that is currently used by the bootstrap
     /// allocator. We need to be careful not to mark the pages it has already issued as free.
     usable_memory: ArrayVec<MemmapEntry, 8>,
+    /// The segments that were used by the bootloader or the firmware during boot, and that are
+    /// given to the global allocator once the kernel no longer needs them.
+    ///
+    /// # Remarks
+    ///
+    /// Those segments are not necessarily page-aligned.
+    reclaimable_memory: ArrayVec<MemmapEntry, 16>,
+    /// The segments that the bootloader reported as reserved, which are assumed to belong to
+    /// devices.
+    reserved_memory: ArrayVec<DeviceMemory, 16>,
     /// The usable framebuffers.
     usable_framebuffers: ArrayVec<Framebuffer, 4>,
     /// The physical address of the kernel image.
@@ -353,6 +376,8 @@ extern "C" fn with_new_stack(package: *mut ToNewStack) -> ! {
         bootstrap_allocator,
         kernel_stack_top,
         usable_memory,
+        reclaimable_memory,
+        reserved_memory,
         kernel_physical_base,
         init_process,
         init_process_cmdline,
@@ -370,13 +395,22 @@ extern "C" fn with_new_stack(package: *mut ToNewStack) -> ! {
     // =============================================================================================
     crate::cpu::gdt::init(&mut bootstrap_allocator, kernel_stack_top).unwrap_or_else(|_| oom());
     crate::cpu::idt::init(&mut bootstrap_allocator).unwrap_or_else(|_| oom());
+    crate::cpu::protection::init();
     let pci_devices = crate::io::pci::init(&mut bootstrap_allocator).unwrap_or_else(|_| oom());
+    let device_memory =
+        crate::io::collect_device_memory(&mut bootstrap_allocator, pci_devices, &reserved_memory)
+            .unwrap_or_else(|_| oom());
 
     // =============================================================================================
     // Global Kernel State
     // =============================================================================================
     let processes = Processes::new(&mut bootstrap_allocator).unwrap_or_else(|_| oom());
-    let allocator = initialize_global_allocator(&usable_memory, bootstrap_allocator, hhdm);
+    let allocator = initialize_global_allocator(
+        &usable_memory,
+        &reclaimable_memory,
+        bootstrap_allocator,
+        hhdm,
+    );
 
     log::trace!("Initializing the global kernel state...");
     let glob = crate::global::init(
@@ -386,8 +420,10 @@ extern "C" fn with_new_stack(package: *mut ToNewStack) -> ! {
             address_space,
             processes,
             framebuffers: Framebuffers::new(usable_framebuffers),
+            shared_memory: SharedMemory::new(),
             upticks: AtomicU64::new(0),
             pci_devices,
+            device_memory,
         },
         kernel_stack_top,
     );
@@ -400,41 +436,36 @@ extern "C" fn with_new_stack(package: *mut ToNewStack) -> ! {
     // =============================================================================================
     // Init Program Loading
     // =============================================================================================
-    let id = glob
-        .processes
-        .spawn_process(crate::boot::init_process::load_any(init_process, init_process_cmdline))
-        .unwrap();
-    glob.processes.schedule(id).unwrap();
+    let (mut process, registers) =
+        crate::boot::init_process::load_any(init_process, init_process_cmdline);
+    process.capabilities = Capabilities::all();
+    let (_, thread) = glob.processes.spawn_process(process, registers).unwrap();
+    glob.processes.schedule(thread).unwrap();
+
+    // =============================================================================================
+    // Boot Memory Reclamation
+    // =============================================================================================
+    // SAFETY:
+    //  Everything the kernel needed from the bootloader has been copied out of the reclaimable
+    //  segments (the memory map, the framebuffers, and the command-line of the init program).
+    //  The init program itself is stored with the kernel image, and the ACPI tables are not
+    //  used by the kernel.
+    unsafe { reclaim_boot_memory(&reclaimable_memory, &glob.allocator) };
 
     // Allow interrupts.
     sti();
 
     log::info!("Spawning the init process!");
 
-    unsafe {
-        let (l4_table, registers) = {
-            let current = glob.processes.current();
-            (current.address_space.l4_table(), current.registers)
-        };
+    let mut frame = TrapFrame::default();
+    let l4_table = glob.processes.with_current(|process, thread| {
+        thread.registers.restore(&mut frame);
+        process.address_space.l4_table()
+    });
 
-        asm!(
-            "
-            mov cr3, {address_space}
-            mov rcx, [r11 + 8 * {RIP_INDEX}]
-            mov rsp, [r11 + 8 * {RSP_INDEX}]
-            mov rbp, [r11 + 8 * {RBP_INDEX}]
-            mov rdi, [r11 + 8 * {RDI_INDEX}]
-            mov r11, 0x202
-            sysretq
-            ",
-            in("r11") &registers,
-            address_space = in(reg) l4_table,
-            RIP_INDEX = const Registers::RIP_INDEX,
-            RSP_INDEX = const Registers::RSP_INDEX,
-            RBP_INDEX = const Registers::RBP_INDEX,
-            RDI_INDEX = const Registers::RDI_INDEX,
-            options(noreturn)
-        );
+    unsafe {
+        write_cr3(l4_table);
+        crate::cpu::trap::resume(&frame);
     }
 }
 
@@ -562,7 +593,9 @@ fn find_memory_upper_bound(memory_map: &[&MemmapEntry]) -> PhysAddr {
     memory_map
         .iter()
         .filter(|entry| {
-            entry.ty == MemmapType::USABLE || entry.ty == MemmapType::BOOTLOADER_RECLAIMABLE
+            entry.ty == MemmapType::USABLE
+                || entry.ty == MemmapType::BOOTLOADER_RECLAIMABLE
+                || entry.ty == MemmapType::ACPI_RECLAIMABLE
         })
         .map(|entry| entry.base + entry.length)
         .max()
@@ -571,15 +604,21 @@ fn find_memory_upper_bound(memory_map: &[&MemmapEntry]) -> PhysAddr {
 
 /// Validates the memory map provided by the bootloader.
 ///
+/// Usable segments are collected in `usable_memory`, and the segments that can be reclaimed once
+/// the kernel has finished booting are collected in `reclaimable_memory`.
+///
 /// If the map is found to break some of the invariants specified in the protocol, the function
 /// stops the CPU.
 fn validate_and_find_usable_segments(
     memory_map: &[&MemmapEntry],
     usable_memory: &mut ArrayVec<MemmapEntry, 8>,
+    reclaimable_memory: &mut ArrayVec<MemmapEntry, 16>,
 ) {
     let mut last_entry: Option<&MemmapEntry> = None;
     let mut too_many_segments = false;
+    let mut ignored_reclaimable_segments = 0;
     let mut total_usable_memory = 0;
+    let mut total_reclaimable_memory = 0;
 
     for entry in memory_map {
         if let Some(last_entry) = last_entry {
@@ -630,18 +669,19 @@ fn validate_and_find_usable_segments(
                     die();
                 }
             }
+        }
 
-            if let Some(last_usable) = usable_memory.last_mut() {
-                if last_usable.base + last_usable.length == entry.base {
-                    last_usable.length += entry.length;
-                } else {
-                    too_many_segments |= usable_memory.try_push(**entry).is_err();
-                }
+        if entry.ty == MemmapType::USABLE {
+            too_many_segments |= !push_segment(usable_memory, entry);
+            total_usable_memory += entry.length;
+        } else if entry.ty == MemmapType::BOOTLOADER_RECLAIMABLE
+            || entry.ty == MemmapType::ACPI_RECLAIMABLE
+        {
+            if push_segment(reclaimable_memory, entry) {
+                total_reclaimable_memory += entry.length;
             } else {
-                too_many_segments |= usable_memory.try_push(**entry).is_err();
+                ignored_reclaimable_segments += 1;
             }
-
-            total_usable_memory += entry.length;
         }
 
         last_entry = Some(entry);
@@ -674,6 +714,66 @@ fn validate_and_find_usable_segments(
     } else {
         log::info!("Available memory: {}", HumanByteCount(total_usable_memory));
     }
+
+    if ignored_reclaimable_segments != 0 {
+        log::warn!(
+            "{} reclaimable memory segments were ignored. They won't be reclaimed after boot.",
+            ignored_reclaimable_segments,
+        );
+    }
+
+    log::info!(
+        "Reclaimable memory: {}",
+        HumanByteCount(total_reclaimable_memory),
+    );
+}
+
+/// Appends `entry` to `segments`, merging it with the last segment when they are contiguous.
+///
+/// # Returns
+///
+/// `false` if `segments` is full and the entry could not be added.
+fn push_segment<const N: usize>(
+    segments: &mut ArrayVec<MemmapEntry, N>,
+    entry: &MemmapEntry,
+) -> bool {
+    if let Some(last) = segments.last_mut() {
+        if last.base + last.length == entry.base {
+            last.length += entry.length;
+            return true;
+        }
+    }
+
+    segments.try_push(*entry).is_ok()
+}
+
+/// Collects the segments of the memory map that are reserved, and that are therefore assumed to
+/// belong to devices rather than to RAM.
+fn find_reserved_segments(
+    memory_map: &[&MemmapEntry],
+    reserved_memory: &mut ArrayVec<DeviceMemory, 16>,
+) {
+    let mut ignored = 0;
+
+    for entry in memory_map {
+        if entry.ty != MemmapType::RESERVED {
+            continue;
+        }
+
+        if reserved_memory
+            .try_push(DeviceMemory::covering(entry.base, entry.length))
+            .is_err()
+        {
+            ignored += 1;
+        }
+    }
+
+    if ignored != 0 {
+        log::warn!(
+            "{} reserved memory segments were ignored. Processes won't be able to map them.",
+            ignored,
+        );
+    }
 }
 
 /// Initializes the global allocator.
@@ -685,29 +785,33 @@ fn validate_and_find_usable_segments(
 /// pages is transferred to the global allocator.
 fn initialize_global_allocator(
     usable_memory: &[MemmapEntry],
+    reclaimable_memory: &[MemmapEntry],
     mut bootstrap_allocator: BumpAllocator,
     hhdm: HhdmToken,
 ) -> MemoryAllocator {
     log::trace!("Initializing the global allocator...");
 
-    let pages_needed = usable_memory
+    // The reclaimable segments are given to the allocator later on, but it must already be able
+    // to keep track of their pages.
+    let memory_upper_bound = usable_memory
         .iter()
-        .map(|e| e.length as usize / FOUR_KIB)
-        .sum::<usize>();
+        .chain(reclaimable_memory)
+        .map(|entry| entry.base + entry.length)
+        .max()
+        .unwrap_or(0);
 
     log::trace!(
-        "The global allocator will need {} pages to store the free list ({}).",
-        pages_needed,
-        HumanByteCount(pages_needed as u64 * size_of::<PhysAddr>() as u64),
+        "The global allocator will need {} to keep track of the state of each page.",
+        HumanByteCount(MemoryAllocator::metadata_size(memory_upper_bound) as u64),
     );
 
     let mut allocator = unsafe {
-        MemoryAllocator::empty(hhdm, &mut bootstrap_allocator, pages_needed)
+        MemoryAllocator::empty(hhdm, &mut bootstrap_allocator, memory_upper_bound)
             .unwrap_or_else(|_| oom())
     };
 
     // Compute the range of pages that have been used by the bootstrap allocator to avoid
-    // pushing them to the free list later on.
+    // giving them to the global allocator later on.
     let used_start = x86_64::page_align_down(bootstrap_allocator.inner.top() as usize) as PhysAddr;
     let used_stop = bootstrap_allocator.inner.original_top();
 
@@ -717,22 +821,48 @@ fn initialize_global_allocator(
     );
 
     for entry in usable_memory {
-        let mut base = entry.base;
-        let mut length = entry.length;
+        let start = entry.base;
+        let end = entry.base + entry.length;
 
-        while length != 0 {
-            if base < used_start || base >= used_stop {
-                unsafe { allocator.assume_available(base) };
-            }
-
-            base += FOUR_KIB as u64;
-            length -= FOUR_KIB as u64;
+        unsafe {
+            allocator.assume_available(start, end.min(used_start).max(start));
+            allocator.assume_available(start.max(used_stop).min(end), end);
         }
     }
 
     allocator
 }
 
+/// Gives the segments that were used by the bootloader and the firmware during boot to the
+/// global allocator.
+///
+/// # Safety
+///
+/// Nothing stored in those segments may be used anymore.
+unsafe fn reclaim_boot_memory(
+    reclaimable_memory: &[MemmapEntry],
+    allocator: &Mutex<MemoryAllocator>,
+) {
+    log::trace!("Reclaiming the memory used during boot...");
+
+    let mut allocator = allocator.lock();
+    let mut reclaimed = 0;
+
+    for entry in reclaimable_memory {
+        // ACPI reclaimable segments are not necessarily page-aligned. Partial pages at their
+        // boundaries are simply not reclaimed.
+        let start = x86_64::page_align_up(entry.base as usize) as PhysAddr;
+        let end = x86_64::page_align_down((entry.base + entry.length) as usize) as PhysAddr;
+
+        if start < end {
+            unsafe { allocator.assume_available(start, end) };
+            reclaimed += end - start;
+        }
+    }
+
+    log::info!("Reclaimed memory: {}", HumanByteCount(reclaimed));
+}
+
 /// Creates the address space of the kernel.
 ///
 /// # Arguments
@@ -814,6 +944,16 @@ pub unsafe fn create_kernel_address_space(
         )
         .unwrap_or_else(|err| handle_mapping_error(err));
 
+    // Create the page directory of the kernel heap right away. The heap is mapped lazily, but
+    // because the directory is shared by every address space created from now on, the pages
+    // mapped later on will be visible in all of them.
+    address_space
+        .make_1gib_entry(
+            KERNEL_HEAP_START,
+            PageTableEntry::WRITABLE | PageTableEntry::GLOBAL | KERNEL_BIT,
+        )
+        .unwrap_or_else(|err| handle_mapping_error(err));
+
     address_space.leak()
 }
 
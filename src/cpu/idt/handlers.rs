@@ -2,95 +2,159 @@
 
 use core::sync::atomic::Ordering::Relaxed;
 
-use ruel_sys::WakeUpPS2MouseFlags;
-use x86_64::{read_cr2, InterruptStackFrame, PageFaultError};
+use ruel_sys::{WakeUpPS2MouseFlags, FAULT_EXIT_CODE};
+use x86_64::{read_cr2, PageFaultError};
 
 use crate::cpu::idt::pic::Irq;
-use crate::global::GlobalToken;
+use crate::cpu::trap::TrapFrame;
+use crate::global::{GlobalToken, OutOfMemory};
 use crate::io::ps2::{self, PS2Status};
-use crate::process::Process;
+use crate::log;
+use crate::process::{recover_user_access, resolve_page_fault};
+
+pub extern "C" fn division_error(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "DIVISION_ERROR");
+    }
 
-pub extern "x86-interrupt" fn division_error(_stack_frame: InterruptStackFrame) {
     panic!("Received a DIVISION_ERROR fault.");
 }
 
-pub extern "x86-interrupt" fn debug(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn debug(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "DEBUG");
+    }
+
     panic!("Received a DEBUG fault/trap.");
 }
 
-pub extern "x86-interrupt" fn non_maskable_interrupt(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn non_maskable_interrupt(_frame: &mut TrapFrame) {
     panic!("Received a NON_MASKABLE_INTERRUPT interrupt.");
 }
 
-pub extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "BREAKPOINT");
+    }
+
     panic!("Received a BREAKPOINT trap.");
 }
 
-pub extern "x86-interrupt" fn overflow(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn overflow(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "OVERFLOW");
+    }
+
     panic!("Received an OVERFLOW trap.");
 }
 
-pub extern "x86-interrupt" fn bound_range_exceeded(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn bound_range_exceeded(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "BOUND_RANGE_EXCEEDED");
+    }
+
     panic!("Received a BOUND_RANGE_EXCEEDED fault.");
 }
 
-pub extern "x86-interrupt" fn invalid_opcode(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn invalid_opcode(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "INVALID_OPCODE");
+    }
+
     panic!("Received an INVALID_OPCODE fault.");
 }
 
-pub extern "x86-interrupt" fn device_not_available(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn device_not_available(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "DEVICE_NOT_AVAILABLE");
+    }
+
     panic!("Received a DEVICE_NOT_AVAILABLE fault.");
 }
 
-pub extern "x86-interrupt" fn double_fault(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
+pub extern "C" fn double_fault(_frame: &mut TrapFrame) -> ! {
     panic!("Received a DOUBLE_FAULT fault.");
 }
 
-pub extern "x86-interrupt" fn invalid_tss(_stack_frame: InterruptStackFrame, error_code: u64) {
+pub extern "C" fn invalid_tss(frame: &mut TrapFrame) {
     panic!(
         "Received an INVALID_TSS fault with error code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn segment_not_present(
-    _stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn segment_not_present(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "SEGMENT_NOT_PRESENT");
+    }
+
     panic!(
         "Received a SEGMENT_NOT_PRESENT fault with error code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn stack_segment_fault(
-    _stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn stack_segment_fault(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "STACK_SEGMENT_FAULT");
+    }
+
     panic!(
         "Received a STACK_SEGMENT_FAULT fault with error code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn general_protection_fault(
-    frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn general_protection_fault(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "GENERAL_PROTECTION_FAULT");
+    }
+
     panic!(
         "\
         Received a GENERAL_PROTECTION_FAULT fault with error code {:#x}.\n\
         > RIP = {:#x}\n\
         > RSP = {:#x}\
         ",
-        error_code, frame.ip, frame.sp,
+        frame.error_code, frame.rip, frame.rsp,
     );
 }
 
-pub extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: PageFaultError) {
+pub extern "C" fn page_fault(frame: &mut TrapFrame) {
+    let error_code = PageFaultError::from_bits_retain(frame.error_code as u32);
+    let address = read_cr2() as usize;
+
+    // The page might have been reserved lazily or shared with another process, in which case
+    // the fault is expected. This also applies when the kernel accesses userspace memory.
+    let resolved = resolve_page_fault(address, error_code);
+
+    if frame.is_user() {
+        match resolved {
+            Ok(true) => return,
+            Ok(false) => (),
+            Err(OutOfMemory) => {
+                log::warn!(
+                    "Process {} ran out of memory and will be terminated (CR2 = {:#x}).",
+                    GlobalToken::get().processes.current_id(),
+                    address,
+                );
+                return terminate_current(frame);
+            }
+        }
+
+        return user_fault(frame, "PAGE_FAULT");
+    }
+
+    if let Ok(true) = resolved {
+        return;
+    }
+
+    // The kernel might have been accessing userspace memory on behalf of a process, in which
+    // case the access is aborted rather than bringing the whole system down.
+    if recover_user_access(frame) {
+        return;
+    }
+
     panic!(
         "\
         Received a PAGE_FAULT fault.\n\
@@ -99,81 +163,142 @@ pub extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code:
         > RSP     = {:#x}\n\
         > ADDRESS = {:#x}\
         ",
-        error_code,
-        frame.ip,
-        frame.sp,
-        read_cr2(),
+        error_code, frame.rip, frame.rsp, address,
     );
 }
 
-pub extern "x86-interrupt" fn x87_floating_point(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn x87_floating_point(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "X87_FLOATING_POINT");
+    }
+
     panic!("Received an X87_FLOATING_POINT fault.");
 }
 
-pub extern "x86-interrupt" fn alignment_check(_stack_frame: InterruptStackFrame, error_code: u64) {
+pub extern "C" fn alignment_check(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "ALIGNMENT_CHECK");
+    }
+
     panic!(
         "Received an ALIGNMENT_CHECK fault with error code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn machine_check(_stack_frame: InterruptStackFrame) -> ! {
+pub extern "C" fn machine_check(_frame: &mut TrapFrame) -> ! {
     panic!("Received a MACHINE_CHECK fault.");
 }
 
-pub extern "x86-interrupt" fn simd_floating_point(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn simd_floating_point(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "SIMD_FLOATING_POINT");
+    }
+
     panic!("Received an SIMD_FLOATING_POINT fault.");
 }
 
-pub extern "x86-interrupt" fn virtualization(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn virtualization(_frame: &mut TrapFrame) {
     panic!("Received a VIRTUALIZATION fault.");
 }
 
-pub extern "x86-interrupt" fn control_protection(
-    _stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn control_protection(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return user_fault(frame, "CONTROL_PROTECTION_EXCEPTION");
+    }
+
     panic!(
         "Received a CONTROL_PROTECTION_EXCEPTION fault with error code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn hypervisor_injection(_stack_frame: InterruptStackFrame) {
+pub extern "C" fn hypervisor_injection(_frame: &mut TrapFrame) {
     panic!("Received a HYPERVISOR_INJECTION_EXCEPTION fault.");
 }
 
-pub extern "x86-interrupt" fn vmm_communication(
-    _stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn vmm_communication(frame: &mut TrapFrame) {
     panic!(
         "Received a VMM_COMMUNICATION_EXCEPTION fault with erro code {:#x}.",
-        error_code
+        frame.error_code
     );
 }
 
-pub extern "x86-interrupt" fn security_exception(
-    _stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
+pub extern "C" fn security_exception(frame: &mut TrapFrame) {
     panic!(
         "Received a SECURITY_EXCEPTION fault with error code {:#x}.",
-        error_code
+        frame.error_code
+    );
+}
+
+/// Handles a CPU exception that was triggered by userspace code.
+///
+/// If the process has registered an exception handler able to handle the exception, the
+/// exception is delivered to it. Otherwise, the offending process is terminated with
+/// [`FAULT_EXIT_CODE`] and another thread is scheduled in its place. The rest of the system
+/// keeps running.
+fn user_fault(frame: &mut TrapFrame, name: &str) {
+    let glob = GlobalToken::get();
+    let process_id = glob.processes.current_id();
+
+    if glob.processes.with_current(|process, thread| {
+        process.deliver_exception(thread, frame, read_cr2() as usize)
+    }) {
+        return;
+    }
+
+    log::warn!(
+        "\
+        Process {} received a {} fault and will be terminated.\n\
+        > VECTOR = {:#x}\n\
+        > ERROR  = {:#x}\n\
+        > RIP    = {:#x}\n\
+        > RSP    = {:#x}\n\
+        > CR2    = {:#x}\
+        ",
+        process_id,
+        name,
+        frame.vector,
+        frame.error_code,
+        frame.rip,
+        frame.rsp,
+        read_cr2(),
     );
+
+    terminate_current(frame);
 }
 
-pub extern "x86-interrupt" fn pic_timer(_frame: InterruptStackFrame) {
+/// Terminates the current process with [`FAULT_EXIT_CODE`] and schedules another thread in its
+/// place.
+fn terminate_current(frame: &mut TrapFrame) {
+    let glob = GlobalToken::get();
+    let process_id = glob.processes.current_id();
+
+    let process = glob
+        .processes
+        .despawn_process(process_id, FAULT_EXIT_CODE)
+        .expect("the current process does not exist");
+    process.release(glob, process_id);
+
+    glob.processes.reschedule(frame, true);
+}
+
+pub extern "C" fn pic_timer(frame: &mut TrapFrame) {
     let glob = GlobalToken::get();
     assert!(
         glob.upticks.fetch_add(1, Relaxed) != u64::MAX,
         "the uptime counter overflowed"
     );
-    glob.processes.for_each_mut(Process::tick);
+    glob.processes.tick();
     super::pic::end_of_interrupt(Irq::Timer);
+
+    // Only preempt userspace code. The kernel itself is never preempted.
+    if frame.is_user() {
+        glob.processes.reschedule(frame, true);
+    }
 }
 
-pub extern "x86-interrupt" fn pic_ps2_keyboard(_frame: InterruptStackFrame) {
+pub extern "C" fn pic_ps2_keyboard(_frame: &mut TrapFrame) {
     let glob = GlobalToken::get();
 
     #[cfg(debug_assertions)]
@@ -191,10 +316,10 @@ pub extern "x86-interrupt" fn pic_ps2_keyboard(_frame: InterruptStackFrame) {
     super::pic::end_of_interrupt(Irq::PS2Keyboard);
 }
 
-pub extern "x86-interrupt" fn pic_ps2_mouse(_frame: InterruptStackFrame) {
+pub extern "C" fn pic_ps2_mouse(_frame: &mut TrapFrame) {
     let glob = GlobalToken::get();
 
-    crate::log::trace!("mouse");
+    log::trace!("mouse");
 
     debug_assert!(ps2::status()
         .contains(PS2Status::OUTPUT_BUFFER_FULL | PS2Status::AUX_OUTPUT_BUFFER_FULL));
@@ -17,6 +17,155 @@ mod handlers;
 mod pic;
 pub mod pit;
 
+/// The entry points of the interrupt service routines defined in the [`handlers`] module.
+///
+/// Those save the state of the interrupted program in a [`TrapFrame`] before calling the
+/// actual handler.
+///
+/// [`TrapFrame`]: crate::cpu::trap::TrapFrame
+mod entries {
+    use x86_64::Exception;
+
+    use super::handlers;
+    use super::pic::Irq;
+    use super::PIC_OFFSET;
+    use crate::cpu::trap::interrupt_entry;
+
+    interrupt_entry!(
+        division_error,
+        Exception::DivisionError,
+        handlers::division_error
+    );
+    interrupt_entry!(debug, Exception::Debug, handlers::debug);
+    interrupt_entry!(
+        non_maskable_interrupt,
+        Exception::NonMaskableInterrupt,
+        handlers::non_maskable_interrupt
+    );
+    interrupt_entry!(
+        breakpoint_handler,
+        Exception::Breakpoint,
+        handlers::breakpoint_handler
+    );
+    interrupt_entry!(overflow, Exception::Overflow, handlers::overflow);
+    interrupt_entry!(
+        bound_range_exceeded,
+        Exception::BoundRangeExceeded,
+        handlers::bound_range_exceeded
+    );
+    interrupt_entry!(
+        invalid_opcode,
+        Exception::InvalidOpcode,
+        handlers::invalid_opcode
+    );
+    interrupt_entry!(
+        device_not_available,
+        Exception::DeviceNotAvailable,
+        handlers::device_not_available
+    );
+    interrupt_entry!(
+        double_fault,
+        Exception::DoubleFault,
+        handlers::double_fault,
+        error_code
+    );
+    interrupt_entry!(
+        invalid_tss,
+        Exception::InvalidTss,
+        handlers::invalid_tss,
+        error_code
+    );
+    interrupt_entry!(
+        segment_not_present,
+        Exception::SegmentNotPresent,
+        handlers::segment_not_present,
+        error_code
+    );
+    interrupt_entry!(
+        stack_segment_fault,
+        Exception::StackSegmentFault,
+        handlers::stack_segment_fault,
+        error_code
+    );
+    interrupt_entry!(
+        general_protection_fault,
+        Exception::GeneralProtectionFault,
+        handlers::general_protection_fault,
+        error_code
+    );
+    interrupt_entry!(
+        page_fault,
+        Exception::PageFault,
+        handlers::page_fault,
+        error_code
+    );
+    interrupt_entry!(
+        x87_floating_point,
+        Exception::X87FloatingPoint,
+        handlers::x87_floating_point
+    );
+    interrupt_entry!(
+        alignment_check,
+        Exception::AlignmentCheck,
+        handlers::alignment_check,
+        error_code
+    );
+    interrupt_entry!(
+        machine_check,
+        Exception::MachineCheck,
+        handlers::machine_check
+    );
+    interrupt_entry!(
+        simd_floating_point,
+        Exception::SimdFloatingPoint,
+        handlers::simd_floating_point
+    );
+    interrupt_entry!(
+        virtualization,
+        Exception::VirtualizationException,
+        handlers::virtualization
+    );
+    interrupt_entry!(
+        control_protection,
+        Exception::ControlProtection,
+        handlers::control_protection,
+        error_code
+    );
+    interrupt_entry!(
+        hypervisor_injection,
+        Exception::HypervisorInjection,
+        handlers::hypervisor_injection
+    );
+    interrupt_entry!(
+        vmm_communication,
+        Exception::VmmCommunication,
+        handlers::vmm_communication,
+        error_code
+    );
+    interrupt_entry!(
+        security_exception,
+        Exception::SecurityException,
+        handlers::security_exception,
+        error_code
+    );
+
+    interrupt_entry!(
+        pic_timer,
+        PIC_OFFSET + Irq::Timer as u8,
+        handlers::pic_timer
+    );
+    interrupt_entry!(
+        pic_ps2_keyboard,
+        PIC_OFFSET + Irq::PS2Keyboard as u8,
+        handlers::pic_ps2_keyboard
+    );
+    interrupt_entry!(
+        pic_ps2_mouse,
+        PIC_OFFSET + Irq::PS2Mouse as u8,
+        handlers::pic_ps2_mouse
+    );
+}
+
 /// The offset used by the PIC to remap the interrupts.
 ///
 /// The next 16 entries in the IDT are reserved for the PIC.
@@ -29,33 +178,33 @@ pub fn init(bootstrap_allocator: &mut BumpAllocator) -> Result<(), OutOfMemory>
     log::trace!("IDT allocated at address: {:p}", idt);
 
     // Initilaize the IDT with our handlers.
-    idt[Exception::DivisionError] = trap_gate(handlers::division_error as usize);
-    idt[Exception::Debug] = trap_gate(handlers::debug as usize);
-    idt[Exception::NonMaskableInterrupt] = int_gate(handlers::non_maskable_interrupt as usize);
-    idt[Exception::Breakpoint] = trap_gate(handlers::breakpoint_handler as usize);
-    idt[Exception::Overflow] = trap_gate(handlers::overflow as usize);
-    idt[Exception::BoundRangeExceeded] = trap_gate(handlers::bound_range_exceeded as usize);
-    idt[Exception::InvalidOpcode] = trap_gate(handlers::invalid_opcode as usize);
-    idt[Exception::DeviceNotAvailable] = trap_gate(handlers::device_not_available as usize);
+    idt[Exception::DivisionError] = trap_gate(entries::division_error as usize);
+    idt[Exception::Debug] = trap_gate(entries::debug as usize);
+    idt[Exception::NonMaskableInterrupt] = int_gate(entries::non_maskable_interrupt as usize);
+    idt[Exception::Breakpoint] = trap_gate(entries::breakpoint_handler as usize);
+    idt[Exception::Overflow] = trap_gate(entries::overflow as usize);
+    idt[Exception::BoundRangeExceeded] = trap_gate(entries::bound_range_exceeded as usize);
+    idt[Exception::InvalidOpcode] = trap_gate(entries::invalid_opcode as usize);
+    idt[Exception::DeviceNotAvailable] = trap_gate(entries::device_not_available as usize);
     idt[Exception::DoubleFault] = double_fault_gate();
-    idt[Exception::InvalidTss] = trap_gate(handlers::invalid_tss as usize);
-    idt[Exception::SegmentNotPresent] = trap_gate(handlers::segment_not_present as usize);
-    idt[Exception::StackSegmentFault] = trap_gate(handlers::stack_segment_fault as usize);
-    idt[Exception::GeneralProtectionFault] = trap_gate(handlers::general_protection_fault as usize);
-    idt[Exception::PageFault] = trap_gate(handlers::page_fault as usize);
-    idt[Exception::X87FloatingPoint] = trap_gate(handlers::x87_floating_point as usize);
-    idt[Exception::AlignmentCheck] = trap_gate(handlers::alignment_check as usize);
-    idt[Exception::MachineCheck] = trap_gate(handlers::machine_check as usize);
-    idt[Exception::SimdFloatingPoint] = trap_gate(handlers::simd_floating_point as usize);
-    idt[Exception::VirtualizationException] = trap_gate(handlers::virtualization as usize);
-    idt[Exception::ControlProtection] = trap_gate(handlers::control_protection as usize);
-    idt[Exception::HypervisorInjection] = trap_gate(handlers::hypervisor_injection as usize);
-    idt[Exception::VmmCommunication] = trap_gate(handlers::vmm_communication as usize);
-    idt[Exception::SecurityException] = trap_gate(handlers::security_exception as usize);
-
-    idt[PIC_OFFSET + Irq::Timer as u8] = int_gate(handlers::pic_timer as usize);
-    idt[PIC_OFFSET + Irq::PS2Keyboard as u8] = int_gate(handlers::pic_ps2_keyboard as usize);
-    idt[PIC_OFFSET + Irq::PS2Mouse as u8] = int_gate(handlers::pic_ps2_mouse as usize);
+    idt[Exception::InvalidTss] = trap_gate(entries::invalid_tss as usize);
+    idt[Exception::SegmentNotPresent] = trap_gate(entries::segment_not_present as usize);
+    idt[Exception::StackSegmentFault] = trap_gate(entries::stack_segment_fault as usize);
+    idt[Exception::GeneralProtectionFault] = trap_gate(entries::general_protection_fault as usize);
+    idt[Exception::PageFault] = trap_gate(entries::page_fault as usize);
+    idt[Exception::X87FloatingPoint] = trap_gate(entries::x87_floating_point as usize);
+    idt[Exception::AlignmentCheck] = trap_gate(entries::alignment_check as usize);
+    idt[Exception::MachineCheck] = trap_gate(entries::machine_check as usize);
+    idt[Exception::SimdFloatingPoint] = trap_gate(entries::simd_floating_point as usize);
+    idt[Exception::VirtualizationException] = trap_gate(entries::virtualization as usize);
+    idt[Exception::ControlProtection] = trap_gate(entries::control_protection as usize);
+    idt[Exception::HypervisorInjection] = trap_gate(entries::hypervisor_injection as usize);
+    idt[Exception::VmmCommunication] = trap_gate(entries::vmm_communication as usize);
+    idt[Exception::SecurityException] = trap_gate(entries::security_exception as usize);
+
+    idt[PIC_OFFSET + Irq::Timer as u8] = int_gate(entries::pic_timer as usize);
+    idt[PIC_OFFSET + Irq::PS2Keyboard as u8] = int_gate(entries::pic_ps2_keyboard as usize);
+    idt[PIC_OFFSET + Irq::PS2Mouse as u8] = int_gate(entries::pic_ps2_mouse as usize);
 
     match crate::io::ps2::init() {
         Ok(()) => (),
@@ -93,7 +242,7 @@ fn int_gate(handler: VirtAddr) -> GateDesc {
 /// Creates the gate for the double fault handler.
 fn double_fault_gate() -> GateDesc {
     GateDesc::new(
-        handlers::double_fault as VirtAddr,
+        entries::double_fault as VirtAddr,
         true,
         Some(DOUBLE_FAULT_IST_INDEX),
         Ring::Zero,
@@ -3,4 +3,6 @@
 pub mod gdt;
 pub mod idt;
 pub mod paging;
+pub mod protection;
 pub mod syscall;
+pub mod trap;
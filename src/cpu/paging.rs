@@ -1,7 +1,6 @@
 use x86_64::{PageTable, PageTableEntry, PageTableIndex, PhysAddr, VirtAddr};
 
 use crate::global::OutOfMemory;
-use crate::process::USERLAND_STOP;
 
 /// The size of a 4KiB page.
 pub const FOUR_KIB: usize = 4 * 1024;
@@ -9,12 +8,18 @@ pub const FOUR_KIB: usize = 4 * 1024;
 pub const TWO_MIB: usize = 2 * 1024 * 1024;
 /// The size of a 1GiB page.
 pub const ONE_GIB: usize = 1024 * 1024 * 1024;
-/// The size of a 4GiB page.
-pub const FOUR_GIB: usize = 4 * 1024 * 1024 * 1024;
 
 /// The offset of the higher-half direct map installed by the kernel during the booting process.
 pub const HHDM_OFFSET: VirtAddr = 0xFFFF_8000_0000_0000;
 
+/// The start of the virtual memory region reserved for the kernel heap.
+pub const KERNEL_HEAP_START: VirtAddr = 0xFFFF_C000_0000_0000;
+/// The size of the virtual memory region reserved for the kernel heap.
+///
+/// This is exactly the amount of memory covered by a single entry of the L4 table, which allows
+/// the kernel heap to be shared by all address spaces.
+pub const KERNEL_HEAP_SIZE: usize = 512 * ONE_GIB;
+
 /// A token that vouchers for the fact that the HHDM has been initiated.
 ///
 /// When this token exists, physical addresses can be safely converted to a virtual address
@@ -51,7 +56,7 @@ impl From<OutOfMemory> for MappingError {
 }
 
 /// Represents an address space.
-pub struct AddressSpace<C> {
+pub struct AddressSpace<C: AddressSpaceContext> {
     /// The context used to allocate and access pages.
     context: C,
     /// The root page table of the address space.
@@ -61,7 +66,7 @@ pub struct AddressSpace<C> {
 impl<C: AddressSpaceContext> AddressSpace<C> {
     /// Creates a new [`AddressSpace`] with the provided context.
     pub fn new(mut context: C) -> Result<Self, OutOfMemory> {
-        let root = context.allocate_page()?;
+        let root = context.allocate_page_table()?;
 
         unsafe {
             let root_ptr = context.physical_to_virtual(root) as *mut PageTable;
@@ -71,6 +76,17 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
         Ok(Self { context, root })
     }
 
+    /// Creates a new [`AddressSpace`] from an existing L4 table.
+    ///
+    /// # Safety
+    ///
+    /// The L4 table and the pages it references must have been allocated by the provided
+    /// context. When the returned [`AddressSpace`] is dropped, they are released.
+    #[inline]
+    pub unsafe fn from_l4_table(context: C, root: PhysAddr) -> Self {
+        Self { context, root }
+    }
+
     /// Returns the physical address of the L4 table of this address space.
     #[inline]
     pub fn l4_table(&self) -> PhysAddr {
@@ -88,33 +104,6 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
         unsafe { &mut *(self.context.physical_to_virtual(self.root) as *mut PageTable) }
     }
 
-    /// Attempts to translate the provided virtual address to a physical address.
-    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
-        let [p1, p2, p3, p4, _] = PageTableIndex::break_virtual_address(virt);
-        let offset = (virt & 0xFFF) as u64;
-
-        unsafe {
-            let l4 = &*(self.context.physical_to_virtual(self.root) as *const PageTable);
-            if !l4[p4].is_present() {
-                return None;
-            }
-            let l3 = &*(self.context.physical_to_virtual(l4[p4].address()) as *const PageTable);
-            if !l3[p3].is_present() {
-                return None;
-            }
-            let l2 = &*(self.context.physical_to_virtual(l3[p3].address()) as *const PageTable);
-            if !l2[p2].is_present() {
-                return None;
-            }
-            let l1 = &*(self.context.physical_to_virtual(l2[p2].address()) as *const PageTable);
-            if !l1[p1].is_present() {
-                return None;
-            }
-
-            Some(l1[p1].address() + offset)
-        }
-    }
-
     /// Returns the 4KiB page table entry for the provided virtual address.
     ///
     /// # Arguments
@@ -241,7 +230,8 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
 
         let entry = self.make_4kib_entry(virt, flags)?;
 
-        if entry.is_present() {
+        // Lazy entries are not present, but the page is reserved nonetheless.
+        if !entry.is_empty() {
             return Err(MappingError::AlreadyMapped);
         }
 
@@ -357,14 +347,14 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
             if !l3[p3].is_present() || l3[p3].intersects(PageTableEntry::HUGE_PAGE) {
                 return Err(PageMiss {
                     layer: MappingLayer::L3,
-                    mapping: l4[p4].intersects(PageTableEntry::HUGE_PAGE),
+                    mapping: l3[p3].intersects(PageTableEntry::HUGE_PAGE),
                 });
             }
             let l2 = &mut *(self.context.physical_to_virtual(l3[p3].address()) as *mut PageTable);
             if !l2[p2].is_present() || l2[p2].intersects(PageTableEntry::HUGE_PAGE) {
                 return Err(PageMiss {
                     layer: MappingLayer::L2,
-                    mapping: l4[p4].intersects(PageTableEntry::HUGE_PAGE),
+                    mapping: l2[p2].intersects(PageTableEntry::HUGE_PAGE),
                 });
             }
             let l1 = &mut *(self.context.physical_to_virtual(l2[p2].address()) as *mut PageTable);
@@ -379,19 +369,129 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
         }
     }
 
-    /// Attempts to unmap the provided 4KiB page.
+    /// Returns the leaf entry responsible for the provided virtual address, along with the
+    /// size of the memory it covers.
     ///
-    /// # Arguments
+    /// Unlike [`get_4kib_entry`], huge pages are returned, and so are L1 entries that are not
+    /// present. If one of the page tables that would contain the entry does not exist, `None` is
+    /// returned.
     ///
-    /// - `virt`: The virtual address of the page to unmap.
-    pub fn unmap_4kib(&mut self, virt: VirtAddr) -> Result<(), PageMiss> {
-        let entry = self.get_4kib_entry(virt)?;
+    /// [`get_4kib_entry`]: AddressSpace::get_4kib_entry
+    fn mapping_entry(&self, virt: VirtAddr) -> Option<(&mut PageTableEntry, usize)> {
+        let [p1, p2, p3, p4, _] = PageTableIndex::break_virtual_address(virt);
 
-        *entry = PageTableEntry::empty();
+        unsafe {
+            let l4 = &mut *(self.context.physical_to_virtual(self.root) as *mut PageTable);
+            if !l4[p4].is_present() || l4[p4].intersects(PageTableEntry::HUGE_PAGE) {
+                return None;
+            }
+            let l3 = &mut *(self.context.physical_to_virtual(l4[p4].address()) as *mut PageTable);
+            if !l3[p3].is_present() {
+                return None;
+            }
+            if l3[p3].intersects(PageTableEntry::HUGE_PAGE) {
+                return Some((&mut l3[p3], ONE_GIB));
+            }
+            let l2 = &mut *(self.context.physical_to_virtual(l3[p3].address()) as *mut PageTable);
+            if !l2[p2].is_present() {
+                return None;
+            }
+            if l2[p2].intersects(PageTableEntry::HUGE_PAGE) {
+                return Some((&mut l2[p2], TWO_MIB));
+            }
+            let l1 = &mut *(self.context.physical_to_virtual(l2[p2].address()) as *mut PageTable);
+            Some((&mut l1[p1], FOUR_KIB))
+        }
+    }
+
+    /// Returns the L1 entry responsible for the provided virtual address, regardless of whether
+    /// it is present or not.
+    ///
+    /// If the L1 table that would contain the entry does not exist, or if the address is part of
+    /// a huge page, `None` is returned.
+    fn leaf_entry(&self, virt: VirtAddr) -> Option<&mut PageTableEntry> {
+        match self.mapping_entry(virt) {
+            Some((entry, FOUR_KIB)) => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Reserves the provided range of virtual addresses without backing it with physical memory.
+    ///
+    /// Each page of the range is backed by a zeroed page on the first access, when
+    /// [`commit_lazy_page`] is called from the page fault handler.
+    ///
+    /// # Panics
+    ///
+    /// In debug mode, this function panics if any of the input addresses are not properly
+    /// aligned to a 4KiB page.
+    ///
+    /// # Errors
+    ///
+    /// Page tables are still allocated eagerly, meaning that this function may fail with
+    /// [`MappingError::OutOfMemory`].
+    ///
+    /// [`commit_lazy_page`]: AddressSpace::commit_lazy_page
+    pub fn reserve_range(
+        &mut self,
+        mut virt: VirtAddr,
+        mut length: usize,
+        flags: PageTableEntry,
+    ) -> Result<(), MappingError> {
+        debug_assert!(
+            virt % FOUR_KIB == 0,
+            "The virtual address is not aligned to a 4KiB page.",
+        );
+        debug_assert!(
+            length % FOUR_KIB == 0,
+            "The length is not a multiple of 4KiB.",
+        );
+
+        while length != 0 {
+            let entry = self.make_4kib_entry(virt, flags)?;
+
+            if !entry.is_empty() {
+                return Err(MappingError::AlreadyMapped);
+            }
+
+            *entry = (flags | LAZY_BIT) - PageTableEntry::PRESENT;
+
+            virt += FOUR_KIB;
+            length -= FOUR_KIB;
+        }
 
         Ok(())
     }
 
+    /// Backs the lazy page containing `virt` with a zeroed page of physical memory.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the page was reserved with [`reserve_range`] and is now mapped, and `false` if
+    /// it was not a lazy page in the first place, or if it is currently inaccessible.
+    ///
+    /// # Errors
+    ///
+    /// If no physical page could be allocated, the entry is left untouched and [`OutOfMemory`]
+    /// is returned.
+    ///
+    /// [`reserve_range`]: AddressSpace::reserve_range
+    pub fn commit_lazy_page(&mut self, virt: VirtAddr) -> Result<bool, OutOfMemory> {
+        let flags = match self.leaf_entry(virt) {
+            Some(entry) if entry.intersects(LAZY_BIT) && !entry.intersects(GUARD_BIT) => {
+                *entry - LAZY_BIT
+            }
+            _ => return Ok(false),
+        };
+
+        let phys = self.context.allocate_zeroed_page()?;
+
+        let entry = self.leaf_entry(virt).unwrap();
+        *entry = PageTableEntry::from_address(phys) | flags | PageTableEntry::PRESENT;
+
+        Ok(true)
+    }
+
     /// Maps the provided range of virtual addresses to the provided range of physical addresses.
     ///
     /// # Arguments
@@ -452,7 +552,13 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
 
     /// Allocates the requested amount of memory, mapping it to the requested virtual addresses.
     ///
-    /// The provided `callback` function can be used to initialize the allocated memory.
+    /// The allocated memory is zeroed. The provided `callback` function can be used to
+    /// initialize it further. It is called once each page has been mapped.
+    ///
+    /// # Errors
+    ///
+    /// If the function fails, the pages that were mapped before the failure remain mapped. The
+    /// page that was being mapped when the failure occured is released.
     ///
     /// # Panics
     ///
@@ -475,10 +581,13 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
         );
 
         while length != 0 {
-            let phys = self.context.allocate_page()?;
+            let phys = self.context.allocate_zeroed_page()?;
             let dst = unsafe { self.context.physical_to_virtual(phys) as *mut u8 };
 
-            self.map_4kib(virt, phys, flags)?;
+            if let Err(err) = self.map_4kib(virt, phys, flags) {
+                unsafe { self.context.deallocate_page(phys) };
+                return Err(err);
+            }
 
             callback(virt, dst);
 
@@ -491,6 +600,10 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
 
     /// Unmaps the provided range of virtual addresses.
     ///
+    /// The physical memory owned by the address space (i.e. not marked with [`NOT_OWNED_BIT`]) is
+    /// released. This includes pages reserved with [`reserve_range`] and pages made inaccessible
+    /// with [`protect_range`].
+    ///
     /// # Panics
     ///
     /// This function panics in debug mode if any of the provided input
@@ -498,12 +611,28 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
     ///
     /// # Errors
     ///
-    /// This function returns `ALREADY_MAPPED` if any of the provided virtual addresses are not
-    /// mapped.
+    /// This function returns a [`PageMiss`] if any of the provided virtual addresses are not
+    /// mapped, or if the range only covers part of a huge page.
     ///
     /// Note that in case of error, part of the requested range might have been properly
     /// unmapped.
-    pub fn unmap_range(&mut self, mut virt: VirtAddr, mut length: usize) -> Result<(), PageMiss> {
+    ///
+    /// # Returns
+    ///
+    /// The number of 4KiB pages of physical memory that were mapped in the range, whether they
+    /// were released or not. Lazy pages that were never accessed are not counted.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the range.
+    ///
+    /// [`reserve_range`]: AddressSpace::reserve_range
+    /// [`protect_range`]: AddressSpace::protect_range
+    pub fn unmap_range(
+        &mut self,
+        mut virt: VirtAddr,
+        mut length: usize,
+    ) -> Result<usize, PageMiss> {
         debug_assert!(
             virt % FOUR_KIB == 0,
             "The virtual address is not aligned to a 4KiB page.",
@@ -513,94 +642,284 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
             "The length is not a multiple of 4KiB.",
         );
 
+        let mut unmapped = 0;
         while length != 0 {
-            // FIXME: take larger mapping into account.
-            self.unmap_4kib(virt)?;
-            virt += FOUR_KIB;
-            length -= FOUR_KIB;
+            let (entry, size) = match self.mapping_entry(virt) {
+                Some((entry, size)) if !entry.is_empty() && virt % size == 0 && length >= size => {
+                    (entry, size)
+                }
+                // Either the page is not mapped or it's part of a huge page that's not entirely
+                // covered by the range. `get_4kib_entry` reports which.
+                _ => return Err(self.get_4kib_entry(virt).unwrap_err()),
+            };
+
+            let entry = core::mem::replace(entry, PageTableEntry::empty());
+            if references_memory(entry) {
+                unmapped += size / FOUR_KIB;
+            }
+            unsafe { release_mapping(entry, size, &mut self.context) };
+
+            virt += size;
+            length -= size;
+        }
+
+        Ok(unmapped)
+    }
+
+    /// Changes the protection of the provided range of virtual addresses.
+    ///
+    /// `flags` may only contain [`PageTableEntry::PRESENT`], [`PageTableEntry::WRITABLE`] and
+    /// [`PageTableEntry::NO_EXECUTE`]. When [`PageTableEntry::PRESENT`] is missing, the pages
+    /// remain reserved but any access to them triggers a page fault. Pages that are still shared
+    /// with another address space are made copy-on-write rather than writable (see
+    /// [`COW_BIT`]).
+    ///
+    /// # Panics
+    ///
+    /// In debug mode, this function panics if any of the input addresses are not properly
+    /// aligned to a 4KiB page.
+    ///
+    /// # Errors
+    ///
+    /// If any of the pages of the range is not mapped, nothing is modified and the miss is
+    /// returned.
+    ///
+    /// Only 4KiB pages are supported. If part of the range is mapped by a larger page, nothing is
+    /// modified either, and the returned miss has [`PageMiss::mapping`] set.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the range.
+    pub fn protect_range(
+        &mut self,
+        virt: VirtAddr,
+        length: usize,
+        flags: PageTableEntry,
+    ) -> Result<(), PageMiss> {
+        debug_assert!(
+            virt % FOUR_KIB == 0,
+            "The virtual address is not aligned to a 4KiB page.",
+        );
+        debug_assert!(
+            length % FOUR_KIB == 0,
+            "The length is not a multiple of 4KiB.",
+        );
+        debug_assert!(PROTECTION_FLAGS.contains(flags));
+
+        // Make sure that the whole range is mapped before modifying anything.
+        let mut page = virt;
+        while page < virt + length {
+            match self.leaf_entry(page) {
+                Some(entry) if !entry.is_empty() => (),
+                // Larger pages are not split, as that would require allocating page tables.
+                // `get_4kib_entry` reports them as a miss with `mapping` set.
+                _ => return Err(self.get_4kib_entry(page).unwrap_err()),
+            }
+            page += FOUR_KIB;
+        }
+
+        let mut page = virt;
+        while page < virt + length {
+            let entry = *self.leaf_entry(page).unwrap();
+
+            // Pages that are still shared with another address space must be copied before they
+            // can be written to.
+            let shared = references_memory(entry)
+                && !entry.intersects(NOT_OWNED_BIT)
+                && self.context.is_page_shared(entry.address());
+
+            // Parent directories must allow whatever the page allows.
+            let parent_flags = (entry & PageTableEntry::USER_ACCESSIBLE)
+                | (flags & (PageTableEntry::WRITABLE | PageTableEntry::NO_EXECUTE));
+            let slot = match self.make_4kib_entry(page, parent_flags) {
+                Ok(slot) => slot,
+                Err(_) => unreachable!("the page tables of a mapped page are present"),
+            };
+
+            let mut new = entry - PROTECTION_FLAGS - GUARD_BIT - COW_BIT;
+            new |= flags & PageTableEntry::NO_EXECUTE;
+            if flags.intersects(PageTableEntry::WRITABLE) {
+                new |= if shared {
+                    COW_BIT
+                } else {
+                    PageTableEntry::WRITABLE
+                };
+            }
+            if !flags.intersects(PageTableEntry::PRESENT) {
+                new |= GUARD_BIT;
+            } else if !entry.intersects(LAZY_BIT) {
+                // Lazy pages only become present once they are backed by physical memory.
+                new |= PageTableEntry::PRESENT;
+            }
+            *slot = new;
+
+            page += FOUR_KIB;
         }
 
         Ok(())
     }
 
-    /// Attempts to find an unmapped range of virtual addresses.
+    /// Maps the provided range of virtual addresses in `other`, sharing the physical pages that
+    /// back it between both address spaces.
+    ///
+    /// Owned pages that were writable become copy-on-write in both address spaces: they are
+    /// marked with [`COW_BIT`] and must be copied with [`copy_on_write`] before being written
+    /// to. Pages marked with [`NOT_OWNED_BIT`] are mapped as-is, and lazy pages remain lazy in
+    /// both address spaces.
+    ///
+    /// Only 4KiB pages are supported. Parts of the range that are not mapped are ignored.
+    ///
+    /// # Returns
+    ///
+    /// The number of pages of physical memory that are now mapped in `other`. Lazy pages that
+    /// were never accessed are not counted.
     ///
     /// # Panics
     ///
-    /// This function panics in debug builds if `count` is not properly aligned
-    /// to the page size.
+    /// In debug mode, this function panics if any of the input addresses are not properly
+    /// aligned to a 4KiB page.
+    ///
+    /// # Errors
+    ///
+    /// If a page table of `other` could not be allocated, [`MappingError::OutOfMemory`] is
+    /// returned. If part of the range is already mapped in `other`,
+    /// [`MappingError::AlreadyMapped`] is returned. In both cases, the pages that were shared
+    /// before the error remain so.
     ///
     /// # Remarks
     ///
-    /// This function only looks for valid memory within the common user-space
-    /// area. (<= USERLAND_STOP)
-    pub fn find_unmapped_range(&self, count: usize) -> Option<VirtAddr> {
+    /// The caller is responsible for invalidating the TLB entries of the range in this address
+    /// space.
+    ///
+    /// [`copy_on_write`]: AddressSpace::copy_on_write
+    pub fn share_range(
+        &mut self,
+        other: &mut AddressSpace<C>,
+        mut virt: VirtAddr,
+        mut length: usize,
+    ) -> Result<usize, MappingError> {
         debug_assert!(
-            count % FOUR_KIB == 0,
-            "The length is not properly aligned to a 4KiB page."
+            virt % FOUR_KIB == 0,
+            "The virtual address is not aligned to a 4KiB page.",
         );
+        debug_assert!(
+            length % FOUR_KIB == 0,
+            "The length is not a multiple of 4KiB.",
+        );
+
+        let mut shared = 0;
+        while length != 0 {
+            let mut entry = match self.leaf_entry(virt) {
+                Some(entry) if !entry.is_empty() => *entry,
+                _ => {
+                    virt += FOUR_KIB;
+                    length -= FOUR_KIB;
+                    continue;
+                }
+            };
+
+            // Parent directories must allow whatever the page allows, including writes that
+            // happen after a copy.
+            let mut parent_flags =
+                entry & (PageTableEntry::USER_ACCESSIBLE | PageTableEntry::NO_EXECUTE);
+            if entry.intersects(PageTableEntry::WRITABLE | COW_BIT) {
+                parent_flags |= PageTableEntry::WRITABLE;
+            }
 
-        const UPPER_BOUND: VirtAddr = USERLAND_STOP + 1;
-
-        let mut virt = 0x1000;
-        let mut count_so_far = 0;
-        while virt < UPPER_BOUND && count_so_far < count {
-            match self.get_4kib_entry(virt) {
-                // The page is already mapped.
-                // We can't use that.
-                Ok(_) => virt += FOUR_KIB,
-                Err(err) => {
-                    match err.layer {
-                        MappingLayer::L1 => {
-                            // The only way to get here is if the last L1 layer
-                            // is not mapped.
-                            debug_assert!(!err.mapping);
-                            count_so_far += FOUR_KIB;
-                        }
-                        MappingLayer::L2 => {
-                            if err.mapping {
-                                debug_assert!(virt % TWO_MIB == 0);
-
-                                // A huge page is mapped.
-                                count_so_far = 0;
-                                virt += TWO_MIB;
-                            } else {
-                                // The last L2 layer is not mapped.
-                                count_so_far += TWO_MIB;
-                            }
-                        }
-                        MappingLayer::L3 => {
-                            if err.mapping {
-                                debug_assert!(virt % ONE_GIB == 0);
-
-                                // A huge page is mapped.
-                                count_so_far = 0;
-                                virt += ONE_GIB;
-                            } else {
-                                // The last L3 layer is not mapped.
-                                count_so_far += ONE_GIB;
-                            }
-                        }
-                        MappingLayer::L4 => {
-                            if err.mapping {
-                                // Not possible because five-level paging
-                                // is not enabled.
-                                unreachable!();
-                            } else {
-                                // The last L4 layer is not mapped.
-                                count_so_far += FOUR_GIB;
-                            }
-                        }
-                    }
+            let slot = other.make_4kib_entry(virt, parent_flags)?;
+            if !slot.is_empty() {
+                return Err(MappingError::AlreadyMapped);
+            }
+
+            if references_memory(entry) {
+                shared += 1;
+            }
+
+            if references_memory(entry) && !entry.intersects(NOT_OWNED_BIT) {
+                unsafe { self.context.share_page(entry.address()) };
+
+                if entry.intersects(PageTableEntry::WRITABLE) {
+                    entry = (entry - PageTableEntry::WRITABLE) | COW_BIT;
+                    *self.leaf_entry(virt).unwrap() = entry;
                 }
             }
+
+            *slot = entry;
+
+            virt += FOUR_KIB;
+            length -= FOUR_KIB;
         }
 
-        if count_so_far >= count {
-            Some(virt)
-        } else {
-            None
+        Ok(shared)
+    }
+
+    /// Makes the copy-on-write page containing `virt` writable.
+    ///
+    /// If the physical page is still shared with another address space, it is copied to a new
+    /// page first. Otherwise, it is simply made writable.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the page was marked with [`COW_BIT`] and is now writable, and `false`
+    /// otherwise.
+    ///
+    /// # Errors
+    ///
+    /// If the page needed to be copied but no physical page could be allocated, the entry is
+    /// left untouched and [`OutOfMemory`] is returned.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entry of the page.
+    pub fn copy_on_write(&mut self, virt: VirtAddr) -> Result<bool, OutOfMemory> {
+        let entry = match self.leaf_entry(virt) {
+            Some(entry) if entry.is_present() && entry.intersects(COW_BIT) => *entry,
+            _ => return Ok(false),
+        };
+
+        let old = entry.address();
+        let flags =
+            (entry - PageTableEntry::PAGE_ADDRESS_MASK - COW_BIT) | PageTableEntry::WRITABLE;
+
+        if !self.context.is_page_shared(old) {
+            *self.leaf_entry(virt).unwrap() = PageTableEntry::from_address(old) | flags;
+            return Ok(true);
         }
+
+        let new = self.context.allocate_page()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.context.physical_to_virtual(old) as *const u8,
+                self.context.physical_to_virtual(new) as *mut u8,
+                FOUR_KIB,
+            );
+        }
+
+        *self.leaf_entry(virt).unwrap() = PageTableEntry::from_address(new) | flags;
+
+        // Drop the reference this address space had on the shared page.
+        unsafe { self.context.deallocate_page(old) };
+
+        Ok(true)
+    }
+
+    /// Returns whether userspace is allowed to access the page that contains `virt`, and to
+    /// write to it if `write` is set.
+    ///
+    /// Lazy pages and copy-on-write pages are considered accessible, as the page fault handler
+    /// makes them so on the first access.
+    pub fn is_user_accessible(&self, virt: VirtAddr, write: bool) -> bool {
+        let entry = match self.mapping_entry(virt) {
+            Some((entry, _)) => *entry,
+            None => return false,
+        };
+
+        let usable =
+            entry.is_present() || (entry.intersects(LAZY_BIT) && !entry.intersects(GUARD_BIT));
+
+        usable
+            && entry.intersects(PageTableEntry::USER_ACCESSIBLE)
+            && (!write || entry.intersects(PageTableEntry::WRITABLE | COW_BIT))
     }
 
     /// Leaks this [`AddressSpace`], exposing the underlying root L4 page table.
@@ -612,6 +931,27 @@ impl<C: AddressSpaceContext> AddressSpace<C> {
     }
 }
 
+impl<C: AddressSpaceContext> Drop for AddressSpace<C> {
+    /// Releases the pages owned by the address space.
+    ///
+    /// This includes every page table that's part of the address space, as well as the pages
+    /// mapped by it, with the exception of those marked with [`NOT_OWNED_BIT`]. Entries of the
+    /// L4 table that are marked with [`KERNEL_BIT`] are shared with the kernel and are left
+    /// untouched.
+    fn drop(&mut self) {
+        unsafe {
+            let l4 = &*(self.context.physical_to_virtual(self.root) as *const PageTable);
+            for i in PageTableIndex::iter() {
+                let entry = l4[i];
+                if entry.is_present() && !entry.intersects(KERNEL_BIT) {
+                    free_directory(entry.address(), 3, &mut self.context);
+                }
+            }
+            self.context.deallocate_page_table(self.root);
+        }
+    }
+}
+
 /// The context passed to an [`AddressSpace`] to describe how it should allocate new pages
 /// of memory and how to access them.
 ///
@@ -632,6 +972,23 @@ pub unsafe trait AddressSpaceContext {
     /// If the system is out of memory, this function returns an [`OutOfMemory`] error.
     fn allocate_page(&mut self) -> Result<PhysAddr, OutOfMemory>;
 
+    /// Allocates a new page of memory, guaranteed to be filled with zeros.
+    ///
+    /// Pages allocated this way are deallocated with [`deallocate_page`], just like the pages
+    /// returned by [`allocate_page`].
+    ///
+    /// # Errors
+    ///
+    /// If the system is out of memory, this function returns an [`OutOfMemory`] error.
+    ///
+    /// [`allocate_page`]: AddressSpaceContext::allocate_page
+    /// [`deallocate_page`]: AddressSpaceContext::deallocate_page
+    fn allocate_zeroed_page(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        let page = self.allocate_page()?;
+        unsafe { core::ptr::write_bytes(self.physical_to_virtual(page) as *mut u8, 0, FOUR_KIB) };
+        Ok(page)
+    }
+
     /// Deallocates a page of memory previously allocated by [`allocate_page`].
     ///
     /// # Safety
@@ -641,6 +998,60 @@ pub unsafe trait AddressSpaceContext {
     /// [`allocate_page`]: AddressSpaceContext::allocate_page
     unsafe fn deallocate_page(&mut self, addr: PhysAddr);
 
+    /// Allocates a new page of memory that's going to be used as a page table.
+    ///
+    /// Such pages are deallocated with [`deallocate_page_table`]. The default implementation
+    /// simply calls [`allocate_page`].
+    ///
+    /// # Errors
+    ///
+    /// If the system is out of memory, this function returns an [`OutOfMemory`] error.
+    ///
+    /// [`allocate_page`]: AddressSpaceContext::allocate_page
+    /// [`deallocate_page_table`]: AddressSpaceContext::deallocate_page_table
+    fn allocate_page_table(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        self.allocate_page()
+    }
+
+    /// Deallocates a page table previously allocated by [`allocate_page_table`].
+    ///
+    /// The default implementation simply calls [`deallocate_page`].
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the page was previously allocated by
+    /// [`allocate_page_table`].
+    ///
+    /// [`allocate_page_table`]: AddressSpaceContext::allocate_page_table
+    /// [`deallocate_page`]: AddressSpaceContext::deallocate_page
+    unsafe fn deallocate_page_table(&mut self, addr: PhysAddr) {
+        unsafe { self.deallocate_page(addr) }
+    }
+
+    /// Adds a reference to a page previously allocated by [`allocate_page`].
+    ///
+    /// Every reference must be dropped with [`deallocate_page`] before the page is actually
+    /// released. The default implementation does not support sharing pages and panics.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the page was previously allocated by [`allocate_page`].
+    ///
+    /// [`allocate_page`]: AddressSpaceContext::allocate_page
+    /// [`deallocate_page`]: AddressSpaceContext::deallocate_page
+    unsafe fn share_page(&mut self, addr: PhysAddr) {
+        let _ = addr;
+        panic!("this `AddressSpaceContext` implementation does not support sharing pages");
+    }
+
+    /// Returns whether the provided page is currently referenced more than once.
+    ///
+    /// See [`share_page`](AddressSpaceContext::share_page).
+    fn is_page_shared(&self, addr: PhysAddr) -> bool {
+        let _ = addr;
+        false
+    }
+
     /// Converts a physical address to a virtual address.
     ///
     /// # Safety
@@ -664,7 +1075,7 @@ unsafe fn make_directory<'a>(
     context: &mut impl AddressSpaceContext,
 ) -> Result<&'a mut PageTable, MappingError> {
     if !table[index].is_present() {
-        let new_table = context.allocate_page()?;
+        let new_table = context.allocate_page_table()?;
 
         unsafe {
             let table_ptr = context.physical_to_virtual(new_table) as *mut PageTable;
@@ -688,29 +1099,102 @@ unsafe fn make_directory<'a>(
     }
 }
 
+/// Releases the page table at `table`, as well as all the pages and page tables it references.
+///
+/// `level` is the level of the page table in the hierarchy, `1` being the last level.
+///
+/// Pages marked with [`NOT_OWNED_BIT`] are not released.
+///
+/// # Safety
+///
+/// The caller must ensure that the page table and the owned pages it references have been
+/// allocated by the provided context, and that they are no longer in use.
+unsafe fn free_directory(table: PhysAddr, level: usize, context: &mut impl AddressSpaceContext) {
+    let entries = unsafe { &*(context.physical_to_virtual(table) as *const PageTable) };
+
+    for i in PageTableIndex::iter() {
+        let entry = entries[i];
+
+        if level != 1 && entry.is_present() && !entry.intersects(PageTableEntry::HUGE_PAGE) {
+            unsafe { free_directory(entry.address(), level - 1, context) };
+        } else {
+            let size = match level {
+                1 => FOUR_KIB,
+                2 => TWO_MIB,
+                _ => ONE_GIB,
+            };
+
+            unsafe { release_mapping(entry, size, context) };
+        }
+    }
+
+    unsafe { context.deallocate_page_table(table) };
+}
+
+/// Releases the physical memory referenced by a leaf entry mapping `size` bytes, if it is owned
+/// by the address space.
+///
+/// Entries that are not present and don't reference any memory, as well as entries marked with
+/// [`NOT_OWNED_BIT`], are ignored.
+///
+/// # Safety
+///
+/// The memory referenced by the entry must have been allocated by the provided context, and it
+/// must no longer be in use.
+unsafe fn release_mapping(
+    entry: PageTableEntry,
+    size: usize,
+    context: &mut impl AddressSpaceContext,
+) {
+    if !references_memory(entry) || entry.intersects(NOT_OWNED_BIT) {
+        return;
+    }
+
+    // Huge pages are made of multiple 4KiB pages, each of which has been allocated separately.
+    let mut page = entry.address();
+    while page < entry.address() + size as u64 {
+        unsafe { context.deallocate_page(page) };
+        page += FOUR_KIB as u64;
+    }
+}
+
+/// Returns whether the provided leaf entry references a physical page.
+#[inline]
+fn references_memory(entry: PageTableEntry) -> bool {
+    // Inaccessible pages are not present, but they still reference physical memory. Lazy ones
+    // that were never touched don't.
+    entry.is_present() || (entry.intersects(GUARD_BIT) && !entry.intersects(LAZY_BIT))
+}
+
 /// Updates the flags of `parent` such that it keeps the same semantics as before, but with that
 /// of the child entry added.
+///
+/// The CPU combines the flags of every level of the hierarchy, so a directory entry must be at
+/// least as permissive as all the entries below it. For [`PageTableEntry::NO_EXECUTE`], this
+/// means that the bit must be cleared as soon as one child is executable: simply merging the
+/// bits of the child into the parent would make a whole directory non-executable once a single
+/// non-executable page was mapped in it.
 fn update_parent(parent: &mut PageTableEntry, child: PageTableEntry) {
     debug_assert!(!parent.intersects(PageTableEntry::HUGE_PAGE));
     debug_assert!(parent.intersects(PageTableEntry::PRESENT));
 
-    // const PRESERVED_FLAGS: PageTableEntry =
-    //     PageTableEntry::PRESENT.union(PageTableEntry::PAGE_ADDRESS_MASK);
-    // const AND_FLAGS: PageTableEntry = PageTableEntry::NO_EXECUTE.union(PageTableEntry::GLOBAL);
-    // const OR_FLAGS: PageTableEntry = PageTableEntry::WRITABLE
-    //     .union(PageTableEntry::USER_ACCESSIBLE)
-    //     .union(KERNEL_BIT)
-    //     .union(NOT_OWNED_BIT);
-
-    // let child_and = AND_FLAGS & child;
-    // let parent_and = AND_FLAGS & *parent;
-    // let child_or = OR_FLAGS & child;
-    // let parent_or = OR_FLAGS & *parent;
-    // let parent_preserved = PRESERVED_FLAGS & *parent;
-
-    // *parent = parent_preserved | (parent_and & child_and) | (parent_or | child_or);
-
-    *parent |= child;
+    // Restrictive flags only remain on the parent if both entries have them, while permissive
+    // flags are kept as soon as one of them has them.
+    const PRESERVED_FLAGS: PageTableEntry =
+        PageTableEntry::PRESENT.union(PageTableEntry::PAGE_ADDRESS_MASK);
+    const AND_FLAGS: PageTableEntry = PageTableEntry::NO_EXECUTE.union(PageTableEntry::GLOBAL);
+    const OR_FLAGS: PageTableEntry = PageTableEntry::WRITABLE
+        .union(PageTableEntry::USER_ACCESSIBLE)
+        .union(KERNEL_BIT)
+        .union(NOT_OWNED_BIT);
+
+    let child_and = AND_FLAGS & child;
+    let parent_and = AND_FLAGS & *parent;
+    let child_or = OR_FLAGS & child;
+    let parent_or = OR_FLAGS & *parent;
+    let parent_preserved = PRESERVED_FLAGS & *parent;
+
+    *parent = parent_preserved | (parent_and & child_and) | (parent_or | child_or);
 }
 
 /// A bit that's set for kernel pages. Used when copying the kernel address space to a process.
@@ -720,6 +1204,46 @@ pub const KERNEL_BIT: PageTableEntry = PageTableEntry::OS_BIT_9;
 /// given back to the kernel when the process is destroyed.
 pub const NOT_OWNED_BIT: PageTableEntry = PageTableEntry::OS_BIT_10;
 
+/// A bit that's set on non-present entries that have been reserved but not backed by physical
+/// memory yet. The other flags of such an entry are the ones the page will be mapped with.
+pub const LAZY_BIT: PageTableEntry = PageTableEntry::OS_BIT_11;
+
+/// A bit that's set on non-present entries that have been made inaccessible with
+/// [`AddressSpace::protect_range`]. Such entries keep referencing their physical page, if any.
+pub const GUARD_BIT: PageTableEntry = PageTableEntry::OS_BIT_52;
+
+/// A bit that's set on present, read-only entries whose page may be shared with other address
+/// spaces. Writing to such a page is allowed, but it must be copied first with
+/// [`AddressSpace::copy_on_write`].
+pub const COW_BIT: PageTableEntry = PageTableEntry::OS_BIT_53;
+
+/// The flags of a page table entry that can be changed with [`AddressSpace::protect_range`].
+pub const PROTECTION_FLAGS: PageTableEntry = PageTableEntry::PRESENT
+    .union(PageTableEntry::WRITABLE)
+    .union(PageTableEntry::NO_EXECUTE);
+
+/// The memory types loaded in the PAT by the kernel.
+///
+/// From PA0 to PA7: write-back, write-combining, uncached-minus, uncached, write-back,
+/// write-through, uncached-minus and uncached. This is the power-on layout of the PAT, with PA1
+/// changed from write-through to write-combining.
+///
+/// The kernel never sets the PAT bit of page table entries, as its position differs between
+/// 4KiB pages and larger ones. Only PA0 to PA3 can be selected, using the
+/// [`PageTableEntry::WRITE_THROUGH`] and [`PageTableEntry::CACHE_DISABLE`] bits which are
+/// located at the same place at every level. The kernel has no use for write-through memory,
+/// while device memory such as framebuffers is much faster to write to when it is
+/// write-combining (see `CachePolicy::WRITE_COMBINING`), which is why PA1 (selected by
+/// [`PageTableEntry::WRITE_THROUGH`] alone) is repurposed. PA4 to PA7 keep their power-on values.
+pub const PAT_LAYOUT: u64 = 0x0007_0406_0007_0106;
+
+/// The flags that make a page uncached, given the [`PAT_LAYOUT`].
+pub const UNCACHED: PageTableEntry =
+    PageTableEntry::WRITE_THROUGH.union(PageTableEntry::CACHE_DISABLE);
+
+/// The flags that make a page write-combining, given the [`PAT_LAYOUT`].
+pub const WRITE_COMBINING: PageTableEntry = PageTableEntry::WRITE_THROUGH;
+
 /// A possible mapping layer.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MappingLayer {
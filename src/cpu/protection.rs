@@ -0,0 +1,52 @@
+//! Enables the protections that the CPU offers against the kernel misusing userspace memory.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::{cpuid, Cr4};
+
+use crate::log;
+
+/// Whether SMAP has been enabled.
+///
+/// When this is the case, the kernel must execute the `stac` instruction before accessing
+/// userspace memory, and the `clac` instruction afterwards.
+///
+/// This is public so that entry points written in assembly can read it (see
+/// [`interrupt_entry`](crate::cpu::trap::interrupt_entry)). Other code should use
+/// [`smap_enabled`].
+pub static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether SMAP has been enabled by [`init`].
+#[inline]
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables SMEP, SMAP and UMIP, for those that are supported by the CPU.
+pub fn init() {
+    log::trace!("Enabling CPU protections...");
+
+    // The structured extended feature flags are reported by leaf 7.
+    let mut features = Cr4::empty();
+    if cpuid(0, 0).eax >= 7 {
+        let leaf = cpuid(7, 0);
+
+        if leaf.ebx & (1 << 7) != 0 {
+            features |= Cr4::SMEP;
+        }
+        if leaf.ebx & (1 << 20) != 0 {
+            features |= Cr4::SMAP;
+        }
+        if leaf.ecx & (1 << 2) != 0 {
+            features |= Cr4::UMIP;
+        }
+    }
+
+    log::trace!("Supported protections: {:?}", features);
+
+    // The kernel never accesses userspace memory at this point, so SMAP can be enabled right
+    // away.
+    unsafe { Cr4::read().union(features).write() };
+
+    SMAP_ENABLED.store(features.contains(Cr4::SMAP), Ordering::Relaxed);
+}
@@ -1,17 +1,30 @@
 //! Defines the system call handlers.
 
+use alloc::vec::Vec;
 use core::fmt::Write;
-use core::mem::MaybeUninit;
-use core::ptr::NonNull;
 use core::sync::atomic::Ordering::Relaxed;
 
-use ruel_sys::{Framebuffer, PciDevice, ProtectionFlags, SysResult, Value, Verbosity, WakeUp};
-use x86_64::{hlt, invlpg, page_align_up, PageTableEntry, PhysAddr, VirtAddr};
-
-use crate::cpu::paging::{MappingError, FOUR_KIB, HHDM_OFFSET};
-use crate::global::GlobalToken;
+use ruel_sys::{
+    CachePolicy, Capabilities, Context, DeadlineUnit, DmaFlags, Framebuffer, MappingKind,
+    MemoryMapping, PciDevice, ProcessId, ProtectionFlags, SharedMemoryId, SysResult, ThreadId,
+    Value, Verbosity, WakeUp, WakeUpTag,
+};
+use x86_64::{invlpg, page_align_up, read_cr3, write_cr3, PageTableEntry, PhysAddr, VirtAddr};
+
+use crate::cpu::paging::{
+    MappingError, PageMiss, FOUR_KIB, HHDM_OFFSET, NOT_OWNED_BIT, UNCACHED, WRITE_COMBINING,
+};
+use crate::cpu::trap::{GeneralPurposeRegisters, TrapFrame};
+use crate::global::{
+    AllocConstraints, DespawnError, GlobalToken, OutOfMemory, SharedMemoryNotFound, ThreadNotFound,
+    TooManyProcesses, TooManyThreads,
+};
 use crate::log;
-use crate::process::{ProcessPtr, SleepingState};
+use crate::process::{
+    page_flags_of, Backing, ExceptionStack, InvalidPointer, LoadError, Process, Region,
+    RegionError, Registers, SleepingState, UserPtr, UserSlice, USERLAND_MAPPABLE_END,
+    USERLAND_STOP,
+};
 
 /// Returns the provided value if the result is [`None`].
 macro_rules! try_or {
@@ -23,10 +36,20 @@ macro_rules! try_or {
     };
 }
 
+/// Returns [`SysResult::INVALID_POINTER`] if the provided access to userspace memory failed.
+macro_rules! try_user {
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(InvalidPointer) => return SysResult::INVALID_POINTER,
+        }
+    };
+}
+
 /// See [`ruel_sys::despawn_process`].
 pub unsafe extern "C" fn despawn_process(
     process_id: usize,
-    _: usize,
+    exit_code: usize,
     _: usize,
     _: usize,
     _: usize,
@@ -34,53 +57,141 @@ pub unsafe extern "C" fn despawn_process(
 ) -> SysResult {
     let glob = GlobalToken::get();
 
-    let _process = try_or!(glob.processes.get(process_id), SysResult::PROCESS_NOT_FOUND);
+    let current_id = glob.processes.current_id();
+    let process_id = if process_id == ProcessId::MAX {
+        current_id
+    } else {
+        process_id
+    };
+
+    let process = match glob
+        .processes
+        .despawn_process_as(current_id, process_id, exit_code)
+    {
+        Ok(process) => process,
+        Err(DespawnError::ProcessNotFound) => return SysResult::PROCESS_NOT_FOUND,
+        Err(DespawnError::MissingCapability) => return SysResult::MISSING_CAPABILITY,
+    };
+
+    process.release(glob, process_id);
+
+    log::trace!("Process {} despawned (exit code {})", process_id, exit_code);
 
-    todo!("despawn_process({})", process_id);
+    // If the current process was despawned, the scheduler will pick another thread to run
+    // when the system call returns.
+
+    SysResult::SUCCESS
 }
 
+/// The maximum number of wake-ups that a thread can wait on at once.
+const MAX_WAKE_UPS: usize = 64;
+
+/// The maximum size of a message sent through the `kernel_log` system call.
+const MAX_LOG_LEN: usize = 64 * 1024;
+
+/// The maximum size of an executable image passed to the `spawn_process` system call.
+const MAX_IMAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// The maximum size of a command line passed to the `spawn_process` system call.
+///
+/// The command line is copied to the first page of the stack of the new process, along with a
+/// null terminator.
+const MAX_CMDLINE_LEN: usize = 4095;
+
 /// See [`ruel_sys::sleep`].
 pub unsafe extern "C" fn sleep(
     wake_ups: usize,
     wake_up_len: usize,
-    _: usize,
+    invalid_index: usize,
     _: usize,
     _: usize,
     _: usize,
 ) -> SysResult {
-    // FIXME: Properly ensure that the memory referenced is valid.
-
     // Put the current process to sleep.
 
     let glob = GlobalToken::get();
 
-    {
-        // Prevent interrupts while we're holding the lock.
-        let mut current_process = glob.processes.current();
+    let wake_ups = UserSlice::<WakeUp>::new(wake_ups, wake_up_len);
+    let invalid_index = UserPtr::<usize>::new(invalid_index);
 
-        // Convert the input pointers to the kernel's address space so that they can be accessed
-        // even if the process ends up waking up in a different address space.
-        let wake_up =
-            current_process.address_space.translate(wake_ups).unwrap() as usize + HHDM_OFFSET;
-        let wake_up = unsafe { NonNull::new_unchecked(wake_up as *mut WakeUp) };
-        let wake_ups =
-            unsafe { ProcessPtr::new(NonNull::slice_from_raw_parts(wake_up, wake_up_len)) };
+    if wake_up_len > MAX_WAKE_UPS {
+        return SysResult::INVALID_VALUE;
+    }
 
-        // Update the state of the process.
-        assert!(current_process.sleeping.is_none());
-        current_process.sleeping = Some(SleepingState { wake_ups });
+    let mut buffer = Vec::new();
+    if buffer.try_reserve_exact(wake_up_len).is_err() {
+        return SysResult::OUT_OF_MEMORY;
     }
 
-    // TODO: Switch to another process.
-    // Currently, because we don't have multitasking, just halt until the process is woken up.
+    // Validate the wake-up events before putting the process to sleep. This ensures that the
+    // process does not end up waiting on a condition that can never be met.
+    for index in 0..wake_ups.len() {
+        let mut wake_up = try_user!(wake_ups.get(index).unwrap().read());
+
+        let ret = validate_wake_up(glob, &wake_up);
+
+        if ret != SysResult::SUCCESS {
+            if !invalid_index.is_null() {
+                try_user!(invalid_index.write(index));
+            }
+
+            return ret;
+        }
 
-    while glob.processes.current().sleeping.is_some() {
-        hlt();
+        wake_up.set_triggered(false);
+        buffer.push(wake_up);
     }
 
+    // This also makes sure that the wake-ups can be written back once the thread wakes up.
+    try_user!(wake_ups.write_from(&buffer));
+
+    // Update the state of the thread.
+    glob.processes.with_current(|process, thread| {
+        assert!(thread.sleeping.is_none());
+        thread.sleeping = Some(SleepingState {
+            wake_ups: buffer,
+            user_wake_ups: wake_ups,
+        });
+
+        // Children that have already exited are collected right away.
+        process
+            .exited_children
+            .retain(|record| !thread.notify_process_exit(record.process_id, record.exit_code));
+    });
+
+    // Some of the wake-up events might already be ready. In that case, the thread should not
+    // have to wait for the next tick to be woken up. The other threads of the process are
+    // ticked as well, since the pending events of the process are cleared afterwards.
+    glob.processes.tick_process(glob.processes.current_id());
+
+    // The scheduler will switch to another thread once the system call returns if the
+    // current thread is still sleeping.
+
     SysResult::SUCCESS
 }
 
+/// Checks whether the provided [`WakeUp`] can be waited on by the current process.
+fn validate_wake_up(glob: GlobalToken, wake_up: &WakeUp) -> SysResult {
+    match wake_up.tag() {
+        WakeUpTag::NOW | WakeUpTag::PS2_KEYBOARD | WakeUpTag::PS2_MOUSE => SysResult::SUCCESS,
+        WakeUpTag::PROCESS_EXIT => {
+            let process_id = unsafe { wake_up.process_exit.process_id };
+            if glob.processes.exists(process_id)
+                || glob.processes.current().has_exit_record(process_id)
+            {
+                SysResult::SUCCESS
+            } else {
+                SysResult::PROCESS_NOT_FOUND
+            }
+        }
+        WakeUpTag::DEADLINE => match unsafe { wake_up.deadline.unit } {
+            DeadlineUnit::UPTICKS | DeadlineUnit::NANOSECONDS => SysResult::SUCCESS,
+            _ => SysResult::INVALID_VALUE,
+        },
+        _ => SysResult::INVALID_VALUE,
+    }
+}
+
 /// See [`ruel_sys::acquire_framebuffers`].
 pub unsafe extern "C" fn acquire_framebuffers(
     ret: usize,
@@ -92,62 +203,41 @@ pub unsafe extern "C" fn acquire_framebuffers(
 ) -> SysResult {
     let glob = GlobalToken::get();
 
-    let ret = unsafe { (ret as *mut MaybeUninit<Framebuffer>).as_mut() };
-    let count = unsafe { &mut *(count as *mut usize) };
+    let ret = UserPtr::<Framebuffer>::new(ret);
+    let count = UserPtr::<usize>::new(count);
 
-    if glob.framebuffers.acquire(glob.processes.current_id()) {
-        assert_eq!(glob.framebuffers.as_slice().len(), 1);
-
-        if let Some(ret) = ret {
-            let framebuffer = glob.framebuffers.as_slice()[0];
-
-            // Allocate the framebuffer in the user's address space.
-            let mut process = glob.processes.current();
-
-            let mapped_size = page_align_up(framebuffer.size());
+    try_user!(count.check_writable());
+    if !ret.is_null() {
+        try_user!(ret.check_writable());
+    }
 
-            let address = match process.address_space.find_unmapped_range(mapped_size) {
-                Some(address) => address,
-                None => return SysResult::OUT_OF_MEMORY,
-            };
+    let current_id = glob.processes.current_id();
+    let already_owned = glob.framebuffers.is_owned_by(current_id);
 
-            log::trace!("{:#x}..{:#x}", address, address + mapped_size);
-
-            match process.address_space.map_range(
-                address,
-                (framebuffer.address as VirtAddr - HHDM_OFFSET) as PhysAddr,
-                mapped_size,
-                PageTableEntry::WRITABLE | PageTableEntry::USER_ACCESSIBLE,
-            ) {
-                Ok(()) => (),
-                Err(MappingError::OutOfMemory) => return SysResult::OUT_OF_MEMORY,
-                Err(MappingError::AlreadyMapped) => unreachable!("framebuffer already mapped"),
-            }
+    if glob.framebuffers.acquire(current_id) {
+        assert_eq!(glob.framebuffers.as_slice().len(), 1);
 
-            {
-                let mut virt = address;
-                let mut size = mapped_size;
+        if !ret.is_null() {
+            let framebuffer = glob.framebuffers.as_slice()[0];
 
-                while size != 0 {
-                    invlpg(virt);
-                    virt += FOUR_KIB;
-                    size -= FOUR_KIB;
+            let address = match map_framebuffer(glob, &framebuffer) {
+                Ok(address) => address,
+                Err(err) => {
+                    // Don't keep ownership of a framebuffer that could not be mapped.
+                    if !already_owned {
+                        glob.framebuffers.release(current_id);
+                    }
+                    return err;
                 }
-            }
-
-            // Save the mapping in the metadata.
-            let metadata = unsafe { glob.framebuffers.metadata_mut() };
-
-            metadata[0].virt_address = framebuffer.address as usize;
-            metadata[0].virt_size = framebuffer.size();
+            };
 
-            ret.write(Framebuffer {
+            try_user!(ret.write(Framebuffer {
                 address: address as *mut u8,
                 ..framebuffer
-            });
+            }));
         }
 
-        *count = glob.framebuffers.as_slice().len();
+        try_user!(count.write(glob.framebuffers.as_slice().len()));
 
         SysResult::SUCCESS
     } else {
@@ -155,6 +245,67 @@ pub unsafe extern "C" fn acquire_framebuffers(
     }
 }
 
+/// Maps the provided framebuffer in the address space of the current process, which must have
+/// acquired it.
+///
+/// # Returns
+///
+/// The address at which the framebuffer has been mapped.
+fn map_framebuffer(glob: GlobalToken, framebuffer: &Framebuffer) -> Result<VirtAddr, SysResult> {
+    let mut process = glob.processes.current();
+
+    let mapped_size = page_align_up(framebuffer.size());
+
+    let address = process
+        .regions
+        .find_free(mapped_size)
+        .ok_or(SysResult::OUT_OF_MEMORY)?;
+
+    if process.regions.reserve(1).is_err() {
+        return Err(SysResult::OUT_OF_MEMORY);
+    }
+
+    log::trace!("{:#x}..{:#x}", address, address + mapped_size);
+
+    let phys = (framebuffer.address as VirtAddr - HHDM_OFFSET) as PhysAddr;
+    let protection = ProtectionFlags::READ | ProtectionFlags::WRITE;
+
+    match process.address_space.map_range(
+        address,
+        phys,
+        mapped_size,
+        page_flags_of(protection) | NOT_OWNED_BIT,
+    ) {
+        Ok(()) => (),
+        Err(MappingError::OutOfMemory) => {
+            // The pages are mapped in order, meaning that this removes the ones that were mapped
+            // before the allocation failed, and stops at the first one that wasn't.
+            let _ = process.address_space.unmap_range(address, mapped_size);
+            return Err(SysResult::OUT_OF_MEMORY);
+        }
+        Err(MappingError::AlreadyMapped) => unreachable!("framebuffer already mapped"),
+    }
+
+    process.resident_pages += mapped_size / FOUR_KIB;
+    process.regions.insert(Region {
+        start: address,
+        length: mapped_size,
+        protection,
+        kind: MappingKind::FRAMEBUFFER,
+        backing: Backing::Physical(phys),
+    });
+
+    invalidate_range(address, mapped_size);
+
+    // Save the mapping in the metadata.
+    let metadata = unsafe { glob.framebuffers.metadata_mut() };
+
+    metadata[0].virt_address = framebuffer.address as usize;
+    metadata[0].virt_size = framebuffer.size();
+
+    Ok(address)
+}
+
 /// See [`ruel_sys::release_framebuffers`].
 pub unsafe extern "C" fn release_framebuffers(
     _: usize,
@@ -186,24 +337,46 @@ pub unsafe extern "C" fn read_value(
 
     match Value::from_raw(value) {
         Value::UPTICKS => {
-            let result = unsafe { &mut *(result as *mut MaybeUninit<u64>) };
-            result.write(glob.upticks.load(Relaxed));
+            let result = UserPtr::<u64>::new(result);
+            try_user!(result.write(glob.upticks.load(Relaxed)));
         }
         Value::UPTIME => {
-            let result = unsafe { &mut *(result as *mut MaybeUninit<ruel_sys::Duration>) };
+            let result = UserPtr::<ruel_sys::Duration>::new(result);
             let ticks = glob.upticks.load(Relaxed);
             let ns_per_tick = crate::cpu::idt::pit::interval_ns();
             let total_ns = ticks as u128 * ns_per_tick as u128;
             let total_secs = (total_ns / 1_000_000_000) as u64;
             let subsec_ns = (total_ns % 1_000_000_000) as u64;
-            result.write(ruel_sys::Duration {
+            try_user!(result.write(ruel_sys::Duration {
                 seconds: total_secs,
                 nanoseconds: subsec_ns,
-            });
+            }));
         }
         Value::NANOSECONDS_PER_TICK => {
-            let result = unsafe { &mut *(result as *mut MaybeUninit<u32>) };
-            result.write(crate::cpu::idt::pit::interval_ns());
+            let result = UserPtr::<u32>::new(result);
+            try_user!(result.write(crate::cpu::idt::pit::interval_ns()));
+        }
+        // The locks must be released before writing the result, as resolving a page fault
+        // might require allocating memory.
+        Value::TOTAL_PAGES => {
+            let result = UserPtr::<usize>::new(result);
+            let total_pages = glob.allocator.lock().total_pages();
+            try_user!(result.write(total_pages));
+        }
+        Value::FREE_PAGES => {
+            let result = UserPtr::<usize>::new(result);
+            let free_pages = glob.allocator.lock().free_pages();
+            try_user!(result.write(free_pages));
+        }
+        Value::PAGE_TABLE_PAGES => {
+            let result = UserPtr::<usize>::new(result);
+            let page_table_pages = glob.allocator.lock().page_table_pages();
+            try_user!(result.write(page_table_pages));
+        }
+        Value::RESIDENT_PAGES => {
+            let result = UserPtr::<usize>::new(result);
+            let resident_pages = glob.processes.current().resident_pages;
+            try_user!(result.write(resident_pages));
         }
         _ => return SysResult::INVALID_VALUE,
     }
@@ -222,25 +395,22 @@ pub unsafe extern "C" fn enumerate_pci_devices(
 ) -> SysResult {
     let glob = GlobalToken::get();
 
-    let count = unsafe { &mut *(count as *mut usize) };
+    let count = UserPtr::<usize>::new(count);
+    let capacity = try_user!(count.read());
+    let devices = UserSlice::<PciDevice>::new(devices, capacity);
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            glob.pci_devices.as_ptr(),
-            devices as *mut PciDevice,
-            glob.pci_devices.len().min(*count),
-        );
-    }
+    let written = glob.pci_devices.len().min(capacity);
+    try_user!(devices.write_from(&glob.pci_devices[..written]));
 
-    *count = glob.pci_devices.len();
+    try_user!(count.write(glob.pci_devices.len()));
 
     SysResult::SUCCESS
 }
 
-/// See [`ruel_sys::unmap_memory`].
+/// See [`ruel_sys::map_memory`].
 pub unsafe extern "C" fn map_memory(
-    mut addr: usize,
-    mut count: usize,
+    addr: usize,
+    count: usize,
     prot: usize,
     out: usize,
     _: usize,
@@ -248,55 +418,87 @@ pub unsafe extern "C" fn map_memory(
 ) -> SysResult {
     let glob = GlobalToken::get();
 
-    if addr % FOUR_KIB != 0 || count % FOUR_KIB != 0 {
+    if addr % FOUR_KIB != 0 || count % FOUR_KIB != 0 || count == 0 {
         return SysResult::INVALID_VALUE;
     }
 
+    let out = UserPtr::<*mut u8>::new(out);
+    try_user!(out.check_writable());
+
     let mut current = glob.processes.current();
 
     let virt = if addr == 0 {
-        match current.address_space.find_unmapped_range(count) {
+        match current.regions.find_free(count) {
             Some(addr) => addr,
             None => return SysResult::OUT_OF_MEMORY,
         }
     } else {
+        match addr.checked_add(count) {
+            Some(end) if end <= USERLAND_MAPPABLE_END => (),
+            _ => return SysResult::INVALID_VALUE,
+        }
+
+        if !current.regions.is_free(addr, count) {
+            return SysResult::ALREADY_MAPPED;
+        }
+
         addr
     };
 
     let prot = ProtectionFlags::from_bits_retain(prot as u8);
-    let mut flags = PageTableEntry::USER_ACCESSIBLE;
-    if prot.intersects(ProtectionFlags::WRITE) {
-        flags.insert(PageTableEntry::WRITABLE);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
     }
-    if !prot.intersects(ProtectionFlags::EXECUTE) {
-        flags.insert(PageTableEntry::NO_EXECUTE);
+    let flags = page_flags_of(prot);
+
+    if current.regions.reserve(1).is_err() {
+        return SysResult::OUT_OF_MEMORY;
     }
 
-    match current
-        .address_space
-        .allocate_range(virt, count, flags, |_, _| ())
-    {
-        Ok(()) => {
-            let out = unsafe { &mut *(out as *mut MaybeUninit<*mut u8>) };
-            out.write(virt as *mut u8);
+    let result = if prot.intersects(ProtectionFlags::LAZY) {
+        // The pages are backed by the page fault handler on first access.
+        current.address_space.reserve_range(virt, count, flags)
+    } else {
+        current
+            .address_space
+            .allocate_range(virt, count, flags, |_, _| ())
+    };
 
-            while count != 0 {
-                invlpg(addr);
-                addr += FOUR_KIB;
-                count -= FOUR_KIB;
+    match result {
+        Ok(()) => {
+            if !prot.intersects(ProtectionFlags::LAZY) {
+                current.resident_pages += count / FOUR_KIB;
             }
+            current.regions.insert(Region {
+                start: virt,
+                length: count,
+                protection: prot,
+                kind: MappingKind::ANONYMOUS,
+                backing: Backing::Anonymous,
+            });
+
+            invalidate_range(virt, count);
+
+            drop(current);
+
+            try_user!(out.write(virt as *mut u8));
 
             SysResult::SUCCESS
         }
-        Err(MappingError::AlreadyMapped) => SysResult::ALREADY_MAPPED,
-        Err(MappingError::OutOfMemory) => SysResult::OUT_OF_MEMORY,
+        Err(MappingError::AlreadyMapped) => unreachable!("untracked memory mapping"),
+        Err(MappingError::OutOfMemory) => {
+            // The pages are mapped in order, meaning that this releases the ones that were mapped
+            // before the allocation failed, and stops at the first one that wasn't.
+            let _ = current.address_space.unmap_range(virt, count);
+            SysResult::OUT_OF_MEMORY
+        }
     }
 }
 
 /// See [`ruel_sys::unmap_memory`].
 pub unsafe extern "C" fn unmap_memory(
-    mut addr: usize,
-    mut count: usize,
+    addr: usize,
+    count: usize,
     _: usize,
     _: usize,
     _: usize,
@@ -310,17 +512,15 @@ pub unsafe extern "C" fn unmap_memory(
 
     let mut current = glob.processes.current();
 
-    match current.address_space.unmap_range(addr, count) {
+    match current.unmap(addr, count) {
         Ok(()) => {
-            while count != 0 {
-                invlpg(addr);
-                addr += FOUR_KIB;
-                count -= FOUR_KIB;
-            }
+            invalidate_range(addr, count);
 
             SysResult::SUCCESS
         }
-        Err(_err) => SysResult::ALREADY_MAPPED,
+        Err(RegionError::NotMapped) => SysResult::ALREADY_MAPPED,
+        Err(RegionError::Indivisible) => SysResult::INVALID_VALUE,
+        Err(RegionError::OutOfMemory) => SysResult::OUT_OF_MEMORY,
     }
 }
 
@@ -335,8 +535,10 @@ pub unsafe extern "C" fn kernel_log(
 ) -> SysResult {
     let verbosity = try_or!(Verbosity::from_raw(verbosity), SysResult::INVALID_VALUE);
 
-    // FIXME: Make sure that the memory provided is valid.
-    let data = unsafe { core::slice::from_raw_parts(data as *const u8, len) };
+    let data = match read_user_bytes(UserSlice::new(data, len), MAX_LOG_LEN) {
+        Ok(data) => data,
+        Err(err) => return err,
+    };
 
     struct ProcessMessage<'a> {
         data: &'a [u8],
@@ -370,7 +572,669 @@ pub unsafe extern "C" fn kernel_log(
         }
     }
 
-    log::log!(verbosity, "{}", ProcessMessage { data });
+    log::log!(verbosity, "{}", ProcessMessage { data: &data });
+
+    SysResult::SUCCESS
+}
+
+/// See [`ruel_sys::spawn_process`].
+pub unsafe extern "C" fn spawn_process(
+    image: usize,
+    image_len: usize,
+    cmdline: usize,
+    cmdline_len: usize,
+    capabilities: usize,
+    process_id: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let process_id = UserPtr::<ProcessId>::new(process_id);
+    try_user!(process_id.check_writable());
+
+    let image = match read_user_bytes(UserSlice::new(image, image_len), MAX_IMAGE_LEN) {
+        Ok(image) => image,
+        Err(err) => return err,
+    };
+    let cmdline = match read_user_bytes(UserSlice::new(cmdline, cmdline_len), MAX_CMDLINE_LEN) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err,
+    };
+
+    let (mut process, registers) = match Process::load(&image, &cmdline) {
+        Ok(loaded) => loaded,
+        Err(LoadError::OutOfMemory) => return SysResult::OUT_OF_MEMORY,
+        Err(LoadError::UnknownFormat)
+        | Err(LoadError::Invalid(_))
+        | Err(LoadError::CommandLineTooLarge) => return SysResult::INVALID_VALUE,
+    };
+
+    // The new process can never be more privileged than the one that created it.
+    process.capabilities =
+        Capabilities::from_bits_retain(capabilities as u32) & glob.processes.current().capabilities;
+    process.parent = glob.processes.current_id();
+
+    match glob.processes.spawn_process(process, registers) {
+        Ok((id, _)) => {
+            log::trace!("Process {} spawned", id);
+            try_user!(process_id.write(id));
+            SysResult::SUCCESS
+        }
+        Err(TooManyProcesses) => SysResult::TOO_MANY_PROCESSES,
+    }
+}
+
+/// See [`ruel_sys::spawn_thread`].
+pub unsafe extern "C" fn spawn_thread(
+    entry_point: usize,
+    stack_pointer: usize,
+    arg: usize,
+    thread_id: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let thread_id = UserPtr::<ThreadId>::new(thread_id);
+
+    if entry_point > USERLAND_STOP || stack_pointer > USERLAND_STOP {
+        return SysResult::INVALID_VALUE;
+    }
+
+    if !thread_id.is_null() {
+        try_user!(thread_id.check_writable());
+    }
+
+    let registers = Registers {
+        rip: entry_point,
+        rsp: stack_pointer,
+        gprs: GeneralPurposeRegisters {
+            rdi: arg,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let process_id = glob.processes.current_id();
+
+    match glob.processes.spawn_thread(process_id, registers) {
+        Ok(id) => {
+            log::trace!("Thread {} spawned in process {}", id, process_id);
+
+            if !thread_id.is_null() {
+                try_user!(thread_id.write(id));
+            }
+
+            SysResult::SUCCESS
+        }
+        Err(TooManyThreads) => SysResult::TOO_MANY_THREADS,
+    }
+}
+
+/// See [`ruel_sys::despawn_thread`].
+pub unsafe extern "C" fn despawn_thread(
+    thread_id: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let process_id = glob.processes.current_id();
+    let thread_id = if thread_id == ThreadId::MAX {
+        glob.processes.current_thread_id()
+    } else {
+        thread_id
+    };
+
+    match glob.processes.despawn_thread(process_id, thread_id) {
+        Ok(None) => (),
+        Ok(Some(process)) => {
+            // That was the last thread of the process.
+            process.release(glob, process_id);
+            log::trace!("Process {} despawned (no threads left)", process_id);
+        }
+        Err(ThreadNotFound) => return SysResult::THREAD_NOT_FOUND,
+    }
+
+    log::trace!("Thread {} despawned", thread_id);
+
+    SysResult::SUCCESS
+}
+
+/// See [`ruel_sys::set_exception_handler`].
+pub unsafe extern "C" fn set_exception_handler(
+    entry_point: usize,
+    stack_top: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    if entry_point > USERLAND_STOP || stack_top > USERLAND_STOP + 1 {
+        return SysResult::INVALID_VALUE;
+    }
+
+    if entry_point == 0 {
+        let process = glob.processes.current_id();
+        glob.processes.current().exception_handler = None;
+        glob.processes
+            .for_each_thread_mut(process, |thread| thread.exception_stack = None);
+    } else {
+        glob.processes.with_current(|process, thread| {
+            process.exception_handler = Some(entry_point);
+            thread.exception_stack = Some(ExceptionStack::new(stack_top));
+        });
+    }
+
+    SysResult::SUCCESS
+}
+
+/// See [`ruel_sys::resume_context`].
+pub unsafe extern "C" fn resume_context(
+    context: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let context = try_user!(UserPtr::<Context>::new(context).read());
+
+    if !crate::process::is_valid_context(&context) {
+        return SysResult::INVALID_VALUE;
+    }
+
+    glob.processes.with_current(|_, thread| {
+        if let Some(stack) = &mut thread.exception_stack {
+            stack.busy = false;
+        }
+    });
+
+    let frame = unsafe { TrapFrame::from_user_entry() };
+    crate::process::restore_context(frame, &context);
+
+    // The return value of the system call is written to the `rax` register of the frame once
+    // this function returns. Returning the value of `rax` itself ensures that it is preserved.
+    SysResult::from_raw(context.rax)
+}
+
+/// See [`ruel_sys::protect_memory`].
+pub unsafe extern "C" fn protect_memory(
+    addr: usize,
+    count: usize,
+    prot: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    if addr % FOUR_KIB != 0 || count % FOUR_KIB != 0 {
+        return SysResult::INVALID_VALUE;
+    }
+
+    match addr.checked_add(count) {
+        Some(end) if end <= USERLAND_STOP + 1 => (),
+        _ => return SysResult::INVALID_VALUE,
+    }
+
+    let prot = ProtectionFlags::from_bits_retain(prot as u8);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
+    }
+    let mut flags = PageTableEntry::empty();
+    if prot.intersects(ProtectionFlags::READ | ProtectionFlags::WRITE | ProtectionFlags::EXECUTE) {
+        flags.insert(PageTableEntry::PRESENT);
+    }
+    if prot.intersects(ProtectionFlags::WRITE) {
+        flags.insert(PageTableEntry::WRITABLE);
+    }
+    if !prot.intersects(ProtectionFlags::EXECUTE) {
+        flags.insert(PageTableEntry::NO_EXECUTE);
+    }
+
+    let mut current = glob.processes.current();
+
+    // Changing the protection of part of a region splits it, which must not fail once the page
+    // tables have been modified.
+    match current.regions.prepare_split(addr, count) {
+        Ok(()) => (),
+        Err(RegionError::NotMapped) => return SysResult::ALREADY_MAPPED,
+        Err(RegionError::Indivisible) => return SysResult::INVALID_VALUE,
+        Err(RegionError::OutOfMemory) => return SysResult::OUT_OF_MEMORY,
+    }
+
+    match current.address_space.protect_range(addr, count, flags) {
+        Ok(()) => {
+            let _ret = current.regions.protect(addr, count, prot);
+            debug_assert!(_ret.is_ok());
+
+            invalidate_range(addr, count);
+
+            SysResult::SUCCESS
+        }
+        Err(PageMiss { mapping: true, .. }) => SysResult::INVALID_VALUE,
+        Err(PageMiss { mapping: false, .. }) => SysResult::ALREADY_MAPPED,
+    }
+}
+
+/// See [`ruel_sys::list_mappings`].
+pub unsafe extern "C" fn list_mappings(
+    mappings: usize,
+    count: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let count = UserPtr::<usize>::new(count);
+    let mappings = UserSlice::<MemoryMapping>::new(mappings, try_user!(count.read()));
+
+    let current = glob.processes.current();
+    let regions = current.regions.iter();
+    let total = regions.len();
+
+    // The mappings are collected first, as the lock must be released before writing them.
+    let mut buf = Vec::new();
+    if buf.try_reserve_exact(total.min(mappings.len())).is_err() {
+        return SysResult::OUT_OF_MEMORY;
+    }
+    buf.extend(
+        regions
+            .take(mappings.len())
+            .map(|region| region.to_mapping()),
+    );
+    drop(current);
+
+    try_user!(mappings.write_from(&buf));
+    try_user!(count.write(total));
 
     SysResult::SUCCESS
 }
+
+/// See [`ruel_sys::create_shared_memory`].
+pub unsafe extern "C" fn create_shared_memory(
+    count: usize,
+    prot: usize,
+    id: usize,
+    out: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    if count % FOUR_KIB != 0 || count == 0 {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let id = UserPtr::<SharedMemoryId>::new(id);
+    let out = UserPtr::<*mut u8>::new(out);
+    try_user!(id.check_writable());
+    try_user!(out.check_writable());
+
+    let prot = ProtectionFlags::from_bits_retain(prot as u8);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let shared_id = match glob.shared_memory.create(&glob.allocator, count / FOUR_KIB) {
+        Ok(shared_id) => shared_id,
+        Err(OutOfMemory) => return SysResult::OUT_OF_MEMORY,
+    };
+
+    let result = glob.processes.with_current(|process, _| {
+        process.grant_shared_memory(shared_id)?;
+        let result = glob.shared_memory.with_pages(shared_id, |pages| {
+            process.map_shared(shared_id, pages, prot)
+        });
+
+        // The object is released below, and must not remain granted to the process.
+        if result.is_err() {
+            process
+                .shared_memory_grants
+                .retain(|&granted| granted != shared_id);
+        }
+
+        result
+    });
+
+    match result {
+        Ok(addr) => {
+            invalidate_range(addr, count);
+
+            try_user!(id.write(shared_id));
+            try_user!(out.write(addr as *mut u8));
+
+            SysResult::SUCCESS
+        }
+        Err(OutOfMemory) => {
+            glob.shared_memory.release(&glob.allocator, shared_id);
+            SysResult::OUT_OF_MEMORY
+        }
+    }
+}
+
+/// See [`ruel_sys::map_shared_memory`].
+pub unsafe extern "C" fn map_shared_memory(
+    id: usize,
+    prot: usize,
+    out: usize,
+    size: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let out = UserPtr::<*mut u8>::new(out);
+    let size = UserPtr::<usize>::new(size);
+    try_user!(out.check_writable());
+    if !size.is_null() {
+        try_user!(size.check_writable());
+    }
+
+    let prot = ProtectionFlags::from_bits_retain(prot as u8);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let mut current = glob.processes.current();
+
+    // The object is reported as missing when the process has not been granted access to it,
+    // such that its existence is not revealed.
+    if !current.shared_memory_grants.contains(&id) {
+        return SysResult::SHARED_MEMORY_NOT_FOUND;
+    }
+
+    let result = glob.shared_memory.acquire(id, |pages| {
+        let addr = current.map_shared(id, pages, prot)?;
+        Ok((addr, pages.len() * FOUR_KIB))
+    });
+    drop(current);
+
+    match result {
+        Err(SharedMemoryNotFound) => SysResult::SHARED_MEMORY_NOT_FOUND,
+        Ok(Ok((addr, count))) => {
+            invalidate_range(addr, count);
+
+            try_user!(out.write(addr as *mut u8));
+            if !size.is_null() {
+                try_user!(size.write(count));
+            }
+
+            SysResult::SUCCESS
+        }
+        Ok(Err(OutOfMemory)) => {
+            glob.shared_memory.release(&glob.allocator, id);
+            SysResult::OUT_OF_MEMORY
+        }
+    }
+}
+
+/// See [`ruel_sys::grant_shared_memory`].
+pub unsafe extern "C" fn grant_shared_memory(
+    id: usize,
+    process_id: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    if !glob.processes.current().shared_memory_grants.contains(&id) {
+        return SysResult::SHARED_MEMORY_NOT_FOUND;
+    }
+
+    let Ok(mut process) = glob.processes.get(process_id) else {
+        return SysResult::PROCESS_NOT_FOUND;
+    };
+
+    match process.grant_shared_memory(id) {
+        Ok(()) => SysResult::SUCCESS,
+        Err(OutOfMemory) => SysResult::OUT_OF_MEMORY,
+    }
+}
+
+/// See [`ruel_sys::duplicate_process`].
+pub unsafe extern "C" fn duplicate_process(
+    process_id: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let process_id = UserPtr::<ProcessId>::new(process_id);
+
+    // This must be written before the address space is duplicated, such that the new process
+    // sees it.
+    try_user!(process_id.write(ProcessId::MAX));
+
+    let mut child = match glob.processes.current().duplicate(glob) {
+        Ok(child) => child,
+        Err(OutOfMemory) => return SysResult::OUT_OF_MEMORY,
+    };
+    child.parent = glob.processes.current_id();
+
+    // Some of the pages of the current process have been made read-only.
+    unsafe { write_cr3(read_cr3()) };
+
+    // The new process resumes right after the system call, as if it had returned successfully.
+    let mut registers = Registers::default();
+    registers.save(unsafe { TrapFrame::from_user_entry() });
+    registers.gprs.rax = SysResult::SUCCESS.as_raw();
+
+    // The exception stack of the current thread is part of the duplicated address space.
+    let exception_stack = glob
+        .processes
+        .with_current(|_, thread| thread.exception_stack);
+
+    match glob.processes.spawn_process(child, registers) {
+        Ok((id, thread)) => {
+            glob.processes
+                .with_thread(thread, |thread| thread.exception_stack = exception_stack)
+                .unwrap();
+
+            log::trace!(
+                "Process {} duplicated into process {}",
+                glob.processes.current_id(),
+                id
+            );
+            try_user!(process_id.write(id));
+            SysResult::SUCCESS
+        }
+        Err(TooManyProcesses) => SysResult::TOO_MANY_PROCESSES,
+    }
+}
+
+/// See [`ruel_sys::map_device_memory`].
+pub unsafe extern "C" fn map_device_memory(
+    phys: usize,
+    count: usize,
+    prot: usize,
+    cache: usize,
+    out: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let mut current = glob.processes.current();
+
+    if !current
+        .capabilities
+        .contains(Capabilities::MAP_DEVICE_MEMORY)
+    {
+        return SysResult::MISSING_CAPABILITY;
+    }
+
+    if phys % FOUR_KIB != 0 || count % FOUR_KIB != 0 || count == 0 {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let out = UserPtr::<*mut u8>::new(out);
+    try_user!(out.check_writable());
+
+    let cache = match CachePolicy::from_raw(cache as u8) {
+        CachePolicy::UNCACHED => UNCACHED,
+        CachePolicy::WRITE_COMBINING => WRITE_COMBINING,
+        _ => return SysResult::INVALID_VALUE,
+    };
+
+    let phys = phys as PhysAddr;
+
+    // Only memory that's known to belong to a device may be mapped. Anything else might be
+    // RAM used by the kernel or by other processes.
+    if !glob
+        .device_memory
+        .iter()
+        .any(|range| range.contains(phys, count as u64))
+    {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let prot = ProtectionFlags::from_bits_retain(prot as u8);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let result = current.map_device(phys, count, prot, cache);
+    drop(current);
+
+    match result {
+        Ok(addr) => {
+            invalidate_range(addr, count);
+
+            try_user!(out.write(addr as *mut u8));
+
+            SysResult::SUCCESS
+        }
+        Err(OutOfMemory) => SysResult::OUT_OF_MEMORY,
+    }
+}
+
+/// See [`ruel_sys::drop_capabilities`].
+pub unsafe extern "C" fn drop_capabilities(
+    capabilities: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let capabilities = Capabilities::from_bits_retain(capabilities as u32);
+    glob.processes.current().capabilities.remove(capabilities);
+
+    SysResult::SUCCESS
+}
+
+/// See [`ruel_sys::allocate_dma_memory`].
+pub unsafe extern "C" fn allocate_dma_memory(
+    count: usize,
+    prot: usize,
+    flags: usize,
+    virt: usize,
+    phys: usize,
+    _: usize,
+) -> SysResult {
+    let glob = GlobalToken::get();
+
+    let mut current = glob.processes.current();
+
+    if !current
+        .capabilities
+        .contains(Capabilities::ALLOCATE_DMA_MEMORY)
+    {
+        return SysResult::MISSING_CAPABILITY;
+    }
+
+    if count % FOUR_KIB != 0 || count == 0 {
+        return SysResult::INVALID_VALUE;
+    }
+
+    let virt = UserPtr::<*mut u8>::new(virt);
+    let phys = UserPtr::<u64>::new(phys);
+    try_user!(virt.check_writable());
+    try_user!(phys.check_writable());
+
+    let prot = ProtectionFlags::from_bits_retain(prot as u8);
+    if !is_allowed_protection(prot) {
+        return SysResult::INVALID_VALUE;
+    }
+    let flags = DmaFlags::from_bits_retain(flags as u8);
+
+    let mut constraints = AllocConstraints::NONE;
+    if flags.intersects(DmaFlags::BELOW_4GIB) {
+        constraints.end = 1 << 32;
+    }
+
+    let result = current.allocate_dma(count, prot, constraints);
+    drop(current);
+
+    match result {
+        Ok((addr, phys_addr)) => {
+            invalidate_range(addr, count);
+
+            try_user!(virt.write(addr as *mut u8));
+            try_user!(phys.write(phys_addr));
+
+            SysResult::SUCCESS
+        }
+        Err(OutOfMemory) => SysResult::OUT_OF_MEMORY,
+    }
+}
+
+/// Returns whether pages may be mapped with the provided protection.
+///
+/// Pages that are both writable and executable are only allowed when
+/// [`ProtectionFlags::ALLOW_WRITE_EXECUTE`] is set.
+fn is_allowed_protection(prot: ProtectionFlags) -> bool {
+    !prot.contains(ProtectionFlags::WRITE | ProtectionFlags::EXECUTE)
+        || prot.intersects(ProtectionFlags::ALLOW_WRITE_EXECUTE)
+}
+
+/// Copies the provided userspace buffer into a kernel buffer.
+///
+/// # Errors
+///
+/// `INVALID_VALUE` is returned without allocating anything if the buffer is larger than
+/// `max_len` bytes.
+fn read_user_bytes(slice: UserSlice<u8>, max_len: usize) -> Result<Vec<u8>, SysResult> {
+    if slice.len() > max_len {
+        return Err(SysResult::INVALID_VALUE);
+    }
+
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(slice.len())
+        .map_err(|_| SysResult::OUT_OF_MEMORY)?;
+
+    if slice
+        .read_into(&mut buf.spare_capacity_mut()[..slice.len()])
+        .is_err()
+    {
+        return Err(SysResult::INVALID_POINTER);
+    }
+
+    unsafe { buf.set_len(slice.len()) };
+    Ok(buf)
+}
+
+/// Invalidates the TLB entries of the provided range of virtual addresses.
+fn invalidate_range(mut addr: VirtAddr, mut count: usize) {
+    while count != 0 {
+        invlpg(addr);
+        addr += FOUR_KIB;
+        count -= FOUR_KIB;
+    }
+}
@@ -1,10 +1,13 @@
 use core::arch::asm;
 
 use ruel_sys::SysResult;
-use x86_64::{wrmsr, Efer, Ring, LSTAR, STAR};
+use x86_64::{wrmsr, Efer, RFlags, Ring, FS_BASE, GS_BASE, LSTAR, SFMASK, STAR};
 
-use crate::global::{GlobalToken, KERNEL_STACK_TOP};
+use crate::cpu::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::cpu::trap::{pop_gprs, pop_segment_bases, push_gprs, push_segment_bases, TrapFrame};
+use crate::global::{GlobalToken, KERNEL_STACK_TOP, SYSCALL_USER_RSP};
 use crate::log;
+use crate::process::USERLAND_STOP;
 
 mod handlers;
 
@@ -12,7 +15,7 @@ mod handlers;
 type SystemCallFn = unsafe extern "C" fn(usize, usize, usize, usize, usize, usize) -> SysResult;
 
 /// The total number of system calls.
-const SYSTEM_CALL_COUNT: usize = 9;
+const SYSTEM_CALL_COUNT: usize = 23;
 
 /// A lookup table of system call handlers.
 ///
@@ -29,6 +32,20 @@ static SYSTEM_CALLS: [SystemCallFn; SYSTEM_CALL_COUNT] = [
     handlers::map_memory,
     handlers::unmap_memory,
     handlers::kernel_log,
+    handlers::spawn_process,
+    handlers::spawn_thread,
+    handlers::despawn_thread,
+    handlers::set_exception_handler,
+    handlers::resume_context,
+    handlers::protect_memory,
+    handlers::list_mappings,
+    handlers::create_shared_memory,
+    handlers::map_shared_memory,
+    handlers::duplicate_process,
+    handlers::map_device_memory,
+    handlers::drop_capabilities,
+    handlers::allocate_dma_memory,
+    handlers::grant_shared_memory,
 ];
 
 /// The function that is called when a userspace program executes the `syscall` instruction.
@@ -56,9 +73,9 @@ static SYSTEM_CALLS: [SystemCallFn; SYSTEM_CALL_COUNT] = [
 ///
 /// Registers in bold are the one that are different from the C calling convention.
 ///
-/// **SCRATCH REGISTERS:** rax, rdi, rsi, rdx, ~**rcx**~, r8, r9, r10, r11, **r12**.
+/// **SCRATCH REGISTERS:** rax, rdi, rsi, rdx, ~**rcx**~, r8, r9, r10, r11.
 ///
-/// **PRESERVED REGISTERS:** rbx, rsp, rbp, ~**r12**~, r13, r14, r15
+/// **PRESERVED REGISTERS:** rbx, rsp, rbp, r12, r13, r14, r15
 ///
 /// # Returns
 ///
@@ -75,53 +92,116 @@ static SYSTEM_CALLS: [SystemCallFn; SYSTEM_CALL_COUNT] = [
 #[naked]
 unsafe extern "C" fn syscall_handler() {
     unsafe {
-        // The `syscall` instruction invoked by the userland program puts the return address in
-        // the `rcx` register. We need to save it on the stack before clobbering all the registers
-        // by calling the system call handler.
-        //
         // Note that system calls must not touch the stack of the caller, as it might be invalid
-        // or broken. Instead, we need to use our own stack. The stack pointer of the caller is
-        // saved on the kernel stack, and will be restored before returning with `sysretq`.
+        // or broken. Instead, we need to use our own stack.
+        //
+        // The state of the caller is saved in a `TrapFrame` at the top of the kernel stack. The
+        // part of the frame normally pushed by the CPU when an interrupt occurs is pushed
+        // manually, using the return address saved in `rcx` and the RFLAGS saved in `r11` by the
+        // `syscall` instruction. The stack pointer of the caller is stashed in
+        // `SYSCALL_USER_RSP` while switching stacks, such that every general-purpose register
+        // is saved unmodified. Saving the segment bases clobbers `rax` and `rdx`, so they are
+        // reloaded from the frame before calling the system call handler.
+        //
+        // After the system call has been handled, the scheduler might decide to switch to another
+        // process, in which case the frame will have been overwritten with the state of that
+        // process. When the frame still looks like something `sysretq` can restore, we use it
+        // because it is faster. Otherwise, we need to go through `iretq`.
         //
-        // We're calling a C function, which writes the return value in the `rax` register. Our
-        // system calls also return the value in `rax`, so we don't need to do anything more than
-        // calling the function.
+        // On Intel processors, `sysretq` raises a general protection fault *in ring 0* (but with
+        // the user stack already loaded) when the return address is not canonical. Return
+        // addresses outside of userland always go through `iretq`.
         asm!(
             r#"
             cmp rax, {syscall_count}
             jae 2f
 
-            mov r12, [{kernel_stack_top}]
-            sub r12, 8
-            and r12, -8
-            mov [r12], rsp
-            mov rsp, r12
+            mov [{user_rsp}], rsp
+            mov rsp, [{kernel_stack_top}]
 
-            push rbp
-            mov rbp, rsp
+            push {user_ss}
+            push qword ptr [{user_rsp}]
+            push r11
+            push {user_cs}
             push rcx
+            push 0
+            push {syscall_vector}
+            "#,
+            push_gprs!(),
+            push_segment_bases!(),
+            r#"
+            mov rax, [rsp + 8 * {rax_index}]
+            mov rdx, [rsp + 8 * {rdx_index}]
 
             mov rcx, r10
             call [{system_calls} + 8 * rax]
+            mov [rsp + 8 * {rax_index}], rax
 
-            pop rcx
-            pop rbp
-            pop rsp
+            mov rdi, rsp
+            call {after_syscall}
+
+            mov rcx, [rsp + 8 * {rcx_index}]
+            cmp rcx, [rsp + 8 * {rip_index}]
+            jne 3f
+            mov r11, rcx
+            shr r11, {userland_bits}
+            jnz 3f
+            mov r11, [rsp + 8 * {r11_index}]
+            cmp r11, [rsp + 8 * {rflags_index}]
+            jne 3f
+            "#,
+            pop_segment_bases!(),
+            pop_gprs!(),
+            r#"
+            mov rsp, [rsp + 8 * ({rsp_index} - {vector_index})]
             sysretq
 
+        3:
+            "#,
+            pop_segment_bases!(),
+            pop_gprs!(),
+            r#"
+            add rsp, 16
+            iretq
+
         2:
             mov rax, {invalid_syscall_number}
             sysretq
             "#,
             kernel_stack_top = sym KERNEL_STACK_TOP,
+            user_rsp = sym SYSCALL_USER_RSP,
             syscall_count = const SYSTEM_CALL_COUNT,
             system_calls = sym SYSTEM_CALLS,
+            after_syscall = sym after_syscall,
+            user_cs = const USER_CODE_SELECTOR.bits(),
+            user_ss = const USER_DATA_SELECTOR.bits(),
+            // `push` sign-extends its 32-bit immediate operand to 64 bits.
+            syscall_vector = const TrapFrame::SYSCALL_VECTOR as i32,
+            rax_index = const TrapFrame::RAX_INDEX,
+            rdx_index = const TrapFrame::RDX_INDEX,
+            rcx_index = const TrapFrame::RCX_INDEX,
+            r11_index = const TrapFrame::R11_INDEX,
+            rip_index = const TrapFrame::RIP_INDEX,
+            rflags_index = const TrapFrame::RFLAGS_INDEX,
+            rsp_index = const TrapFrame::RSP_INDEX,
+            vector_index = const TrapFrame::VECTOR_INDEX,
             invalid_syscall_number = const SysResult::INVALID_VALUE.as_raw(),
+            userland_bits = const (USERLAND_STOP + 1).trailing_zeros(),
+            fs_base_msr = const FS_BASE,
+            gs_base_msr = const GS_BASE,
             options(noreturn),
         )
     }
 }
 
+/// The function called by [`syscall_handler`] once the system call itself has been handled.
+///
+/// This gives the scheduler the opportunity to switch to another process if the current one
+/// went to sleep during the system call.
+extern "C" fn after_syscall(frame: &mut TrapFrame) {
+    GlobalToken::get().processes.reschedule(frame, false);
+}
+
 /// Initialize the system call handler.
 #[allow(clippy::assertions_on_constants)]
 pub fn init() {
@@ -136,7 +216,6 @@ pub fn init() {
     // Specify the code segment and data segment to use when executing the **SYSCALL** and
     // **SYSRET** instructions.
     use crate::cpu::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
-    use crate::cpu::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
 
     // This constant specifies the segment selectors that will be loaded when the **SYSRET**
     // instruction is loaded.
@@ -160,6 +239,14 @@ pub fn init() {
 
     register_syscall_segments(SYSCALL_BASE, SYSRET_BASE);
 
+    // System calls run with interrupts disabled: the interrupt handlers lock the processes
+    // themselves, and must not run while a system call is modifying one of them. The direction
+    // flag is also cleared, as expected by the C calling convention. The alignment check flag is
+    // cleared to prevent userspace from disabling SMAP in the kernel.
+    register_syscall_flag_mask(
+        RFlags::INTERRUPTS | RFlags::DIRECTION | RFlags::TRAP | RFlags::ALIGNMENT_CHECK,
+    );
+
     // Intel processors normally use **SYSENTER** and **SYSEXIT** instructions to perform system
     // calls. However, Intel also provide a way to use the **SYSCALL** and **SYSRET** instructions
     // instead. This is what we're going to use, because that allows us to be compatible with AMD
@@ -186,3 +273,9 @@ fn register_syscall_routine(routine: usize) {
 fn register_syscall_segments(syscall: u16, sysret: u16) {
     unsafe { wrmsr(STAR, (syscall as u64) << 32 | (sysret as u64) << 48) }
 }
+
+/// Registers the RFLAGS bits that should be cleared when the **SYSCALL** instruction is executed.
+#[inline]
+fn register_syscall_flag_mask(mask: RFlags) {
+    unsafe { wrmsr(SFMASK, mask.bits()) }
+}
@@ -0,0 +1,281 @@
+//! Defines the [`TrapFrame`] that's saved on the kernel stack when the CPU enters the kernel, as
+//! well as the routines used to push and restore it.
+//!
+//! Every entry point of the kernel (system calls, hardware interrupts and CPU exceptions) saves
+//! the state of the interrupted program using the same layout, allowing the scheduler to switch
+//! between processes regardless of how the kernel was entered.
+
+use core::arch::asm;
+use core::mem::size_of;
+
+use x86_64::{FS_BASE, GS_BASE};
+
+use crate::global::KERNEL_STACK_TOP;
+
+/// The general-purpose registers of the CPU.
+///
+/// The order of the fields matches the order in which [`push_gprs!`] pushes the registers
+/// on the stack.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GeneralPurposeRegisters {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rbp: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rbx: usize,
+    pub rax: usize,
+}
+
+/// The state of the CPU, saved on the kernel stack when the kernel is entered.
+///
+/// # Layout
+///
+/// The last five fields are pushed by the CPU itself when an interrupt occurs. The system call
+/// handler pushes them manually to mimic that layout.
+///
+/// The `error_code` is pushed by the CPU for some exceptions only. Other entry points push a
+/// zero instead, such that the layout remains the same. The `vector` is then pushed by the entry
+/// point itself, followed by the general-purpose registers (using [`push_gprs!`]) and the
+/// segment base addresses (using [`push_segment_bases!`]).
+///
+/// When the kernel is entered from userspace, the frame is always located at the very top of the
+/// kernel stack.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    /// The base address of the FS segment of the interrupted program.
+    pub fs_base: usize,
+    /// The base address of the GS segment of the interrupted program.
+    pub gs_base: usize,
+    /// The general-purpose registers of the interrupted program.
+    pub gprs: GeneralPurposeRegisters,
+    /// The interrupt vector that caused the kernel to be entered.
+    ///
+    /// This is [`TrapFrame::SYSCALL_VECTOR`] when the kernel was entered through the `syscall`
+    /// instruction.
+    pub vector: usize,
+    /// The error code pushed by the CPU, or zero if the interrupt vector does not have one.
+    pub error_code: usize,
+    /// The instruction pointer of the interrupted program.
+    pub rip: usize,
+    /// The code segment selector of the interrupted program.
+    pub cs: usize,
+    /// The RFLAGS register of the interrupted program.
+    pub rflags: usize,
+    /// The stack pointer of the interrupted program.
+    pub rsp: usize,
+    /// The stack segment selector of the interrupted program.
+    pub ss: usize,
+}
+
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(size_of::<TrapFrame>() == 8 * 24);
+
+impl TrapFrame {
+    pub const R11_INDEX: usize = 6;
+    pub const RDX_INDEX: usize = 13;
+    pub const RCX_INDEX: usize = 14;
+    pub const RAX_INDEX: usize = 16;
+    pub const VECTOR_INDEX: usize = 17;
+    pub const RIP_INDEX: usize = 19;
+    pub const RFLAGS_INDEX: usize = 21;
+    pub const RSP_INDEX: usize = 22;
+
+    /// The value of the `vector` field when the kernel is entered through the `syscall`
+    /// instruction.
+    pub const SYSCALL_VECTOR: usize = usize::MAX;
+
+    /// Returns whether the frame was pushed while the CPU was executing userspace code.
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 0b11
+    }
+
+    /// Returns the frame that was pushed when the kernel was entered from userspace.
+    ///
+    /// This is useful for code that does not receive the frame directly, such as system call
+    /// handlers.
+    ///
+    /// # Safety
+    ///
+    /// The kernel must have been entered from userspace, and the caller must make sure that no
+    /// other reference to the frame is alive.
+    #[inline]
+    pub unsafe fn from_user_entry() -> &'static mut Self {
+        unsafe { &mut *((KERNEL_STACK_TOP - size_of::<Self>()) as *mut Self) }
+    }
+}
+
+/// Expands to the instructions pushing the general-purpose registers on the stack, in the
+/// order expected by [`GeneralPurposeRegisters`].
+pub macro push_gprs() {
+    "
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    "
+}
+
+/// Expands to the instructions popping the general-purpose registers from the stack. This is the
+/// reverse of [`push_gprs!`].
+pub macro pop_gprs() {
+    "
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    "
+}
+
+/// Expands to the instructions pushing the base addresses of the GS and FS segments on the
+/// stack, in the order expected by [`TrapFrame`].
+///
+/// The `rax`, `rcx` and `rdx` registers are clobbered, meaning that this must be used after
+/// [`push_gprs!`].
+///
+/// The `fs_base_msr` and `gs_base_msr` operands must be provided as constants set to
+/// [`x86_64::FS_BASE`] and [`x86_64::GS_BASE`].
+pub macro push_segment_bases() {
+    "
+    mov ecx, {gs_base_msr}
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    push rax
+    mov ecx, {fs_base_msr}
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    push rax
+    "
+}
+
+/// Expands to the instructions popping the base addresses of the FS and GS segments from the
+/// stack. This is the reverse of [`push_segment_bases!`].
+///
+/// The `rax`, `rcx` and `rdx` registers are clobbered, meaning that this must be used before
+/// [`pop_gprs!`].
+///
+/// The same operands as for [`push_segment_bases!`] must be provided.
+pub macro pop_segment_bases() {
+    "
+    pop rax
+    mov rdx, rax
+    shr rdx, 32
+    mov ecx, {fs_base_msr}
+    wrmsr
+    pop rax
+    mov rdx, rax
+    shr rdx, 32
+    mov ecx, {gs_base_msr}
+    wrmsr
+    "
+}
+
+/// Defines an interrupt service routine named `$name` that saves the state of the interrupted
+/// program in a [`TrapFrame`] and calls `$handler` with a mutable reference to it.
+///
+/// When `$handler` returns, the state described by the frame is restored. This allows the
+/// handler to switch to another process by modifying the frame.
+///
+/// The `error_code` variant must be used for interrupt vectors for which the CPU pushes an error
+/// code on the stack.
+pub macro interrupt_entry {
+    ($name:ident, $vector:expr, $handler:path) => {
+        $crate::cpu::trap::interrupt_entry!(@define $name, $vector, $handler, "push 0");
+    },
+    ($name:ident, $vector:expr, $handler:path, error_code) => {
+        $crate::cpu::trap::interrupt_entry!(@define $name, $vector, $handler, "");
+    },
+    (@define $name:ident, $vector:expr, $handler:path, $push_error_code:literal) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() {
+            unsafe {
+                ::core::arch::asm!(
+                    $push_error_code,
+                    "push {vector}",
+                    $crate::cpu::trap::push_gprs!(),
+                    $crate::cpu::trap::push_segment_bases!(),
+                    "cld",
+                    // Userspace can set the AC flag, which would allow the kernel to access its
+                    // memory when SMAP is enabled. `clac` is only available when the CPU
+                    // supports SMAP. The saved RFLAGS restore the flag on `iretq`.
+                    "cmp byte ptr [rip + {smap_enabled}], 0",
+                    "je 2f",
+                    "clac",
+                    "2:",
+                    "mov rdi, rsp",
+                    "call {handler}",
+                    $crate::cpu::trap::pop_segment_bases!(),
+                    $crate::cpu::trap::pop_gprs!(),
+                    "add rsp, 16",
+                    "iretq",
+                    // `push` sign-extends its immediate operand to 64 bits, which must not
+                    // affect vectors above 127.
+                    vector = const $vector as u8 as i32,
+                    handler = sym $handler,
+                    fs_base_msr = const ::x86_64::FS_BASE,
+                    gs_base_msr = const ::x86_64::GS_BASE,
+                    smap_enabled = sym $crate::cpu::protection::SMAP_ENABLED,
+                    options(noreturn),
+                );
+            }
+        }
+    },
+}
+
+/// Restores the state described by the provided [`TrapFrame`], returning to the code it
+/// was saved from.
+///
+/// # Safety
+///
+/// The provided frame must describe a valid state to return to, and the active address space
+/// must be the one that state expects.
+#[naked]
+pub unsafe extern "C" fn resume(frame: *const TrapFrame) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, rdi",
+            pop_segment_bases!(),
+            pop_gprs!(),
+            "add rsp, 16",
+            "iretq",
+            fs_base_msr = const FS_BASE,
+            gs_base_msr = const GS_BASE,
+            options(noreturn),
+        );
+    }
+}
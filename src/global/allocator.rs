@@ -1,22 +1,106 @@
-use core::mem::MaybeUninit;
+use core::mem::size_of;
 
 use x86_64::PhysAddr;
 
-use crate::cpu::paging::HhdmToken;
-use crate::utility::{BumpAllocator, FixedVec};
+use crate::cpu::paging::{HhdmToken, FOUR_KIB, HHDM_OFFSET};
+use crate::sync::Mutex;
+use crate::utility::BumpAllocator;
 
-/// A memory allocator that keeps track of a list of free regions.
+/// The largest order of a block managed by the [`MemoryAllocator`].
+///
+/// A block of order `n` is made of `2^n` contiguous pages, meaning that the largest blocks
+/// are 1 GiB in size.
+pub const MAX_ORDER: usize = 18;
+
+/// A special physical address used to mark the end of a free list.
+const NO_BLOCK: PhysAddr = PhysAddr::MAX;
+
+/// The number of pre-zeroed pages that the [`MemoryAllocator`] attempts to keep around.
+const ZEROED_POOL_TARGET: usize = 64;
+
+/// The header written at the beginning of every free block.
+///
+/// Free blocks of the same order are linked together in a doubly-linked list, allowing a block
+/// to be removed from its list when it gets merged with its buddy.
+#[repr(C)]
+struct FreeBlock {
+    /// The physical address of the previous block in the list, or [`NO_BLOCK`].
+    prev: PhysAddr,
+    /// The physical address of the next block in the list, or [`NO_BLOCK`].
+    next: PhysAddr,
+}
+
+/// Some constraints that a block returned by [`MemoryAllocator::allocate_with`] must respect.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocConstraints {
+    /// The alignment of the block, in bytes.
+    ///
+    /// This must be a power of two. Blocks are always aligned to their own size, so this only
+    /// matters when it is larger than that.
+    pub align: usize,
+    /// The lowest physical address that the block may start at.
+    pub start: PhysAddr,
+    /// One past the highest physical address that the block may end at.
+    pub end: PhysAddr,
+}
+
+impl AllocConstraints {
+    /// No constraints at all.
+    pub const NONE: Self = Self {
+        align: FOUR_KIB,
+        start: 0,
+        end: PhysAddr::MAX,
+    };
+}
+
+/// A buddy allocator managing the physical memory of the system.
+///
+/// Free memory is split in blocks of `2^order` contiguous pages, each of them aligned to its own
+/// size. Allocating a block splits larger blocks as needed, and deallocating a block merges it
+/// back with its buddy when that one is free as well.
+///
+/// On top of that, the allocator keeps a small pool of pages that have already been zeroed. That
+/// pool is refilled when the CPU has nothing better to do, which makes handing zeroed pages to
+/// userspace cheap.
 pub struct MemoryAllocator {
     /// We know that the HHDM has been initated already.
     _hhdm: HhdmToken,
-    /// A list of the pages that are currently free and available for use.
-    free_list: FixedVec<&'static mut [MaybeUninit<PhysAddr>]>,
+    /// The first free block of each order, or [`NO_BLOCK`] if the list is empty.
+    free_lists: [PhysAddr; MAX_ORDER + 1],
+    /// For every physical page of the system, stores `order + 1` if that page is the start of
+    /// a free block of the given order, and `0` otherwise.
+    block_orders: &'static mut [u8],
+    /// For every physical page of the system, stores the number of references to that page
+    /// beyond the first one.
+    ///
+    /// Deallocating a page that has additional references only drops one of them.
+    share_counts: &'static mut [u32],
+    /// The first page of the pool of pre-zeroed pages, or [`NO_BLOCK`] if the pool is empty.
+    ///
+    /// Those pages are linked together through their first 8 bytes, which are cleared when
+    /// the page is removed from the pool. The rest of the page is always zeroed.
+    zeroed_pool: PhysAddr,
+    /// The number of pages in the pool of pre-zeroed pages.
+    zeroed_count: usize,
+    /// The total number of pages given to the allocator with [`assume_available`].
+    ///
+    /// [`assume_available`]: MemoryAllocator::assume_available
+    total_pages: usize,
+    /// The number of pages that are part of a free block.
+    ///
+    /// This does not include the pages of the pool of pre-zeroed pages.
+    free_pages: usize,
+    /// The number of allocated pages that are currently used as page tables.
+    ///
+    /// See [`allocate_page_table`](MemoryAllocator::allocate_page_table).
+    page_table_pages: usize,
 }
 
 impl MemoryAllocator {
-    /// Creates a new empty [`MemoryAllocator`] with the given capacity.
+    /// Creates a new empty [`MemoryAllocator`].
     ///
-    /// The capacity is the maximum number of pages that can be managed by the allocator.
+    /// `memory_upper_bound` is the physical address past which no memory will ever be managed by
+    /// the allocator.
     ///
     /// # Safety
     ///
@@ -24,41 +108,449 @@ impl MemoryAllocator {
     pub unsafe fn empty(
         hhdm: HhdmToken,
         bootstrap_allocator: &mut BumpAllocator,
-        capacity: usize,
+        memory_upper_bound: PhysAddr,
     ) -> Result<Self, OutOfMemory> {
-        let free_list_slice = bootstrap_allocator.allocate_slice(capacity)?;
+        let page_count = (memory_upper_bound as usize).div_ceil(FOUR_KIB);
+        let block_orders = bootstrap_allocator.allocate_slice(page_count)?;
+        let share_counts = bootstrap_allocator.allocate_slice(page_count)?;
 
         Ok(Self {
             _hhdm: hhdm,
-            free_list: FixedVec::new(free_list_slice),
+            free_lists: [NO_BLOCK; MAX_ORDER + 1],
+            block_orders: crate::utility::init_slice_with(block_orders, |_| 0),
+            share_counts: crate::utility::init_slice_with(share_counts, |_| 0),
+            zeroed_pool: NO_BLOCK,
+            zeroed_count: 0,
+            total_pages: 0,
+            free_pages: 0,
+            page_table_pages: 0,
         })
     }
 
-    /// Assumes that a given page is available for use.
+    /// Returns the number of bytes that [`empty`] allocates to keep track of the state of each
+    /// page below `memory_upper_bound`.
+    ///
+    /// [`empty`]: MemoryAllocator::empty
+    pub fn metadata_size(memory_upper_bound: PhysAddr) -> usize {
+        let page_count = (memory_upper_bound as usize).div_ceil(FOUR_KIB);
+        page_count * (size_of::<u8>() + size_of::<u32>())
+    }
+
+    /// Assumes that the pages in the range `start..end` are available for use.
     ///
     /// # Safety
     ///
-    /// The allocator takes logical ownership of the physical page. Accessing it without having
-    /// allocated it becomes unsafe and may cause conflicts with other parts of the system.
-    pub unsafe fn assume_available(&mut self, page: PhysAddr) {
-        debug_assert!(page & 0xFFF == 0);
-        self.free_list.push(page);
+    /// The allocator takes logical ownership of the physical pages. Accessing them without having
+    /// allocated them becomes unsafe and may cause conflicts with other parts of the system.
+    pub unsafe fn assume_available(&mut self, mut start: PhysAddr, end: PhysAddr) {
+        debug_assert!(start & 0xFFF == 0 && end & 0xFFF == 0);
+
+        self.total_pages += (end.saturating_sub(start) / FOUR_KIB as PhysAddr) as usize;
+
+        while start < end {
+            // Find the largest block that starts at `start` and fits in the range.
+            let mut order = ((start / FOUR_KIB as u64).trailing_zeros() as usize).min(MAX_ORDER);
+            while start + block_size(order) > end {
+                order -= 1;
+            }
+
+            unsafe { self.deallocate_order(start, order) };
+            start += block_size(order);
+        }
+    }
+
+    /// Returns the total number of pages managed by the allocator, whether they are free or not.
+    #[inline]
+    pub fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    /// Returns the number of pages that are currently free, including the pre-zeroed ones.
+    #[inline]
+    pub fn free_pages(&self) -> usize {
+        self.free_pages + self.zeroed_count
+    }
+
+    /// Returns the number of allocated pages that are currently used as page tables.
+    #[inline]
+    pub fn page_table_pages(&self) -> usize {
+        self.page_table_pages
     }
 
     /// Allocates a new page.
     pub fn allocate(&mut self) -> Result<PhysAddr, OutOfMemory> {
-        self.free_list.pop().ok_or(OutOfMemory)
+        // Fast path: a single page is available already.
+        if let Some(page) = self.pop_free(0) {
+            return Ok(page);
+        }
+
+        // When the buddy allocator is exhausted, the pre-zeroed pages are the last resort.
+        self.allocate_order(0)
+            .or_else(|_| self.pop_zeroed().ok_or(OutOfMemory))
+    }
+
+    /// Allocates a new page that's going to be used as a page table.
+    ///
+    /// The page must be deallocated with [`deallocate_page_table`], such that it stops being
+    /// counted as a page table.
+    ///
+    /// [`deallocate_page_table`]: MemoryAllocator::deallocate_page_table
+    pub fn allocate_page_table(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        let page = self.allocate()?;
+        self.page_table_pages += 1;
+        Ok(page)
     }
 
-    /// Deallocates a page that was previously allocated.
+    /// Allocates a new page that's guaranteed to be filled with zeros.
+    ///
+    /// The page is taken from the pool of pre-zeroed pages when possible. Otherwise, it is
+    /// zeroed after the lock on the allocator has been released.
+    pub fn allocate_zeroed(this: &Mutex<Self>) -> Result<PhysAddr, OutOfMemory> {
+        let mut allocator = this.lock();
+
+        if let Some(page) = allocator.pop_zeroed() {
+            return Ok(page);
+        }
+
+        let page = allocator.allocate()?;
+        drop(allocator);
+
+        unsafe { zero_page(page) };
+        Ok(page)
+    }
+
+    /// Refills the pool of pre-zeroed pages.
+    ///
+    /// This is meant to be called when the CPU is idle. The lock on the allocator is released
+    /// while pages are being zeroed.
+    pub fn refill_zeroed_pool(this: &Mutex<Self>) {
+        loop {
+            let page = {
+                let mut allocator = this.lock();
+
+                if allocator.zeroed_count >= ZEROED_POOL_TARGET {
+                    return;
+                }
+
+                // Pages from the pool must not be used to refill the pool itself.
+                match allocator.allocate_order(0) {
+                    Ok(page) => page,
+                    Err(OutOfMemory) => return,
+                }
+            };
+
+            unsafe { zero_page(page) };
+
+            this.lock().push_zeroed(page);
+        }
+    }
+
+    /// Allocates a block of `2^order` contiguous pages.
+    ///
+    /// The returned block is aligned to its own size.
+    #[inline]
+    pub fn allocate_order(&mut self, order: usize) -> Result<PhysAddr, OutOfMemory> {
+        self.allocate_with(order, AllocConstraints::NONE)
+    }
+
+    /// Allocates a block of `2^order` contiguous pages that respects the provided constraints.
+    ///
+    /// # Errors
+    ///
+    /// This function fails with [`OutOfMemory`] if no free block satisfies the constraints.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `order` is larger than [`MAX_ORDER`], or if the requested alignment
+    /// is not a power of two.
+    pub fn allocate_with(
+        &mut self,
+        order: usize,
+        constraints: AllocConstraints,
+    ) -> Result<PhysAddr, OutOfMemory> {
+        assert!(order <= MAX_ORDER, "requested block order is too large");
+        assert!(
+            constraints.align.is_power_of_two(),
+            "requested alignment is not a power of two"
+        );
+
+        let size = block_size(order);
+        let align = size.max(constraints.align as u64);
+
+        for block_order in order..=MAX_ORDER {
+            let mut block = self.free_lists[block_order];
+
+            while block != NO_BLOCK {
+                let block_end = block + block_size(block_order);
+
+                // The first properly aligned sub-block of `block` that respects the constraints.
+                let candidate = block.max(constraints.start).checked_next_multiple_of(align);
+
+                if let Some(candidate) = candidate {
+                    if candidate
+                        .checked_add(size)
+                        .is_some_and(|end| end <= block_end && end <= constraints.end)
+                    {
+                        self.remove_free(block, block_order);
+                        self.carve(block, block_order, candidate, order);
+                        return Ok(candidate);
+                    }
+                }
+
+                block = unsafe { (*free_block(block)).next };
+            }
+        }
+
+        Err(OutOfMemory)
+    }
+
+    /// Allocates `count` physically contiguous pages that respect the provided constraints.
+    ///
+    /// Unlike blocks returned by [`allocate_with`], the pages must be deallocated individually
+    /// with [`deallocate`].
+    ///
+    /// # Errors
+    ///
+    /// This function fails with [`OutOfMemory`] if no free block is large enough, or if `count`
+    /// is larger than the largest block managed by the allocator.
+    ///
+    /// [`allocate_with`]: MemoryAllocator::allocate_with
+    /// [`deallocate`]: MemoryAllocator::deallocate
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        constraints: AllocConstraints,
+    ) -> Result<PhysAddr, OutOfMemory> {
+        debug_assert!(count != 0);
+
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(OutOfMemory);
+        }
+
+        let block = self.allocate_with(order, constraints)?;
+
+        // Give back the pages of the block that were not requested.
+        let mut page = block + (count * FOUR_KIB) as PhysAddr;
+        while page < block + block_size(order) {
+            unsafe { self.deallocate_order(page, 0) };
+            page += FOUR_KIB as PhysAddr;
+        }
+
+        Ok(block)
+    }
+
+    /// Adds a reference to a page that was previously allocated.
+    ///
+    /// The page will only be released once [`deallocate`] has been called once more than this
+    /// function.
+    ///
+    /// # Safety
+    ///
+    /// The provided page must have been allocated previously by this allocator, and must not have
+    /// been released yet.
+    ///
+    /// [`deallocate`]: MemoryAllocator::deallocate
+    pub unsafe fn share(&mut self, page: PhysAddr) {
+        let count = &mut self.share_counts[page_index(page)];
+        *count = count
+            .checked_add(1)
+            .expect("too many references to a single page");
+    }
+
+    /// Returns whether the provided page is currently referenced more than once.
+    #[inline]
+    pub fn is_shared(&self, page: PhysAddr) -> bool {
+        self.share_counts[page_index(page)] != 0
+    }
+
+    /// Drops a reference to a page that was previously allocated, releasing it if it was the
+    /// last one.
     ///
     /// # Safety
     ///
     /// The provided page must have been allocated previously by this allocator.
+    #[inline]
     pub unsafe fn deallocate(&mut self, page: PhysAddr) {
-        debug_assert!(page & 0xFFF == 0);
-        self.free_list.push(page);
+        let count = &mut self.share_counts[page_index(page)];
+        if *count != 0 {
+            *count -= 1;
+            return;
+        }
+
+        unsafe { self.deallocate_order(page, 0) }
     }
+
+    /// Deallocates a page table that was previously allocated with [`allocate_page_table`].
+    ///
+    /// # Safety
+    ///
+    /// The provided page must have been allocated previously by [`allocate_page_table`].
+    ///
+    /// [`allocate_page_table`]: MemoryAllocator::allocate_page_table
+    #[inline]
+    pub unsafe fn deallocate_page_table(&mut self, page: PhysAddr) {
+        self.page_table_pages -= 1;
+        unsafe { self.deallocate(page) }
+    }
+
+    /// Deallocates a block of `2^order` contiguous pages that was previously allocated.
+    ///
+    /// # Safety
+    ///
+    /// The provided block must have been allocated previously by this allocator, with the same
+    /// order. Alternatively, the pages of the block may have been allocated individually, as long
+    /// as none of them remains in use.
+    pub unsafe fn deallocate_order(&mut self, mut block: PhysAddr, mut order: usize) {
+        debug_assert!(block % block_size(order) == 0);
+
+        // Merge the block with its buddy for as long as it is free.
+        while order < MAX_ORDER {
+            let buddy = block ^ block_size(order);
+
+            if self.block_orders.get(page_index(buddy)) != Some(&(order as u8 + 1)) {
+                break;
+            }
+
+            self.remove_free(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+
+        self.push_free(block, order);
+    }
+
+    /// Splits the free block at `block` of order `block_order` until the sub-block at `target`
+    /// of order `order` is isolated.
+    ///
+    /// The other halves produced along the way are given back to the free lists.
+    fn carve(
+        &mut self,
+        mut block: PhysAddr,
+        mut block_order: usize,
+        target: PhysAddr,
+        order: usize,
+    ) {
+        while block_order > order {
+            block_order -= 1;
+            let half = block_size(block_order);
+
+            if target >= block + half {
+                self.push_free(block, block_order);
+                block += half;
+            } else {
+                self.push_free(block + half, block_order);
+            }
+        }
+
+        debug_assert_eq!(block, target);
+    }
+
+    /// Removes a page from the pool of pre-zeroed pages, if any.
+    fn pop_zeroed(&mut self) -> Option<PhysAddr> {
+        let page = self.zeroed_pool;
+
+        if page == NO_BLOCK {
+            return None;
+        }
+
+        let link = (page as usize + HHDM_OFFSET) as *mut PhysAddr;
+        self.zeroed_pool = unsafe { link.read() };
+        self.zeroed_count -= 1;
+        unsafe { link.write(0) };
+
+        Some(page)
+    }
+
+    /// Inserts a zeroed page in the pool of pre-zeroed pages.
+    fn push_zeroed(&mut self, page: PhysAddr) {
+        let link = (page as usize + HHDM_OFFSET) as *mut PhysAddr;
+        unsafe { link.write(self.zeroed_pool) };
+        self.zeroed_pool = page;
+        self.zeroed_count += 1;
+    }
+
+    /// Removes the first block of the free list of the provided order, if any.
+    fn pop_free(&mut self, order: usize) -> Option<PhysAddr> {
+        let block = self.free_lists[order];
+
+        if block == NO_BLOCK {
+            None
+        } else {
+            self.remove_free(block, order);
+            Some(block)
+        }
+    }
+
+    /// Inserts the provided block in the free list of the provided order.
+    fn push_free(&mut self, block: PhysAddr, order: usize) {
+        let head = self.free_lists[order];
+
+        unsafe {
+            free_block(block).write(FreeBlock {
+                prev: NO_BLOCK,
+                next: head,
+            });
+
+            if head != NO_BLOCK {
+                (*free_block(head)).prev = block;
+            }
+        }
+
+        self.free_lists[order] = block;
+        self.block_orders[page_index(block)] = order as u8 + 1;
+        self.free_pages += 1 << order;
+    }
+
+    /// Removes the provided block from the free list of the provided order.
+    fn remove_free(&mut self, block: PhysAddr, order: usize) {
+        debug_assert_eq!(self.block_orders[page_index(block)], order as u8 + 1);
+
+        let FreeBlock { prev, next } = unsafe { free_block(block).read() };
+
+        unsafe {
+            if prev == NO_BLOCK {
+                self.free_lists[order] = next;
+            } else {
+                (*free_block(prev)).next = next;
+            }
+
+            if next != NO_BLOCK {
+                (*free_block(next)).prev = prev;
+            }
+        }
+
+        self.block_orders[page_index(block)] = 0;
+        self.free_pages -= 1 << order;
+    }
+}
+
+/// Returns the size of a block of the provided order, in bytes.
+#[inline]
+fn block_size(order: usize) -> PhysAddr {
+    (FOUR_KIB as PhysAddr) << order
+}
+
+/// Returns the index of the page that contains the provided physical address.
+#[inline]
+fn page_index(addr: PhysAddr) -> usize {
+    addr as usize / FOUR_KIB
+}
+
+/// Fills the page at the provided physical address with zeros.
+///
+/// # Safety
+///
+/// The page must be owned by the caller.
+#[inline]
+unsafe fn zero_page(page: PhysAddr) {
+    unsafe { core::ptr::write_bytes((page as usize + HHDM_OFFSET) as *mut u8, 0, FOUR_KIB) };
+}
+
+/// Returns a pointer to the header of the free block at the provided physical address.
+#[inline]
+fn free_block(block: PhysAddr) -> *mut FreeBlock {
+    (block as usize + HHDM_OFFSET) as *mut FreeBlock
 }
 
 /// An error returned when an allocation fails because the system is out of memory.
@@ -47,6 +47,12 @@ impl Framebuffers {
         }
     }
 
+    /// Returns whether the framebuffers are currently owned by the given process.
+    #[inline]
+    pub fn is_owned_by(&self, id: ProcessId) -> bool {
+        self.owner.load(Relaxed) == id
+    }
+
     /// Releases the framebuffers from the given process.
     #[inline]
     pub fn release(&self, id: ProcessId) -> bool {
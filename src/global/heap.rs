@@ -0,0 +1,292 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use x86_64::{invlpg, page_align_up, PageTableEntry, PhysAddr, VirtAddr};
+
+use super::{GlobalToken, OutOfMemory};
+use crate::cpu::paging::{
+    AddressSpace, AddressSpaceContext, FOUR_KIB, HHDM_OFFSET, KERNEL_BIT, KERNEL_HEAP_SIZE,
+    KERNEL_HEAP_START,
+};
+use crate::sync::Mutex;
+
+/// The minimum alignment (and size granularity) of the blocks handed out by the kernel heap.
+const MIN_ALIGN: usize = 16;
+
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(size_of::<FreeChunk>() <= MIN_ALIGN);
+
+/// The allocator used by the `alloc` crate within the kernel.
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+/// The header written at the beginning of every free chunk of the kernel heap.
+struct FreeChunk {
+    /// The size of the chunk, in bytes.
+    size: usize,
+    /// The next free chunk, which is always located at a higher address.
+    next: Option<NonNull<FreeChunk>>,
+}
+
+/// The state of the kernel heap.
+struct HeapState {
+    /// The free chunk with the lowest address.
+    first: Option<NonNull<FreeChunk>>,
+    /// The end of the part of the heap region that's currently mapped.
+    top: VirtAddr,
+}
+
+unsafe impl Send for HeapState {}
+
+/// A first-fit allocator managing the kernel heap region.
+///
+/// The heap starts empty and grows on demand by mapping pages taken from the global
+/// [`MemoryAllocator`](super::MemoryAllocator) right after the part of the region that's already
+/// in use. Those pages are never given back to the system.
+///
+/// # Remarks
+///
+/// The heap cannot grow before the global state of the kernel has been initialized. Before that,
+/// every allocation fails.
+pub struct KernelHeap {
+    state: Mutex<HeapState>,
+}
+
+impl KernelHeap {
+    /// Creates a new empty [`KernelHeap`].
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(HeapState {
+                first: None,
+                top: KERNEL_HEAP_START,
+            }),
+        }
+    }
+}
+
+impl HeapState {
+    /// Attempts to allocate a block of `size` bytes aligned to `align` from the free chunks.
+    fn allocate(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        let mut link = &mut self.first;
+
+        while let Some(mut chunk) = *link {
+            let start = chunk.as_ptr() as usize;
+            let chunk_size = unsafe { chunk.as_ref().size };
+            let chunk_end = start + chunk_size;
+
+            let block = start.next_multiple_of(align);
+            let block_end = block + size;
+
+            if block_end > chunk_end {
+                link = unsafe { &mut chunk.as_mut().next };
+                continue;
+            }
+
+            let next = unsafe { chunk.as_ref().next };
+
+            // Whatever remains after the block becomes a new free chunk.
+            let next = if block_end < chunk_end {
+                Some(unsafe { write_chunk(block_end, chunk_end - block_end, next) })
+            } else {
+                next
+            };
+
+            if block > start {
+                // The padding before the block remains free.
+                unsafe { chunk.as_mut().size = block - start };
+                unsafe { chunk.as_mut().next = next };
+            } else {
+                *link = next;
+            }
+
+            return Some(unsafe { NonNull::new_unchecked(block as *mut u8) });
+        }
+
+        None
+    }
+
+    /// Gives the block at `addr` of `size` bytes back to the free chunks, merging it with its
+    /// neighbours when possible.
+    ///
+    /// # Safety
+    ///
+    /// The block must not overlap with any free chunk.
+    unsafe fn deallocate(&mut self, addr: VirtAddr, size: usize) {
+        let mut prev: Option<NonNull<FreeChunk>> = None;
+        let mut next = self.first;
+
+        while let Some(chunk) = next {
+            if chunk.as_ptr() as usize > addr {
+                break;
+            }
+
+            prev = Some(chunk);
+            next = unsafe { chunk.as_ref().next };
+        }
+
+        let mut start = addr;
+        let mut end = addr + size;
+
+        // Merge with the following chunk.
+        if let Some(chunk) = next {
+            if chunk.as_ptr() as usize == end {
+                end += unsafe { chunk.as_ref().size };
+                next = unsafe { chunk.as_ref().next };
+            }
+        }
+
+        // Merge with the preceding chunk.
+        match prev {
+            Some(mut chunk)
+                if chunk.as_ptr() as usize + unsafe { chunk.as_ref().size } == start =>
+            {
+                start = chunk.as_ptr() as usize;
+                unsafe {
+                    chunk.as_mut().size = end - start;
+                    chunk.as_mut().next = next;
+                }
+            }
+            Some(mut chunk) => unsafe {
+                chunk.as_mut().next = Some(write_chunk(start, end - start, next));
+            },
+            None => self.first = Some(unsafe { write_chunk(start, end - start, next) }),
+        }
+    }
+
+    /// Maps at least `size` more bytes at the end of the heap and makes them available for
+    /// allocation.
+    fn grow(&mut self, size: usize) -> Result<(), OutOfMemory> {
+        if !GlobalToken::is_initialized() {
+            return Err(OutOfMemory);
+        }
+
+        let glob = GlobalToken::get();
+        let size = page_align_up(size);
+
+        if self.top - KERNEL_HEAP_START + size > KERNEL_HEAP_SIZE {
+            return Err(OutOfMemory);
+        }
+
+        // The part of the kernel's address space that contains the heap is shared with every
+        // process, meaning that mapping the new pages in the kernel's address space is enough
+        // to make them visible everywhere.
+        let mut address_space =
+            unsafe { AddressSpace::from_l4_table(HeapContext(glob), glob.address_space) };
+        let mut mapped_end = self.top;
+        let result = address_space.allocate_range(
+            self.top,
+            size,
+            PageTableEntry::WRITABLE
+                | PageTableEntry::GLOBAL
+                | PageTableEntry::NO_EXECUTE
+                | KERNEL_BIT,
+            |virt, _| mapped_end = virt + FOUR_KIB,
+        );
+
+        if result.is_err() {
+            // Give back the pages that were mapped before the failure. The page tables that
+            // were created for them are kept, as they will be needed next time anyway.
+            let _ret = address_space.unmap_range(self.top, mapped_end - self.top);
+            debug_assert!(_ret.is_ok());
+            address_space.leak();
+
+            let mut page = self.top;
+            while page < mapped_end {
+                invlpg(page);
+                page += FOUR_KIB;
+            }
+
+            return Err(OutOfMemory);
+        }
+
+        address_space.leak();
+
+        unsafe { self.deallocate(self.top, size) };
+        self.top += size;
+
+        Ok(())
+    }
+}
+
+/// Writes a free chunk header at `addr`, returning a pointer to it.
+///
+/// # Safety
+///
+/// The memory at `addr` must be owned by the heap and unused.
+unsafe fn write_chunk(
+    addr: VirtAddr,
+    size: usize,
+    next: Option<NonNull<FreeChunk>>,
+) -> NonNull<FreeChunk> {
+    debug_assert!(addr % MIN_ALIGN == 0 && size % MIN_ALIGN == 0 && size != 0);
+
+    unsafe {
+        let chunk = addr as *mut FreeChunk;
+        chunk.write(FreeChunk { size, next });
+        NonNull::new_unchecked(chunk)
+    }
+}
+
+/// Rounds the provided layout to the granularity of the kernel heap.
+#[inline]
+fn normalize(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(MIN_ALIGN);
+    let size = layout.size().max(1).next_multiple_of(MIN_ALIGN);
+    (size, align)
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = normalize(layout);
+        let mut state = self.state.lock();
+
+        if let Some(block) = state.allocate(size, align) {
+            return block.as_ptr();
+        }
+
+        // Make sure that the new memory is large enough even if the block needs to be aligned.
+        if state.grow(size + align).is_err() {
+            return core::ptr::null_mut();
+        }
+
+        state
+            .allocate(size, align)
+            .map_or(core::ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = normalize(layout);
+        unsafe { self.state.lock().deallocate(ptr as VirtAddr, size) };
+    }
+}
+
+/// The [`AddressSpaceContext`] used to map the pages of the kernel heap.
+struct HeapContext(GlobalToken);
+
+unsafe impl AddressSpaceContext for HeapContext {
+    #[inline]
+    fn allocate_page(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        self.0.allocator.lock().allocate()
+    }
+
+    #[inline]
+    unsafe fn deallocate_page(&mut self, addr: PhysAddr) {
+        unsafe { self.0.allocator.lock().deallocate(addr) }
+    }
+
+    #[inline]
+    fn allocate_page_table(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        self.0.allocator.lock().allocate_page_table()
+    }
+
+    #[inline]
+    unsafe fn deallocate_page_table(&mut self, addr: PhysAddr) {
+        unsafe { self.0.allocator.lock().deallocate_page_table(addr) }
+    }
+
+    #[inline]
+    unsafe fn physical_to_virtual(&self, addr: PhysAddr) -> VirtAddr {
+        addr as usize + HHDM_OFFSET
+    }
+}
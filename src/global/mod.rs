@@ -9,12 +9,18 @@ pub use self::processes::*;
 mod framebuffer;
 pub use self::framebuffer::*;
 
+mod shared_memory;
+pub use self::shared_memory::*;
+
+mod heap;
+
 use core::ops::Deref;
 use core::sync::atomic::AtomicU64;
 
 use ruel_sys::PciDevice;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::io::DeviceMemory;
 use crate::sync::{Mutex, OnceLock};
 
 /// Stores the global state of the kernel.
@@ -33,11 +39,18 @@ pub struct Global {
     /// The framebuffers available to the kernel.
     pub framebuffers: Framebuffers,
 
+    /// The shared memory objects that processes can map.
+    pub shared_memory: SharedMemory,
+
     /// Stores the total number of ticks since the kernel was started.
     pub upticks: AtomicU64,
 
     /// The list of PCI devices that have been found on the machine.
     pub pci_devices: &'static [PciDevice],
+
+    /// The ranges of physical memory that belong to devices, and that processes can map with
+    /// the right capability.
+    pub device_memory: &'static [DeviceMemory],
 }
 
 /// The global state of the kernel.
@@ -111,3 +124,13 @@ impl Deref for GlobalToken {
 /// Do not access mutably after initialization; do not access before initialization. Initialization
 /// can be checked through the [`GlobalToken`] type.
 pub static mut KERNEL_STACK_TOP: VirtAddr = 0;
+
+/// A scratch slot in which the system call handler stashes the stack pointer of the caller
+/// while it switches to the kernel stack.
+///
+/// This allows every general-purpose register of the caller to be saved unmodified.
+///
+/// # Safety
+///
+/// This is only accessed by the system call handler, while interrupts are disabled.
+pub static mut SYSCALL_USER_RSP: VirtAddr = 0;
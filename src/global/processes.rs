@@ -1,9 +1,11 @@
 use core::cell::Cell;
 
-use ruel_sys::ProcessId;
+use ruel_sys::{Capabilities, ProcessId, ThreadId};
+use x86_64::{cli, sti_hlt, write_cr3};
 
-use super::OutOfMemory;
-use crate::process::Process;
+use super::{GlobalToken, MemoryAllocator, OutOfMemory};
+use crate::cpu::trap::TrapFrame;
+use crate::process::{Process, Registers, Thread};
 use crate::sync::{CpuLocal, Mutex, MutexGuard};
 use crate::utility::{BumpAllocator, StableFixedVec};
 
@@ -11,55 +13,271 @@ use crate::utility::{BumpAllocator, StableFixedVec};
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ProcessNotFound;
 
+/// An error that's returned by [`Processes::despawn_process_as`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DespawnError {
+    /// The process to despawn could not be found.
+    ProcessNotFound,
+    /// The process requesting the despawn is not allowed to terminate the other one.
+    MissingCapability,
+}
+
 /// An error that's returned when too many processes are running on the system and one cannot
 /// be spawned.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TooManyProcesses;
 
+/// An error that's returned when a thread could not be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadNotFound;
+
+/// An error that's returned when too many threads are running on the system and one cannot
+/// be spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyThreads;
+
+/// The processes and threads running on the system.
+struct State {
+    /// The processes that are currently running on the system.
+    processes: StableFixedVec<Process>,
+    /// The threads that are currently running on the system.
+    threads: StableFixedVec<Thread>,
+}
+
 /// The collection of all processes in the system.
 pub struct Processes {
-    /// The processes that are currently running on the system.
-    list: Mutex<StableFixedVec<Process>>,
-    /// The index of the current process running on each CPU.
+    /// The processes and threads that are currently running on the system.
+    state: Mutex<State>,
+    /// The index of the current thread running on each CPU.
+    ///
+    /// The special value `ThreadId::MAX` means that no thread is currently running on the CPU.
+    current_thread: CpuLocal<Cell<ThreadId>>,
+    /// The index of the process whose address space is currently loaded on each CPU.
     ///
     /// The special value `ProcessId::MAX` means that no process is currently running on the CPU.
     current_process: CpuLocal<Cell<ProcessId>>,
+    /// The number of pages committed by the page fault handler in the address space that's
+    /// currently loaded on each CPU, and that have not been added to
+    /// [`Process::resident_pages`] yet.
+    ///
+    /// The page fault handler cannot lock the processes, as the lock might already be held by
+    /// the code that caused the fault.
+    committed_pages: CpuLocal<Cell<usize>>,
 }
 
 impl Processes {
     /// Creates a new empty [`Processes`] collection.
     pub fn new(boostrap_allocator: &mut BumpAllocator) -> Result<Self, OutOfMemory> {
         Ok(Self {
-            list: Mutex::new(StableFixedVec::new(boostrap_allocator, 1024)?),
+            state: Mutex::new(State {
+                processes: StableFixedVec::new(boostrap_allocator, 1024)?,
+                threads: StableFixedVec::new(boostrap_allocator, 4096)?,
+            }),
+            current_thread: CpuLocal::new(boostrap_allocator)?,
             current_process: CpuLocal::new(boostrap_allocator)?,
+            committed_pages: CpuLocal::new(boostrap_allocator)?,
         })
     }
 
-    /// Schedules the given process to run on the current CPU.
+    /// Schedules the given thread to run on the current CPU.
+    pub fn schedule(&self, id: ThreadId) -> Result<(), ThreadNotFound> {
+        let state = self.state.lock();
+        let thread = state.threads.get(id).ok_or(ThreadNotFound)?;
+        self.current_thread.set(id);
+        self.current_process.set(thread.process);
+        Ok(())
+    }
+
+    /// Returns whether a process with the given ID currently exists on the system.
+    #[inline]
+    pub fn exists(&self, id: ProcessId) -> bool {
+        self.state.lock().processes.is_present(id)
+    }
+
+    /// Attempts to spawn a process on the system.
     ///
-    /// # Safety
+    /// The main thread of the process starts with the provided registers.
     ///
-    /// The provided process ID must be valid.
-    #[inline]
-    pub fn schedule_unchecked(&self, id: ProcessId) {
-        self.current_process.set(id);
+    /// # Returns
+    ///
+    /// The ID of the new process, along with the ID of its main thread.
+    pub fn spawn_process(
+        &self,
+        process: Process,
+        registers: Registers,
+    ) -> Result<(ProcessId, ThreadId), TooManyProcesses> {
+        let mut state = self.state.lock();
+        let id = state
+            .processes
+            .push(process)
+            .map_err(|_| TooManyProcesses)?;
+
+        match state.threads.push(Thread::new(id, registers)) {
+            Ok(thread) => {
+                unsafe { state.processes.get_unchecked_mut(id).thread_count = 1 };
+                Ok((id, thread))
+            }
+            Err(_) => {
+                state.processes.remove(id);
+                Err(TooManyProcesses)
+            }
+        }
     }
 
-    /// Schedules the given process to run on the current CPU.
-    pub fn schedule(&self, id: ProcessId) -> Result<(), ProcessNotFound> {
-        let list = self.list.lock();
-        if list.is_present(id) {
-            self.schedule_unchecked(id);
-            Ok(())
-        } else {
-            Err(ProcessNotFound)
+    /// Attempts to spawn a new thread within the provided process.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the process does not exist.
+    pub fn spawn_thread(
+        &self,
+        process: ProcessId,
+        registers: Registers,
+    ) -> Result<ThreadId, TooManyThreads> {
+        let mut state = self.state.lock();
+        let id = state
+            .threads
+            .push(Thread::new(process, registers))
+            .map_err(|_| TooManyThreads)?;
+
+        state
+            .processes
+            .get_mut(process)
+            .expect("attempted to spawn a thread in a process that does not exist")
+            .thread_count += 1;
+
+        Ok(id)
+    }
+
+    /// Removes the process with the given ID from the system, returning it.
+    ///
+    /// All the threads of the process are removed as well. The threads waiting for this process
+    /// to terminate are woken up and receive the provided `exit_code`. If none of them belongs
+    /// to the parent of the process, the exit code is recorded in the parent so that it can
+    /// still be collected later.
+    ///
+    /// If the process was running on the current CPU, no process is considered running on it
+    /// anymore. In that case, the caller must make sure that the address space of the process
+    /// is no longer in use before dropping it.
+    pub fn despawn_process(
+        &self,
+        id: ProcessId,
+        exit_code: usize,
+    ) -> Result<Process, ProcessNotFound> {
+        self.remove_process(&mut self.state.lock(), id, exit_code)
+    }
+
+    /// Removes the process with the given ID from the system on behalf of the process `despawner`,
+    /// returning it.
+    ///
+    /// A process may always despawn itself and its descendants. Other processes may only be
+    /// despawned by processes that hold [`Capabilities::DESPAWN_PROCESSES`].
+    ///
+    /// See [`despawn_process`](Processes::despawn_process) for more information.
+    pub fn despawn_process_as(
+        &self,
+        despawner: ProcessId,
+        id: ProcessId,
+        exit_code: usize,
+    ) -> Result<Process, DespawnError> {
+        let mut state = self.state.lock();
+
+        if !state.processes.is_present(id) {
+            return Err(DespawnError::ProcessNotFound);
         }
+
+        let allowed = state.processes.get(despawner).is_some_and(|process| {
+            process
+                .capabilities
+                .contains(Capabilities::DESPAWN_PROCESSES)
+        }) || is_ancestor_or_self(&state.processes, despawner, id);
+
+        if !allowed {
+            return Err(DespawnError::MissingCapability);
+        }
+
+        self.remove_process(&mut state, id, exit_code)
+            .map_err(|ProcessNotFound| DespawnError::ProcessNotFound)
     }
 
-    /// Attempts to spawn a process on the system.
-    pub fn spawn_process(&self, process: Process) -> Result<ProcessId, TooManyProcesses> {
-        let mut list = self.list.lock();
-        list.push(process).map_err(|_| TooManyProcesses)
+    /// The implementation of [`despawn_process`](Processes::despawn_process), for when the
+    /// state is already locked.
+    fn remove_process(
+        &self,
+        state: &mut State,
+        id: ProcessId,
+        exit_code: usize,
+    ) -> Result<Process, ProcessNotFound> {
+        let State { processes, threads } = state;
+        let mut process = processes.remove(id).ok_or(ProcessNotFound)?;
+
+        threads.retain(|thread| thread.process != id);
+
+        let mut collected = false;
+        for other in threads.iter_mut() {
+            if other.notify_process_exit(id, exit_code) && other.process == process.parent {
+                collected = true;
+            }
+        }
+
+        if !collected {
+            if let Some(parent) = processes.get_mut(process.parent) {
+                parent.record_exit(id, exit_code);
+            }
+        }
+
+        // The children of the process are not waited on by anyone anymore.
+        for child in processes.iter_mut() {
+            if child.parent == id {
+                child.parent = ProcessId::MAX;
+            }
+        }
+
+        if self.current_process.get() == id {
+            self.current_thread.set(ThreadId::MAX);
+            self.current_process.set(ProcessId::MAX);
+            process.resident_pages += self.committed_pages.take();
+        }
+
+        Ok(process)
+    }
+
+    /// Removes the thread with the given ID from the provided process.
+    ///
+    /// If the thread was running on the current CPU, no thread is considered running on it
+    /// anymore.
+    ///
+    /// # Returns
+    ///
+    /// If the thread was the last one of its process, the process is despawned as well (as if it
+    /// had exited with an exit code of `0`) and returned. The same remarks as for
+    /// [`despawn_process`](Self::despawn_process) apply.
+    pub fn despawn_thread(
+        &self,
+        process: ProcessId,
+        id: ThreadId,
+    ) -> Result<Option<Process>, ThreadNotFound> {
+        let mut state = self.state.lock();
+
+        if !state.threads.get(id).is_some_and(|t| t.process == process) {
+            return Err(ThreadNotFound);
+        }
+
+        state.threads.remove(id);
+
+        if self.current_thread.get() == id {
+            self.current_thread.set(ThreadId::MAX);
+        }
+
+        let owner = unsafe { state.processes.get_unchecked_mut(process) };
+        owner.thread_count -= 1;
+
+        if owner.thread_count == 0 {
+            drop(state);
+            Ok(self.despawn_process(process, 0).ok())
+        } else {
+            Ok(None)
+        }
     }
 
     /// Returns the process ID of the process currently running on the CPU.
@@ -79,6 +297,33 @@ impl Processes {
         id
     }
 
+    /// Returns the ID of the thread currently running on the CPU.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if no thread is running on the current CPU.
+    #[inline]
+    pub fn current_thread_id(&self) -> ThreadId {
+        let id = self.current_thread.get();
+
+        assert!(
+            id != ThreadId::MAX,
+            "Attempted to access the current thread while no thread is running on the CPU"
+        );
+
+        id
+    }
+
+    /// Records that the page fault handler committed `count` pages in the address space that's
+    /// currently loaded.
+    ///
+    /// They are added to the [`Process::resident_pages`] of the current process the next time
+    /// it is accessed.
+    #[inline]
+    pub fn record_committed_pages(&self, count: usize) {
+        self.committed_pages.set(self.committed_pages.get() + count);
+    }
+
     /// Returns the current process.
     ///
     /// # Panics
@@ -86,31 +331,185 @@ impl Processes {
     /// This function panics if no process is running on the current CPU.
     pub fn current(&self) -> MutexGuard<Process> {
         let id = self.current_id();
-        MutexGuard::map(self.list.lock(), move |list| {
-            debug_assert!(list.is_present(id));
-            unsafe { list.get_unchecked_mut(id) }
+        MutexGuard::map(self.state.lock(), move |state| {
+            debug_assert!(state.processes.is_present(id));
+            let process = unsafe { state.processes.get_unchecked_mut(id) };
+            process.resident_pages += self.committed_pages.take();
+            process
         })
     }
 
     /// Attempts to get the process with the given ID.
-    ///
-    /// The special value `ProcessId::MAX` is used to refer to the current process.
-    pub fn get(&self, id: ProcessId) -> Option<MutexGuard<Process>> {
-        MutexGuard::try_map(self.list.lock(), |list| {
-            if id == ProcessId::MAX {
-                let id = self.current_id();
-                debug_assert!(list.is_present(id));
-                Ok(unsafe { list.get_unchecked_mut(id) })
-            } else {
-                list.get_mut(id).ok_or(())
-            }
+    pub fn get(&self, id: ProcessId) -> Result<MutexGuard<Process>, ProcessNotFound> {
+        MutexGuard::try_map(self.state.lock(), |state| {
+            state.processes.get_mut(id).ok_or(ProcessNotFound)
         })
-        .ok()
+    }
+
+    /// Calls the provided closure with the thread with the given ID.
+    pub fn with_thread<R>(
+        &self,
+        id: ThreadId,
+        f: impl FnOnce(&mut Thread) -> R,
+    ) -> Result<R, ThreadNotFound> {
+        self.state
+            .lock()
+            .threads
+            .get_mut(id)
+            .map(f)
+            .ok_or(ThreadNotFound)
+    }
+
+    /// Calls the provided closure with the current process and the current thread.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if no thread is running on the current CPU.
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut Process, &mut Thread) -> R) -> R {
+        let process = self.current_id();
+        let thread = self.current_thread_id();
+
+        let mut state = self.state.lock();
+        let State { processes, threads } = &mut *state;
+
+        debug_assert!(processes.is_present(process));
+        debug_assert!(threads.is_present(thread));
+
+        let process = unsafe { processes.get_unchecked_mut(process) };
+        process.resident_pages += self.committed_pages.take();
+
+        f(process, unsafe { threads.get_unchecked_mut(thread) })
     }
 
     /// Calls the provided closure with a reference to each process.
     #[inline]
     pub fn for_each_mut(&self, f: impl FnMut(&mut Process)) {
-        self.list.lock().iter_mut().for_each(f)
+        self.state.lock().processes.iter_mut().for_each(f)
+    }
+
+    /// Calls the provided closure with a reference to each thread of the given process.
+    pub fn for_each_thread_mut(&self, process: ProcessId, f: impl FnMut(&mut Thread)) {
+        self.state
+            .lock()
+            .threads
+            .iter_mut()
+            .filter(|thread| thread.process == process)
+            .for_each(f)
+    }
+
+    /// Ticks every thread of the system once, waking up the ones whose conditions are met.
+    ///
+    /// The local I/O states of the processes are cleared afterwards.
+    pub fn tick(&self) {
+        let mut state = self.state.lock();
+        let State { processes, threads } = &mut *state;
+
+        for thread in threads.iter_mut() {
+            let process = unsafe { processes.get_unchecked_mut(thread.process) };
+            thread.tick(&process.io_states);
+        }
+
+        for process in processes.iter_mut() {
+            process.io_states.clear();
+        }
+    }
+
+    /// Ticks every thread of the provided process once, waking up the ones whose conditions
+    /// are met.
+    ///
+    /// Like [`tick`](Self::tick), the local I/O state of the process is cleared afterwards. All
+    /// the threads of the process must be ticked before that happens, otherwise the events would
+    /// be lost for the threads that were not ticked.
+    pub fn tick_process(&self, id: ProcessId) {
+        let mut state = self.state.lock();
+        let State { processes, threads } = &mut *state;
+
+        let Some(process) = processes.get_mut(id) else {
+            return;
+        };
+
+        for thread in threads.iter_mut() {
+            if thread.process == id {
+                thread.tick(&process.io_states);
+            }
+        }
+
+        process.io_states.clear();
     }
+
+    /// Saves the state of the current thread and loads the state of the thread that should
+    /// run next into `frame`.
+    ///
+    /// When `preempt` is `false`, the current thread keeps running as long as it is not
+    /// sleeping. Otherwise, the next runnable thread is selected in a round-robin fashion
+    /// (which might be the current thread itself if no other thread can run).
+    ///
+    /// If no thread is able to run, the CPU is halted until one of them is woken up.
+    ///
+    /// # Remarks
+    ///
+    /// `frame` must have been pushed while the CPU was running the current thread in userspace.
+    pub fn reschedule(&self, frame: &mut TrapFrame, preempt: bool) {
+        let current = self.current_thread.get();
+        let mut state = self.state.lock();
+
+        if let Some(thread) = state.threads.get_mut(current) {
+            if !preempt && thread.is_runnable() {
+                thread.write_back_wake_ups(frame);
+                return;
+            }
+
+            thread.registers.save(frame);
+        }
+
+        loop {
+            if let Some(next) = state.threads.find_next(current, Thread::is_runnable) {
+                let State { processes, threads } = &mut *state;
+                let thread = unsafe { threads.get_unchecked_mut(next) };
+                thread.registers.restore(frame);
+
+                // Threads of the same process share the same address space.
+                if thread.process != self.current_process.get() {
+                    let committed = self.committed_pages.take();
+                    if let Some(previous) = processes.get_mut(self.current_process.get()) {
+                        previous.resident_pages += committed;
+                    }
+
+                    let process = unsafe { processes.get_unchecked_mut(thread.process) };
+                    unsafe { write_cr3(process.address_space.l4_table()) };
+                }
+
+                thread.write_back_wake_ups(frame);
+
+                self.current_thread.set(next);
+                self.current_process.set(thread.process);
+                return;
+            }
+
+            // No thread can run right now. Make use of that time to prepare zeroed pages, then
+            // wait for an interrupt to wake one of them up.
+            drop(state);
+            MemoryAllocator::refill_zeroed_pool(&GlobalToken::get().allocator);
+            sti_hlt();
+            cli();
+            state = self.state.lock();
+        }
+    }
+}
+
+/// Returns whether `ancestor` is `id` itself, or one of the processes that (transitively)
+/// spawned it.
+fn is_ancestor_or_self(
+    processes: &StableFixedVec<Process>,
+    ancestor: ProcessId,
+    mut id: ProcessId,
+) -> bool {
+    while id != ancestor {
+        match processes.get(id) {
+            Some(process) => id = process.parent,
+            None => return false,
+        }
+    }
+
+    true
 }
@@ -0,0 +1,195 @@
+//! Shared memory objects, which can be mapped by several processes at once.
+//!
+//! Objects are identified by a global [`SharedMemoryId`], but a process may only map the objects
+//! it has been granted (see [`Process::shared_memory_grants`]).
+//!
+//! [`Process::shared_memory_grants`]: crate::process::Process::shared_memory_grants
+
+use alloc::vec::Vec;
+
+use ruel_sys::SharedMemoryId;
+use x86_64::PhysAddr;
+
+use super::{MemoryAllocator, OutOfMemory};
+use crate::sync::Mutex;
+
+/// An error that's returned when a shared memory object does not exist.
+#[derive(Debug, Clone, Copy)]
+pub struct SharedMemoryNotFound;
+
+/// A shared memory object.
+struct SharedObject {
+    /// The ID of the object.
+    id: SharedMemoryId,
+    /// The physical pages making up the object, in order.
+    pages: Vec<PhysAddr>,
+    /// The number of times the object is currently mapped.
+    mappings: usize,
+}
+
+/// The state of the [`SharedMemory`] objects.
+struct SharedMemoryState {
+    /// The objects that currently exist, sorted by ID.
+    ///
+    /// IDs are allocated in increasing order, meaning that new objects are always pushed at the
+    /// end of the list.
+    objects: Vec<SharedObject>,
+    /// The ID of the next object to be created.
+    ///
+    /// IDs are never reused, such that a stale ID cannot refer to an unrelated object.
+    next_id: SharedMemoryId,
+}
+
+impl SharedMemoryState {
+    /// Returns the object with the provided ID, if it exists.
+    fn get_mut(&mut self, id: SharedMemoryId) -> Option<&mut SharedObject> {
+        let index = self.objects.binary_search_by_key(&id, |o| o.id).ok()?;
+        Some(&mut self.objects[index])
+    }
+}
+
+/// Manages the shared memory objects that processes can map in their address space.
+///
+/// The pages of an object are shared by all its mappings. They are released once the last of
+/// those mappings is removed.
+pub struct SharedMemory {
+    state: Mutex<SharedMemoryState>,
+}
+
+impl SharedMemory {
+    /// Creates a new [`SharedMemory`] instance without any object.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(SharedMemoryState {
+                objects: Vec::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Creates a new shared memory object made of `page_count` zeroed pages.
+    ///
+    /// The object starts with a single mapping, meaning that the caller is expected to map it
+    /// using [`with_pages`] (or to call [`release`] if that fails).
+    ///
+    /// [`with_pages`]: SharedMemory::with_pages
+    /// [`release`]: SharedMemory::release
+    pub fn create(
+        &self,
+        allocator: &Mutex<MemoryAllocator>,
+        page_count: usize,
+    ) -> Result<SharedMemoryId, OutOfMemory> {
+        let mut pages = Vec::new();
+        pages
+            .try_reserve_exact(page_count)
+            .map_err(|_| OutOfMemory)?;
+
+        for _ in 0..page_count {
+            match MemoryAllocator::allocate_zeroed(allocator) {
+                Ok(page) => pages.push(page),
+                Err(OutOfMemory) => {
+                    release_pages(allocator, pages);
+                    return Err(OutOfMemory);
+                }
+            }
+        }
+
+        let mut state = self.state.lock();
+
+        if state.objects.try_reserve(1).is_err() {
+            drop(state);
+            release_pages(allocator, pages);
+            return Err(OutOfMemory);
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.objects.push(SharedObject {
+            id,
+            pages,
+            mappings: 1,
+        });
+
+        Ok(id)
+    }
+
+    /// Registers a new mapping of the object with the provided ID, and calls the provided
+    /// closure with its pages.
+    ///
+    /// The caller must call [`release`] once the mapping is removed.
+    ///
+    /// [`release`]: SharedMemory::release
+    pub fn acquire<R>(
+        &self,
+        id: SharedMemoryId,
+        f: impl FnOnce(&[PhysAddr]) -> R,
+    ) -> Result<R, SharedMemoryNotFound> {
+        let mut state = self.state.lock();
+        let object = state.get_mut(id).ok_or(SharedMemoryNotFound)?;
+        object.mappings += 1;
+        Ok(f(&object.pages))
+    }
+
+    /// Calls the provided closure with the pages of the object with the provided ID, without
+    /// registering a new mapping.
+    ///
+    /// This is used to map an object that was just created.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the object does not exist.
+    pub fn with_pages<R>(&self, id: SharedMemoryId, f: impl FnOnce(&[PhysAddr]) -> R) -> R {
+        let mut state = self.state.lock();
+        let object = state
+            .get_mut(id)
+            .expect("mapped an unknown shared memory object");
+        f(&object.pages)
+    }
+
+    /// Registers a new mapping of the object with the provided ID, without returning its pages.
+    ///
+    /// This is used when an existing mapping of the object is duplicated.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the object does not exist.
+    pub fn retain(&self, id: SharedMemoryId) {
+        let mut state = self.state.lock();
+        let object = state
+            .get_mut(id)
+            .expect("retained an unknown shared memory object");
+        object.mappings += 1;
+    }
+
+    /// Unregisters a mapping of the object with the provided ID.
+    ///
+    /// If that was the last mapping of the object, it is destroyed and its pages are given back
+    /// to the provided allocator.
+    pub fn release(&self, allocator: &Mutex<MemoryAllocator>, id: SharedMemoryId) {
+        let mut state = self.state.lock();
+
+        let index = state
+            .objects
+            .binary_search_by_key(&id, |o| o.id)
+            .expect("released an unknown shared memory object");
+
+        let object = &mut state.objects[index];
+        object.mappings -= 1;
+        if object.mappings != 0 {
+            return;
+        }
+
+        let object = state.objects.remove(index);
+        drop(state);
+
+        release_pages(allocator, object.pages);
+    }
+}
+
+/// Gives the provided pages back to the allocator.
+fn release_pages(allocator: &Mutex<MemoryAllocator>, pages: Vec<PhysAddr>) {
+    let mut allocator = allocator.lock();
+    for page in pages {
+        unsafe { allocator.deallocate(page) };
+    }
+}
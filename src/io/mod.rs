@@ -1,4 +1,78 @@
 //! Provides ways to access input/output devices available on the system.
 
+use ruel_sys::PciDevice;
+use x86_64::PhysAddr;
+
+use crate::cpu::paging::FOUR_KIB;
+use crate::global::OutOfMemory;
+use crate::utility::BumpAllocator;
+
 pub mod pci;
 pub mod ps2;
+
+/// A range of physical memory that belongs to a device rather than to RAM.
+///
+/// The bounds of the range are always aligned to the page size.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceMemory {
+    /// The physical address of the first byte of the range.
+    pub base: PhysAddr,
+    /// The size of the range, in bytes.
+    pub length: u64,
+}
+
+impl DeviceMemory {
+    /// Creates a new [`DeviceMemory`] instance covering at least the provided range.
+    ///
+    /// The range is extended to the pages that contain it.
+    pub fn covering(base: PhysAddr, length: u64) -> Self {
+        let page = FOUR_KIB as u64;
+        let start = base / page * page;
+        let end = (base + length).div_ceil(page) * page;
+
+        Self {
+            base: start,
+            length: end - start,
+        }
+    }
+
+    /// Returns whether the provided range is entirely part of this one.
+    #[inline]
+    pub fn contains(&self, base: PhysAddr, length: u64) -> bool {
+        base >= self.base
+            && base
+                .checked_add(length)
+                .is_some_and(|end| end <= self.base + self.length)
+    }
+}
+
+/// Collects the ranges of physical memory that belong to devices.
+///
+/// This includes the memory described by the base address registers of the provided PCI devices,
+/// as well as the provided `reserved` ranges, which the firmware reported as not being RAM.
+pub fn collect_device_memory(
+    bootstrap_allocator: &mut BumpAllocator,
+    pci_devices: &[PciDevice],
+    reserved: &[DeviceMemory],
+) -> Result<&'static [DeviceMemory], OutOfMemory> {
+    // A device has at most six base address registers.
+    let capacity = pci_devices.len() * 6 + reserved.len();
+    let slots = bootstrap_allocator.allocate_slice::<DeviceMemory>(capacity)?;
+    let mut count = 0;
+
+    let mut push = |range: DeviceMemory| {
+        slots[count].write(range);
+        count += 1;
+    };
+
+    for device in pci_devices {
+        pci::for_each_memory_bar(device, |base, length| {
+            push(DeviceMemory::covering(base, length));
+        });
+    }
+
+    reserved.iter().copied().for_each(push);
+
+    // Only the first `count` slots have been initialized.
+    Ok(unsafe { core::slice::from_raw_parts(slots.as_ptr() as *const DeviceMemory, count) })
+}
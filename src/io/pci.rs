@@ -3,7 +3,7 @@
 use core::mem::{transmute, MaybeUninit};
 
 use ruel_sys::PciDevice;
-use x86_64::{inl, outl};
+use x86_64::{inl, outl, PhysAddr};
 
 use crate::global::OutOfMemory;
 use crate::log;
@@ -35,6 +35,15 @@ fn config_read_u32(address: u32) -> u32 {
     }
 }
 
+/// Writes a word to the configuration space of the PCI device at the provided coordinates
+/// (bus, device, function, offset).
+fn config_write_u32(address: u32, value: u32) {
+    unsafe {
+        outl(0xCF8, address);
+        outl(0xCFC, value.to_le());
+    }
+}
+
 /// Returns whether the PCI device at the provided coordinates is present.
 fn is_present(bus: u32, device: u32, func: u32) -> bool {
     let off0 = config_read_u32(pci_address(bus, device, func));
@@ -148,6 +157,72 @@ impl CommonHeader {
     }
 }
 
+/// Calls the provided function with the memory regions described by the base address registers
+/// of the provided PCI device.
+///
+/// The parameters of the function are the physical address of the region and its size, in
+/// bytes. I/O space registers, as well as registers that are not implemented, are ignored.
+pub fn for_each_memory_bar(device: &PciDevice, mut f: impl FnMut(PhysAddr, u64)) {
+    let address = device.address;
+
+    // Only regular devices and PCI-to-PCI bridges have base address registers, at offset 0x10.
+    let bar_count = match CommonHeader::read(address).header_type & 0x7F {
+        0x00 => 6,
+        0x01 => 2,
+        _ => return,
+    };
+
+    // The device must not decode memory accesses while the size of its registers is probed.
+    // The upper half of that word is the status register, whose bits are cleared by writing
+    // ones to them.
+    let command = config_read_u32(address + 4) & 0xFFFF;
+    config_write_u32(address + 4, command & !0b11);
+
+    // Writing all ones to a register and reading it back gives a mask of the address bits that
+    // the device actually decodes, from which the size of the region can be deduced.
+    let probe = |offset: u32| {
+        let original = config_read_u32(offset);
+        config_write_u32(offset, 0xFFFF_FFFF);
+        let mask = config_read_u32(offset);
+        config_write_u32(offset, original);
+        (original, mask)
+    };
+
+    let mut index = 0;
+    while index < bar_count {
+        let offset = address + 0x10 + index * 4;
+        index += 1;
+
+        let (low, low_mask) = probe(offset);
+
+        // Bit 0 is set for registers that describe a range of I/O ports.
+        if low & 1 != 0 {
+            continue;
+        }
+
+        let mut base = (low & !0xF) as u64;
+        let mut mask = (low_mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+
+        // Bits 1-2 indicate that the register is 64-bit wide, and uses the next register as
+        // its upper half.
+        if (low >> 1) & 0b11 == 0b10 && index < bar_count {
+            let (high, high_mask) = probe(offset + 4);
+            index += 1;
+
+            base |= (high as u64) << 32;
+            mask = (mask & 0xFFFF_FFFF) | (high_mask as u64) << 32;
+        }
+
+        if low_mask & !0xF == 0 || base == 0 {
+            continue;
+        }
+
+        f(base, (!mask).wrapping_add(1));
+    }
+
+    config_write_u32(address + 4, command);
+}
+
 /// Calls the provided function for every detected PCI device.
 ///
 /// The parameter of the function is the device's address in the configuration
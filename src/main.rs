@@ -24,6 +24,8 @@
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 
+extern crate alloc;
+
 mod boot;
 mod cpu;
 mod global;
@@ -1,21 +1,40 @@
+//! Provides a way to load an ELF executable into a new process.
+
+use ruel_sys::MappingKind;
 use x86_64::{page_align_down, page_align_up, PageTableEntry, VirtAddr};
 
-use crate::boot::{handle_mapping_error, oom};
-use crate::cpu::paging::FOUR_KIB;
+use super::{protection_of, Backing, LoadError, Process, Region, Registers, USERLAND_MAPPABLE_END};
+use crate::cpu::paging::{MappingError, FOUR_KIB};
 use crate::global::GlobalToken;
-use crate::hcf::die;
 use crate::log;
-use crate::process::{Process, USERLAND_STOP};
+
+impl From<elf::Error> for LoadError {
+    #[inline]
+    fn from(_value: elf::Error) -> Self {
+        LoadError::Invalid("malformed ELF file")
+    }
+}
+
+impl From<MappingError> for LoadError {
+    #[inline]
+    fn from(value: MappingError) -> Self {
+        match value {
+            MappingError::OutOfMemory => LoadError::OutOfMemory,
+            MappingError::AlreadyMapped => LoadError::Invalid("overlapping segments"),
+        }
+    }
+}
 
 /// Loads an ELF process from the provided file.
-pub fn load(file: &[u8], cmdline: &[u8]) -> Process {
-    log::trace!("Loading the init process from and ELF file...");
+pub fn load(file: &[u8], cmdline: &[u8]) -> Result<(Process, Registers), LoadError> {
+    log::trace!("Loading a process from an ELF file...");
 
     let glob = GlobalToken::get();
-    let mut process = Process::empty(glob).unwrap_or_else(|_| oom());
+    let mut process = Process::empty(glob).map_err(|_| LoadError::OutOfMemory)?;
+    let mut registers = Registers::default();
 
     let elf_file = elf::Elf::new(file);
-    let hdr = elf_file.header().unwrap_or_else(|err| panic_parse(err));
+    let hdr = elf_file.header()?;
 
     // =============================================================================================
     // SANITY CHECKS
@@ -25,113 +44,77 @@ pub fn load(file: &[u8], cmdline: &[u8]) -> Process {
     assert_eq!(hdr.magic, [0x7f, 0x45, 0x4c, 0x46]);
 
     if hdr.class != elf::Class::ELF64 {
-        log::error!("The provided init process is not a 64-bit ELF file.");
-        die();
+        return Err(LoadError::Invalid("not a 64-bit ELF file"));
     }
 
     if hdr.data_encoding != elf::DataEncoding::NATIVE {
-        log::error!(
-            "\
-            The provided init process does not have the right endianess.\n\
-            Expected: {:?}; got: {:?}\
-            ",
-            elf::DataEncoding::NATIVE,
-            hdr.data_encoding
-        );
-        die();
+        return Err(LoadError::Invalid("wrong endianess"));
     }
 
     if hdr.elf_version != elf::Version::CURRENT {
-        log::error!(
-            "\
-            The provided init process does not have the right ELF version.\n\
-            Expected: {:?}; got: {:?}\
-            ",
-            elf::Version::CURRENT,
-            hdr.elf_version
-        );
-        die();
+        return Err(LoadError::Invalid("unsupported ELF version"));
     }
 
     if hdr.os_abi != elf::OsAbi::SYSV {
-        log::error!(
-            "\
-            The provided init process does not have the right OS ABI.\n\
-            Expected: {:?}; got: {:?}\
-            ",
-            elf::OsAbi::SYSV,
-            hdr.os_abi
-        );
-        die();
+        return Err(LoadError::Invalid("unsupported OS ABI"));
     }
 
     if hdr.ty == elf::Type::DYN {
-        log::error!(
-            "\
-            The provided init process is a relocatable file. You probably compiled your\n\
-            init process as a Position-Independent Executable (PIE), which is not supported\n\
-            by the kernel.\n\
-            \n\
-            Please compile the init process with a static relocation model.\
-            "
-        );
-        die();
+        return Err(LoadError::Invalid(
+            "relocatable files (PIE) are not supported, use a static relocation model",
+        ));
     }
 
     if hdr.ty != elf::Type::EXEC {
-        log::error!(
-            "\
-            The provided init process is not an executable.\n\
-            Expected: {:?}; got: {:?}\
-            ",
-            elf::Type::EXEC,
-            hdr.ty
-        );
-        die();
+        return Err(LoadError::Invalid("not an executable"));
     }
 
     if hdr.machine != elf::Machine::X86_64 {
-        log::error!(
-            "\
-            The provided init process is not an x86_64 executable.\n\
-            Expected: {:?}; got: {:?}\
-            ",
-            elf::Machine::X86_64,
-            hdr.machine
-        );
-        die();
+        return Err(LoadError::Invalid("not an x86_64 executable"));
     }
 
     if hdr.entry_point == 0 {
-        log::error!("The provided init process has no entry point specified.");
-        die();
+        return Err(LoadError::Invalid("no entry point specified"));
+    }
+
+    // Returning to a non-canonical or kernel address would fault in the kernel rather than in
+    // the process.
+    if hdr.entry_point >= USERLAND_MAPPABLE_END as u64 {
+        return Err(LoadError::Invalid("entry point is outside of userland"));
     }
 
-    process.registers.rip = hdr.entry_point as VirtAddr;
+    registers.rip = hdr.entry_point as VirtAddr;
 
     // =============================================================================================
     // LOAD SEGMENTS
     // =============================================================================================
 
     let mut stack_flags = None::<PageTableEntry>;
+    let mut entry_point_loaded = false;
 
-    for phdr in elf_file
-        .program_headers()
-        .unwrap_or_else(|err| panic_parse(err))
-    {
+    for phdr in elf_file.program_headers()? {
         match phdr.ty {
             elf::PhdrType::NULL | elf::PhdrType::PHDR => (),
             elf::PhdrType::GNU_STACK => {
                 if stack_flags.is_some() {
-                    custom_panic_parse("multiple GNU_STACK segments");
+                    return Err(LoadError::Invalid("multiple GNU_STACK segments"));
                 }
 
-                stack_flags = Some(phdr_to_page_flags(phdr.flags));
+                stack_flags = Some(phdr_to_page_flags(phdr.flags)?);
+            }
+            elf::PhdrType::LOAD => {
+                load_segment(phdr, file, &mut process)?;
+
+                if phdr.flags.intersects(elf::PhdrFlags::EXECUTABLE)
+                    && phdr.vaddr <= hdr.entry_point
+                    && hdr.entry_point - phdr.vaddr < phdr.memsz
+                {
+                    entry_point_loaded = true;
+                }
             }
-            elf::PhdrType::LOAD => load_segment(phdr, file, &mut process),
             unknown => {
                 log::warn!(
-                    "Found an unsupported segment type in the init process ELF file: {unknown:?}\n\
+                    "Found an unsupported segment type in an ELF file: {unknown:?}\n\
                     This segment will be ignored."
                 );
                 continue;
@@ -139,19 +122,19 @@ pub fn load(file: &[u8], cmdline: &[u8]) -> Process {
         }
     }
 
+    if !entry_point_loaded {
+        return Err(LoadError::Invalid(
+            "entry point is not part of an executable segment",
+        ));
+    }
+
     // =============================================================================================
     // ALLOCATE STACK
     // =============================================================================================
 
     // Make sure that the command line can be copied into the stack.
     if cmdline.len() + 1 >= 4096 {
-        log::error!(
-            "\
-            The provided command line is larger than 4096 bytes. Larger command-line are not\n\
-            supported by the kernel due to the laziness of the kernel developers.\
-            ",
-        );
-        die();
+        return Err(LoadError::CommandLineTooLarge);
     }
 
     let stack_flags = stack_flags.unwrap_or(
@@ -160,12 +143,17 @@ pub fn load(file: &[u8], cmdline: &[u8]) -> Process {
 
     // Allocate a stack for the process.
     const STACK_SIZE: usize = 8 * FOUR_KIB;
-    const STACK_POS: VirtAddr = 1 + USERLAND_STOP - STACK_SIZE - FOUR_KIB;
+    const STACK_POS: VirtAddr = USERLAND_MAPPABLE_END - STACK_SIZE;
     #[allow(clippy::assertions_on_constants)]
     const _: () = assert!(STACK_POS & 0xFFF == 0);
 
     let cmdline_start = STACK_POS + STACK_SIZE - cmdline.len() - 1;
 
+    process
+        .regions
+        .reserve(1)
+        .map_err(|_| LoadError::OutOfMemory)?;
+
     process
         .address_space
         .allocate_range(STACK_POS, STACK_SIZE, stack_flags, |virt, dst| {
@@ -180,36 +168,54 @@ pub fn load(file: &[u8], cmdline: &[u8]) -> Process {
                     core::ptr::write(dst.add(cmdline_start - virt + cmdline.len()), 0);
                 }
             }
-        })
-        .unwrap_or_else(|err| handle_mapping_error(err));
+        })?;
+
+    process.resident_pages += STACK_SIZE / FOUR_KIB;
+    process.regions.insert(Region {
+        start: STACK_POS,
+        length: STACK_SIZE,
+        protection: protection_of(stack_flags),
+        kind: MappingKind::STACK,
+        backing: Backing::Anonymous,
+    });
 
     // The command-line string has been copied at the top of the stack, meaning that the stack
     // starts right after it.
-    process.registers.rsp = cmdline_start & !0xF;
-    process.registers.rbp = cmdline_start & !0xF;
-    process.registers.rdi = cmdline_start;
+    registers.rsp = cmdline_start & !0xF;
+    registers.gprs.rbp = cmdline_start & !0xF;
+    registers.gprs.rdi = cmdline_start;
 
-    process
+    Ok((process, registers))
 }
 
 /// Loads a segment into the process' memory.
-fn load_segment(segment: &elf::Phdr, file: &[u8], process: &mut Process) {
-    let flags = phdr_to_page_flags(segment.flags);
+fn load_segment(segment: &elf::Phdr, file: &[u8], process: &mut Process) -> Result<(), LoadError> {
+    let flags = phdr_to_page_flags(segment.flags)?;
 
     if segment.align != FOUR_KIB as u64 {
-        custom_panic_parse("segment alignment is not 4KiB");
+        return Err(LoadError::Invalid("segment alignment is not 4KiB"));
     }
 
     if segment.vaddr & 0xFFF != segment.offset & 0xFFF {
-        custom_panic_parse("segment virtual address and offset are not aligned");
+        return Err(LoadError::Invalid(
+            "segment virtual address and offset are not aligned",
+        ));
     }
 
     if segment.filesz > segment.memsz {
-        custom_panic_parse("segment file size is larger than its memory size");
+        return Err(LoadError::Invalid(
+            "segment file size is larger than its memory size",
+        ));
     }
 
     if segment.offset.saturating_add(segment.filesz) > file.len() as u64 {
-        custom_panic_parse("segment file size is larger than the file size");
+        return Err(LoadError::Invalid(
+            "segment file size is larger than the file size",
+        ));
+    }
+
+    if segment.vaddr.saturating_add(segment.memsz) > USERLAND_MAPPABLE_END as u64 {
+        return Err(LoadError::Invalid("segment is outside of userland"));
     }
 
     let page_start = page_align_down(segment.vaddr as usize);
@@ -218,8 +224,15 @@ fn load_segment(segment: &elf::Phdr, file: &[u8], process: &mut Process) {
     let virt_to_file = segment.offset.wrapping_sub(segment.vaddr);
 
     process
-        .address_space
-        .allocate_range(page_start, page_end - page_start, flags, |virt, dst| {
+        .regions
+        .reserve(1)
+        .map_err(|_| LoadError::OutOfMemory)?;
+
+    process.address_space.allocate_range(
+        page_start,
+        page_end - page_start,
+        flags,
+        |virt, dst| {
             let mem_start = (segment.vaddr as usize).max(virt);
             let mem_end = (segment.vaddr as usize + segment.memsz as usize).min(virt + FOUR_KIB);
 
@@ -250,19 +263,37 @@ fn load_segment(segment: &elf::Phdr, file: &[u8], process: &mut Process) {
                     zeroed,
                 );
             }
-        })
-        .unwrap_or_else(|err| handle_mapping_error(err));
+        },
+    )?;
+
+    process.resident_pages += (page_end - page_start) / FOUR_KIB;
+    process.regions.insert(Region {
+        start: page_start,
+        length: page_end - page_start,
+        protection: protection_of(flags),
+        kind: MappingKind::IMAGE,
+        backing: Backing::Anonymous,
+    });
+
+    Ok(())
 }
 
 /// Converts an ELF program header flags to page table flags.
-fn phdr_to_page_flags(flags: elf::PhdrFlags) -> PageTableEntry {
-    // FIXME: Figure out why NO_EXECUTE breaks everything.
+///
+/// Segments that are both writable and executable are rejected, as such mappings are never
+/// allowed in userspace.
+fn phdr_to_page_flags(flags: elf::PhdrFlags) -> Result<PageTableEntry, LoadError> {
+    if flags.contains(elf::PhdrFlags::WRITABLE | elf::PhdrFlags::EXECUTABLE) {
+        return Err(LoadError::Invalid(
+            "segment is both writable and executable",
+        ));
+    }
 
-    let mut out = /* PageTableEntry::NO_EXECUTE | */ PageTableEntry::USER_ACCESSIBLE;
+    let mut out = PageTableEntry::NO_EXECUTE | PageTableEntry::USER_ACCESSIBLE;
 
     if !flags.intersects(elf::PhdrFlags::READABLE) {
         log::warn!(
-            "Found a non-readable segment in the init process.\n\
+            "Found a non-readable segment in an ELF file.\n\
             The segment will be readable anyway."
         );
     }
@@ -271,21 +302,9 @@ fn phdr_to_page_flags(flags: elf::PhdrFlags) -> PageTableEntry {
         out.insert(PageTableEntry::WRITABLE);
     }
 
-    // if !flags.intersects(elf::PhdrFlags::EXECUTABLE) {
-    //     out.remove(PageTableEntry::NO_EXECUTE);
-    // }
-
-    out
-}
-
-/// Prints an message indicating that the ELF file is invalid and dies.
-fn panic_parse(err: elf::Error) -> ! {
-    log::trace!("The init process is an invalid ELF file: {:?}", err);
-    die();
-}
+    if flags.intersects(elf::PhdrFlags::EXECUTABLE) {
+        out.remove(PageTableEntry::NO_EXECUTE);
+    }
 
-/// Prints an message indicating that the ELF file is invalid and dies.
-fn custom_panic_parse(err: &str) -> ! {
-    log::trace!("The init process is an invalid ELF file: {}", err);
-    die();
+    Ok(out)
 }
@@ -0,0 +1,179 @@
+//! Delivery of CPU exceptions to the userspace exception handler of a process.
+
+use core::mem::size_of;
+
+use ruel_sys::{Context, Exception, ExceptionContext};
+use x86_64::VirtAddr;
+
+use super::{Process, Thread, UserPtr, USERLAND_STOP};
+use crate::cpu::trap::TrapFrame;
+
+/// The bits of the RFLAGS register that userspace is allowed to modify when resuming a context.
+///
+/// This includes the status flags (CF, PF, AF, ZF, SF, OF) and the direction flag.
+const USER_RFLAGS_MASK: usize = 0xCD5;
+
+/// The stack on which a thread runs the exception handler of its process.
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionStack {
+    /// The top of the stack.
+    pub top: VirtAddr,
+    /// Whether the thread is currently handling an exception on this stack.
+    ///
+    /// This is cleared when the thread calls the `resume_context` system call.
+    pub busy: bool,
+}
+
+impl ExceptionStack {
+    /// Creates a new [`ExceptionStack`] that's not currently used to handle any exception.
+    #[inline]
+    pub fn new(top: VirtAddr) -> Self {
+        Self { top, busy: false }
+    }
+}
+
+impl Process {
+    /// Attempts to deliver the exception described by `frame`, which has been triggered by
+    /// `thread`, to the exception handler of the process.
+    ///
+    /// `fault_address` is the content of the CR2 register, which is only reported for page
+    /// faults.
+    ///
+    /// # Returns
+    ///
+    /// If the exception could be delivered, `frame` is modified such that returning to
+    /// userspace jumps to the handler, and `true` is returned.
+    ///
+    /// Otherwise, `frame` is left untouched and `false` is returned. This happens when the
+    /// exception cannot be handled by userspace, when no handler is registered, when the thread
+    /// has no exception stack or is already handling an exception, or when its exception stack
+    /// is not mapped and writable.
+    pub fn deliver_exception(
+        &self,
+        thread: &mut Thread,
+        frame: &mut TrapFrame,
+        fault_address: VirtAddr,
+    ) -> bool {
+        let exception = Exception::from_raw(frame.vector);
+        if !exception.is_known() {
+            return false;
+        }
+
+        let Some(entry_point) = self.exception_handler else {
+            return false;
+        };
+
+        let stack = match thread.exception_stack {
+            Some(stack) if !stack.busy => stack,
+            _ => return false,
+        };
+
+        let context = ExceptionContext {
+            exception,
+            error_code: frame.error_code,
+            fault_address: if exception == Exception::PAGE_FAULT {
+                fault_address
+            } else {
+                0
+            },
+            context: context_from_frame(frame),
+        };
+
+        let addr = match stack.top.checked_sub(size_of::<ExceptionContext>()) {
+            Some(addr) => addr & !0xF,
+            None => return false,
+        };
+
+        // The address space of the process is loaded, as the exception has just been triggered
+        // by one of its threads. The stack of the handler might not have been touched yet, or
+        // it might still be shared with another process, in which case the write resolves the
+        // resulting page faults.
+        if UserPtr::<ExceptionContext>::new(addr)
+            .write(context)
+            .is_err()
+        {
+            return false;
+        }
+
+        // The handler is entered as if it had been called with a pointer to the context, meaning
+        // that the stack is misaligned by a return address.
+        frame.rip = entry_point;
+        frame.rsp = addr - 8;
+        frame.gprs.rdi = addr;
+        frame.rflags = 0x202;
+
+        thread.exception_stack.as_mut().unwrap().busy = true;
+
+        true
+    }
+}
+
+/// Creates a [`Context`] from the userspace state saved in the provided [`TrapFrame`].
+fn context_from_frame(frame: &TrapFrame) -> Context {
+    Context {
+        rax: frame.gprs.rax,
+        rbx: frame.gprs.rbx,
+        rcx: frame.gprs.rcx,
+        rdx: frame.gprs.rdx,
+        rsi: frame.gprs.rsi,
+        rdi: frame.gprs.rdi,
+        rbp: frame.gprs.rbp,
+        rsp: frame.rsp,
+        r8: frame.gprs.r8,
+        r9: frame.gprs.r9,
+        r10: frame.gprs.r10,
+        r11: frame.gprs.r11,
+        r12: frame.gprs.r12,
+        r13: frame.gprs.r13,
+        r14: frame.gprs.r14,
+        r15: frame.gprs.r15,
+        rip: frame.rip,
+        rflags: frame.rflags,
+        fs_base: frame.fs_base,
+        gs_base: frame.gs_base,
+    }
+}
+
+/// Returns whether the provided [`Context`] can safely be restored in userspace.
+///
+/// The instruction pointer, the stack pointer and the segment bases must all be userland (and
+/// thus canonical) addresses.
+pub fn is_valid_context(context: &Context) -> bool {
+    context.rip <= USERLAND_STOP
+        && context.rsp <= USERLAND_STOP
+        && context.fs_base <= USERLAND_STOP
+        && context.gs_base <= USERLAND_STOP
+}
+
+/// Writes the provided [`Context`] to `frame`, such that returning to userspace with that frame
+/// resumes the context.
+///
+/// Only the bits of RFLAGS that userspace is allowed to modify are taken from the context.
+///
+/// # Remarks
+///
+/// The context must have been validated with [`is_valid_context`] beforehand.
+pub fn restore_context(frame: &mut TrapFrame, context: &Context) {
+    debug_assert!(is_valid_context(context));
+
+    frame.gprs.rax = context.rax;
+    frame.gprs.rbx = context.rbx;
+    frame.gprs.rcx = context.rcx;
+    frame.gprs.rdx = context.rdx;
+    frame.gprs.rsi = context.rsi;
+    frame.gprs.rdi = context.rdi;
+    frame.gprs.rbp = context.rbp;
+    frame.rsp = context.rsp;
+    frame.gprs.r8 = context.r8;
+    frame.gprs.r9 = context.r9;
+    frame.gprs.r10 = context.r10;
+    frame.gprs.r11 = context.r11;
+    frame.gprs.r12 = context.r12;
+    frame.gprs.r13 = context.r13;
+    frame.gprs.r14 = context.r14;
+    frame.gprs.r15 = context.r15;
+    frame.rip = context.rip;
+    frame.rflags = (context.rflags & USER_RFLAGS_MASK) | 0x202;
+    frame.fs_base = context.fs_base;
+    frame.gs_base = context.gs_base;
+}
@@ -1,87 +1,180 @@
 //! This module provides the different structures and functions used to manage running processes.
 
-use core::ptr::NonNull;
+use alloc::vec::Vec;
 
-use ruel_sys::{WakeUp, WakeUpPS2MouseFlags};
-use x86_64::{PageTable, PageTableIndex, PhysAddr, VirtAddr};
+use ruel_sys::{Capabilities, ProcessId, SharedMemoryId, WakeUp};
+use x86_64::{read_cr3, write_cr3, PageTable, PageTableIndex, PhysAddr, VirtAddr};
 
-use crate::cpu::paging::{AddressSpace, AddressSpaceContext, HHDM_OFFSET, KERNEL_BIT};
-use crate::global::{GlobalToken, OutOfMemory};
+use crate::cpu::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::cpu::paging::{AddressSpace, AddressSpaceContext, FOUR_KIB, HHDM_OFFSET, KERNEL_BIT};
+use crate::cpu::trap::{GeneralPurposeRegisters, TrapFrame};
+use crate::global::{GlobalToken, MemoryAllocator, OutOfMemory};
+use crate::log;
+
+mod exception;
+pub use self::exception::*;
 
 mod io_states;
 pub use self::io_states::*;
 
+mod page_fault;
+pub use self::page_fault::*;
+
+mod regions;
+pub use self::regions::*;
+
+mod thread;
+pub use self::thread::*;
+
+mod user_ptr;
+pub use self::user_ptr::*;
+
+#[cfg(feature = "init-elf")]
+mod elf;
+
 /// The last address that is part of userland.
 pub const USERLAND_STOP: VirtAddr = 0x0000_7FFF_FFFF_FFFF;
 
+/// The end of the part of userland in which memory can be mapped.
+///
+/// The last page of userland is never mapped. Otherwise, a `syscall` instruction located at its
+/// very end would require the kernel to return to a non-canonical address, which faults in ring 0
+/// with both `sysretq` and `iretq`.
+pub const USERLAND_MAPPABLE_END: VirtAddr = USERLAND_STOP + 1 - FOUR_KIB;
+
+/// The maximum number of exit records that a process keeps for its children.
+///
+/// Past this limit, the oldest record is dropped to make room for the new one, so that a
+/// process that never waits for its children cannot make the kernel run out of memory.
+pub const MAX_EXITED_CHILDREN: usize = 256;
+
 /// The registers of a paused process.
-#[derive(Debug, Clone, Copy, Default)]
-#[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct Registers {
+    /// The general-purpose registers of the process.
+    pub gprs: GeneralPurposeRegisters,
+    /// The instruction pointer of the process.
     pub rip: usize,
+    /// The stack pointer of the process.
     pub rsp: usize,
-    pub rbp: usize,
-    pub rdi: usize,
+    /// The RFLAGS register of the process.
+    pub rflags: usize,
+    /// The base address of the FS segment of the process.
+    pub fs_base: usize,
+    /// The base address of the GS segment of the process.
+    pub gs_base: usize,
 }
 
-impl Registers {
-    pub const RIP_INDEX: usize = 0;
-    pub const RSP_INDEX: usize = 1;
-    pub const RBP_INDEX: usize = 2;
-    pub const RDI_INDEX: usize = 3;
+impl Default for Registers {
+    fn default() -> Self {
+        Self {
+            gprs: GeneralPurposeRegisters::default(),
+            rip: 0,
+            rsp: 0,
+            // Interrupts must be enabled in userspace. Bit 1 is reserved and always set.
+            rflags: 0x202,
+            fs_base: 0,
+            gs_base: 0,
+        }
+    }
 }
 
-/// A pointer into a process's address space.
-pub struct ProcessPtr<T: ?Sized>(NonNull<T>);
-
-unsafe impl<T: ?Sized + Send> Send for ProcessPtr<T> {}
-unsafe impl<T: ?Sized + Sync> Sync for ProcessPtr<T> {}
-
-impl<T: ?Sized> ProcessPtr<T> {
-    /// Creates a new [`ProcessPtr<T>`] instance.
-    ///
-    /// # Safety
-    ///
-    /// The created [`ProcessPtr<T>`] must be destroyed before the process is.
+impl Registers {
+    /// Saves the userspace state described by the provided [`TrapFrame`].
     #[inline]
-    pub const unsafe fn new(ptr: NonNull<T>) -> Self {
-        Self(ptr)
+    pub fn save(&mut self, frame: &TrapFrame) {
+        debug_assert!(frame.is_user());
+
+        self.gprs = frame.gprs;
+        self.rip = frame.rip;
+        self.rsp = frame.rsp;
+        self.rflags = frame.rflags;
+        self.fs_base = frame.fs_base;
+        self.gs_base = frame.gs_base;
     }
 
-    /// Reads the value from the pointer.
-    ///
-    /// # Remarks
-    ///
-    /// This function can race with the process if it has some other threads running
-    /// concurrently. When that happens, it's undefined behavior. There's no good way to
-    /// solve this, but since the pointer has no provenance, the compiler won't be able to
-    /// optimize it in a way that breaks the program.
-    ///
-    /// What we *do* need to care about, however, is to check the value just before we actually
-    /// read it.
+    /// Writes the saved registers to the provided [`TrapFrame`], such that returning from the
+    /// kernel with that frame resumes the process.
     #[inline]
-    pub fn as_mut(&mut self) -> &mut T {
-        unsafe { self.0.as_mut() }
+    pub fn restore(&self, frame: &mut TrapFrame) {
+        frame.gprs = self.gprs;
+        frame.rip = self.rip;
+        frame.rsp = self.rsp;
+        frame.rflags = self.rflags;
+        frame.fs_base = self.fs_base;
+        frame.gs_base = self.gs_base;
+        frame.cs = USER_CODE_SELECTOR.bits() as usize;
+        frame.ss = USER_DATA_SELECTOR.bits() as usize;
     }
 }
 
-/// When a process is currently waiting for some condition to be met, this type stores which
+/// When a thread is currently waiting for some condition to be met, this type stores which
 /// conditions are being waited on.
 pub struct SleepingState {
-    /// The `WakeUp` instances provided by the process.
-    pub wake_ups: ProcessPtr<[WakeUp]>,
+    /// A copy of the `WakeUp` instances provided by the thread.
+    ///
+    /// The kernel never keeps references to the memory of the process while the thread is
+    /// sleeping, as the process is free to unmap it (or to have it become shared) in the
+    /// meantime.
+    pub wake_ups: Vec<WakeUp>,
+    /// The array that the wake-ups are written back to once the thread has been woken up.
+    pub user_wake_ups: UserSlice<WakeUp>,
+}
+
+/// The exit status of a process, kept by its parent until it is collected.
+///
+/// See [`Process::exited_children`].
+#[derive(Debug, Clone, Copy)]
+pub struct ExitRecord {
+    /// The ID that the process had.
+    pub process_id: ProcessId,
+    /// The exit code of the process.
+    pub exit_code: usize,
 }
 
 /// A process that's running on the system.
+///
+/// A process owns an address space, in which one or more [`Thread`]s are running.
 pub struct Process {
     /// The address space of the process.
     pub address_space: AddressSpace<ASContext>,
-    /// The current state of the process.
-    pub registers: Registers,
     /// The local I/O state reported to the process.
     pub io_states: IoStates,
-    /// The state of the process.
-    pub sleeping: Option<SleepingState>,
+    /// The number of threads currently running within the process.
+    pub thread_count: usize,
+    /// The entry point of the exception handler registered by the process, if any.
+    ///
+    /// Each thread runs the handler on its own stack (see [`Thread::exception_stack`]).
+    pub exception_handler: Option<VirtAddr>,
+    /// The memory regions mapped in the address space of the process.
+    pub regions: Regions,
+    /// The privileged operations that the process is allowed to perform.
+    pub capabilities: Capabilities,
+    /// The shared memory objects that the process is allowed to map.
+    ///
+    /// The IDs of shared memory objects are easy to guess. Knowing one is not enough to map the
+    /// object: the process must have created it, or have been granted access to it by a process
+    /// that already had it.
+    pub shared_memory_grants: Vec<SharedMemoryId>,
+    /// The ID of the process that spawned this one.
+    ///
+    /// This is `ProcessId::MAX` for the init process, and for processes whose parent has
+    /// exited.
+    pub parent: ProcessId,
+    /// The exit status of the children of the process that exited while none of its threads
+    /// was waiting for them.
+    ///
+    /// A record is removed once a thread of the process waits for the child it describes. At
+    /// most [`MAX_EXITED_CHILDREN`] records are kept.
+    pub exited_children: Vec<ExitRecord>,
+    /// The number of 4KiB pages of physical memory mapped in the address space of the process.
+    ///
+    /// This includes memory shared with other processes, as well as device memory. Lazy pages
+    /// are only counted once they have been committed by the page fault handler (see
+    /// [`Processes::record_committed_pages`]).
+    ///
+    /// [`Processes::record_committed_pages`]: crate::global::Processes::record_committed_pages
+    pub resident_pages: usize,
 }
 
 impl Process {
@@ -104,62 +197,161 @@ impl Process {
 
         Ok(Self {
             address_space,
-            registers: Registers::default(),
-            sleeping: None,
             io_states: IoStates::empty(),
+            thread_count: 0,
+            exception_handler: None,
+            regions: Regions::default(),
+            capabilities: Capabilities::empty(),
+            shared_memory_grants: Vec::new(),
+            parent: ProcessId::MAX,
+            exited_children: Vec::new(),
+            resident_pages: 0,
         })
     }
 
-    /// Ticks the process once.
-    pub fn tick(&mut self) {
-        let mut woken_up = false;
-
-        if let Some(sleeping) = &mut self.sleeping {
-            for wake_up in sleeping.wake_ups.as_mut() {
-                match wake_up.tag() {
-                    ruel_sys::WakeUpTag::NOW => {
-                        woken_up = true;
-                    }
-                    ruel_sys::WakeUpTag::PS2_KEYBOARD => {
-                        if self.io_states.ps2_keyboard.total_len() > 0 {
-                            let state = unsafe { &mut wake_up.ps2_keyboard };
-                            state.length = self.io_states.ps2_keyboard.total_len();
-                            self.io_states
-                                .ps2_keyboard
-                                .copy_to_slice(&mut state.scancodes);
-
-                            woken_up = true;
-                        }
-                    }
-                    ruel_sys::WakeUpTag::PS2_MOUSE => {
-                        if self
-                            .io_states
-                            .ps2_mouse_state
-                            .intersects(WakeUpPS2MouseFlags::CHANGED)
-                        {
-                            let state = unsafe { &mut wake_up.ps2_mouse };
-                            state.flags = self.io_states.ps2_mouse_state;
-                            state.dx = self.io_states.ps2_mouse_offset[0];
-                            state.dy = self.io_states.ps2_mouse_offset[1];
-
-                            woken_up = true;
-                        }
-                    }
-                    _ => {
-                        // TODO: properly propagate the error to the process.
-                    }
-                }
-            }
+    /// Records that the child process with the provided ID exited with `exit_code`, for a later
+    /// call to the `sleep` system call to collect it.
+    ///
+    /// If the process already holds [`MAX_EXITED_CHILDREN`] records, the oldest one is dropped.
+    pub fn record_exit(&mut self, process_id: ProcessId, exit_code: usize) {
+        if self.exited_children.len() >= MAX_EXITED_CHILDREN {
+            let dropped = self.exited_children.remove(0);
+            log::warn!(
+                "Dropped the exit code of process {}: too many unclaimed exit records",
+                dropped.process_id
+            );
+        }
+
+        if self.exited_children.try_reserve(1).is_err() {
+            log::warn!(
+                "Failed to record the exit code of process {}: out of memory",
+                process_id
+            );
+            return;
+        }
+
+        self.exited_children.push(ExitRecord {
+            process_id,
+            exit_code,
+        });
+    }
+
+    /// Allows the process to map the shared memory object with the provided ID.
+    pub fn grant_shared_memory(&mut self, id: SharedMemoryId) -> Result<(), OutOfMemory> {
+        if self.shared_memory_grants.contains(&id) {
+            return Ok(());
+        }
+
+        self.shared_memory_grants
+            .try_reserve(1)
+            .map_err(|_| OutOfMemory)?;
+        self.shared_memory_grants.push(id);
+        Ok(())
+    }
+
+    /// Returns whether the process holds the exit record of the provided process.
+    #[inline]
+    pub fn has_exit_record(&self, process_id: ProcessId) -> bool {
+        self.exited_children
+            .iter()
+            .any(|record| record.process_id == process_id)
+    }
+
+    /// Releases the resources owned by the process, which has just been despawned.
+    ///
+    /// If the address space of the process is currently in use, the kernel's address space is
+    /// loaded before it is destroyed.
+    pub fn release(self, glob: GlobalToken, id: ProcessId) {
+        // Release the resources that the process might have owned.
+        glob.framebuffers.release(id);
+
+        // The address space of the process is about to be destroyed. If it's the one we're
+        // currently using, we need to switch back to the kernel's address space first.
+        if read_cr3() & !0xFFF == self.address_space.l4_table() {
+            unsafe { write_cr3(glob.address_space) };
         }
 
-        if woken_up {
-            self.sleeping = None;
+        drop(self);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Regions may reference resources shared with other processes, which must be released
+        // even when the process is dropped before having been spawned.
+        self.release_regions();
+    }
+}
+
+/// A possible file type, used to determine how to load the file into memory.
+enum FileType {
+    /// The type of the file is unknown.
+    Unknown,
+    /// The file is an ELF file.
+    Elf,
+}
+
+impl FileType {
+    /// Attempts to determine the type of the provided file.
+    pub fn of(file: &[u8]) -> Self {
+        if file.starts_with(b"\x7fELF") {
+            Self::Elf
+        } else {
+            Self::Unknown
         }
+    }
+}
+
+/// An error that might occur while loading a process from a file.
+#[derive(Debug, Clone, Copy)]
+pub enum LoadError {
+    /// The kernel ran out of memory while loading the process.
+    OutOfMemory,
+    /// The format of the file could not be determined, or is not supported by the kernel.
+    UnknownFormat,
+    /// The file is invalid, or uses features that are not supported by the kernel.
+    Invalid(&'static str),
+    /// The provided command line is too large to be passed to the process.
+    CommandLineTooLarge,
+}
 
-        self.io_states.clear();
+impl Process {
+    /// Loads a process from the provided file.
+    ///
+    /// The `cmdline` is copied at the top of the stack of the new process, and a pointer to it
+    /// is passed as its first argument.
+    ///
+    /// # Returns
+    ///
+    /// The loaded process, along with the registers its main thread must start with.
+    pub fn load(file: &[u8], cmdline: &[u8]) -> Result<(Self, Registers), LoadError> {
+        match FileType::of(file) {
+            FileType::Elf => self::elf::load(file, cmdline),
+            FileType::Unknown => Err(LoadError::UnknownFormat),
+        }
     }
 }
 
+/// Calls the provided closure with the address space that's currently loaded.
+///
+/// The lock protecting the processes might be held by the caller (for example, a system call
+/// accessing userspace memory), which is why the page tables are accessed through CR3 instead.
+///
+/// # Panics
+///
+/// This function panics if the global state is not initialized.
+fn with_loaded_address_space<R>(f: impl FnOnce(&mut AddressSpace<ASContext>) -> R) -> R {
+    let mut address_space =
+        unsafe { AddressSpace::from_l4_table(ASContext(GlobalToken::get()), read_cr3() & !0xFFF) };
+
+    let ret = f(&mut address_space);
+
+    // The address space is owned by the process.
+    address_space.leak();
+
+    ret
+}
+
 /// The address space context used for processes.
 pub struct ASContext(GlobalToken);
 
@@ -169,11 +361,36 @@ unsafe impl AddressSpaceContext for ASContext {
         self.0.allocator.lock().allocate()
     }
 
+    #[inline]
+    fn allocate_zeroed_page(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        MemoryAllocator::allocate_zeroed(&self.0.allocator)
+    }
+
     #[inline]
     unsafe fn deallocate_page(&mut self, addr: PhysAddr) {
         unsafe { self.0.allocator.lock().deallocate(addr) }
     }
 
+    #[inline]
+    fn allocate_page_table(&mut self) -> Result<PhysAddr, OutOfMemory> {
+        self.0.allocator.lock().allocate_page_table()
+    }
+
+    #[inline]
+    unsafe fn deallocate_page_table(&mut self, addr: PhysAddr) {
+        unsafe { self.0.allocator.lock().deallocate_page_table(addr) }
+    }
+
+    #[inline]
+    unsafe fn share_page(&mut self, addr: PhysAddr) {
+        unsafe { self.0.allocator.lock().share(addr) }
+    }
+
+    #[inline]
+    fn is_page_shared(&self, addr: PhysAddr) -> bool {
+        self.0.allocator.lock().is_shared(addr)
+    }
+
     #[inline]
     unsafe fn physical_to_virtual(&self, addr: PhysAddr) -> x86_64::VirtAddr {
         addr as usize + HHDM_OFFSET
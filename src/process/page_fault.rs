@@ -0,0 +1,49 @@
+//! Resolution of the page faults caused by accesses to userspace memory.
+
+use x86_64::{invlpg, page_align_down, PageFaultError, VirtAddr};
+
+use super::{with_loaded_address_space, USERLAND_STOP};
+use crate::global::{GlobalToken, OutOfMemory};
+
+/// Attempts to resolve a page fault that occured when accessing `address` in the address space
+/// that's currently loaded.
+///
+/// Lazy pages are backed by physical memory, and copy-on-write pages are copied when written
+/// to. This applies both to accesses made by userspace and to accesses made by the kernel on
+/// its behalf.
+///
+/// # Returns
+///
+/// `true` if the access may be attempted again, and `false` if the fault is a genuine access
+/// violation.
+///
+/// # Errors
+///
+/// If the fault could not be resolved because the system is out of memory, [`OutOfMemory`] is
+/// returned.
+pub fn resolve_page_fault(address: VirtAddr, error: PageFaultError) -> Result<bool, OutOfMemory> {
+    // Faults that occur while the kernel is booting are never expected.
+    if address > USERLAND_STOP || !GlobalToken::is_initialized() {
+        return Ok(false);
+    }
+
+    let page = page_align_down(address);
+
+    with_loaded_address_space(|address_space| {
+        if !error.intersects(PageFaultError::PRESENT) {
+            let result = address_space.commit_lazy_page(page);
+            if matches!(result, Ok(true)) {
+                GlobalToken::get().processes.record_committed_pages(1);
+            }
+            result
+        } else if error.intersects(PageFaultError::WRITE) {
+            let result = address_space.copy_on_write(page);
+            if matches!(result, Ok(true)) {
+                invlpg(page);
+            }
+            result
+        } else {
+            Ok(false)
+        }
+    })
+}
@@ -0,0 +1,603 @@
+//! Tracking of the memory regions mapped in the address space of a process.
+
+use alloc::vec::Vec;
+
+use ruel_sys::{MappingKind, MemoryMapping, ProtectionFlags, SharedMemoryId};
+use x86_64::{PageTableEntry, PhysAddr, VirtAddr};
+
+use super::{ASContext, Process, USERLAND_MAPPABLE_END};
+use crate::cpu::paging::{AddressSpace, MappingError, FOUR_KIB, HHDM_OFFSET, NOT_OWNED_BIT};
+use crate::global::{AllocConstraints, GlobalToken, OutOfMemory};
+
+/// The lowest address at which regions are automatically placed.
+///
+/// The first page of the address space is never used, such that dereferencing a null pointer
+/// always faults.
+const PLACEMENT_START: VirtAddr = 0x1000;
+
+/// Describes the physical memory backing a [`Region`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Pages allocated by the kernel on behalf of the process, possibly lazily.
+    ///
+    /// They are released when the region is unmapped.
+    Anonymous,
+    /// A contiguous range of physical memory starting at the provided address, which is not
+    /// owned by the process.
+    ///
+    /// Such regions may be mapped using huge pages, and can therefore not be split.
+    Physical(PhysAddr),
+    /// Physically contiguous pages allocated by the kernel on behalf of the process, starting at
+    /// the provided address.
+    ///
+    /// They are released when the region is unmapped. Because devices might be accessing them,
+    /// they are never moved, and can therefore not be shared with other processes nor split.
+    Dma(PhysAddr),
+    /// The pages of the shared memory object with the provided ID.
+    ///
+    /// Each region counts as a single mapping of the object, and can therefore not be split.
+    Shared(SharedMemoryId),
+}
+
+/// A region of memory mapped in the address space of a process.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// The first address of the region.
+    pub start: VirtAddr,
+    /// The size of the region, in bytes.
+    pub length: usize,
+    /// The protection of the pages of the region.
+    pub protection: ProtectionFlags,
+    /// What the region is used for.
+    pub kind: MappingKind,
+    /// The physical memory backing the region.
+    pub backing: Backing,
+}
+
+impl Region {
+    /// Returns the address right after the last byte of the region.
+    #[inline]
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.length
+    }
+
+    /// Returns whether the region can be split in two.
+    #[inline]
+    fn is_divisible(&self) -> bool {
+        matches!(self.backing, Backing::Anonymous)
+    }
+
+    /// Truncates the region such that it ends at `at`, returning the part that was removed.
+    fn split(&mut self, at: VirtAddr) -> Region {
+        debug_assert!(self.start < at && at < self.end());
+
+        let offset = at - self.start;
+
+        let mut tail = *self;
+        tail.start = at;
+        tail.length = self.length - offset;
+        if let Backing::Physical(phys) = &mut tail.backing {
+            *phys += offset as u64;
+        }
+
+        self.length = offset;
+
+        tail
+    }
+
+    /// Returns the [`MemoryMapping`] describing this region to userspace.
+    #[inline]
+    pub fn to_mapping(self) -> MemoryMapping {
+        MemoryMapping {
+            address: self.start,
+            size: self.length,
+            protection: self.protection,
+            kind: self.kind,
+        }
+    }
+}
+
+/// An error that might occur when operating on the regions of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// Part of the requested range is not covered by any region.
+    NotMapped,
+    /// The requested range only covers part of a region that cannot be split.
+    Indivisible,
+    /// The regions that result from splitting could not be stored.
+    OutOfMemory,
+}
+
+/// The collection of the memory regions of a process, sorted by address.
+///
+/// Regions never overlap.
+///
+/// Storing a region might require allocating memory, which must not happen once the page tables
+/// of the process have been modified. Room for new regions is therefore reserved beforehand
+/// using [`reserve`](Regions::reserve).
+#[derive(Default)]
+pub struct Regions {
+    /// The regions, sorted by their first address.
+    list: Vec<Region>,
+}
+
+impl Regions {
+    /// Returns an iterator over the regions, sorted by address.
+    #[inline]
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &Region> {
+        self.list.iter()
+    }
+
+    /// Returns the index of the first region that starts at or after `addr`.
+    #[inline]
+    fn index_of(&self, addr: VirtAddr) -> usize {
+        self.list.partition_point(|region| region.start < addr)
+    }
+
+    /// Returns the index of the region that contains the provided address, if any.
+    fn containing(&self, addr: VirtAddr) -> Option<usize> {
+        let index = self.list.partition_point(|region| region.start <= addr);
+        index
+            .checked_sub(1)
+            .filter(|&index| self.list[index].end() > addr)
+    }
+
+    /// Ensures that at least `additional` regions can be inserted without allocating memory.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), OutOfMemory> {
+        self.list.try_reserve(additional).map_err(|_| OutOfMemory)
+    }
+
+    /// Attempts to find a free range of `length` bytes in userland.
+    ///
+    /// The lowest suitable address is returned.
+    pub fn find_free(&self, length: usize) -> Option<VirtAddr> {
+        let mut cursor = PLACEMENT_START;
+
+        for region in &self.list {
+            if region.start >= cursor && region.start - cursor >= length {
+                return Some(cursor);
+            }
+
+            cursor = cursor.max(region.end());
+        }
+
+        if USERLAND_MAPPABLE_END.saturating_sub(cursor) >= length {
+            Some(cursor)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the provided range does not overlap with any region.
+    pub fn is_free(&self, start: VirtAddr, length: usize) -> bool {
+        let next = self.list.get(self.index_of(start));
+        self.containing(start).is_none()
+            && !matches!(next, Some(region) if region.start < start + length)
+    }
+
+    /// Inserts a new region.
+    ///
+    /// Room for the region must have been reserved using [`reserve`](Regions::reserve).
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this function panics if the region overlaps with an existing one, or if
+    /// no room was reserved for it.
+    pub fn insert(&mut self, region: Region) {
+        debug_assert!(self.is_free(region.start, region.length));
+        debug_assert!(self.list.len() < self.list.capacity());
+        let index = self.index_of(region.start);
+        self.list.insert(index, region);
+    }
+
+    /// Checks whether the provided range is entirely covered by regions, and whether it can be
+    /// separated from the rest of those regions.
+    pub fn validate(&self, start: VirtAddr, length: usize) -> Result<(), RegionError> {
+        if length == 0 {
+            return Ok(());
+        }
+
+        let end = start.checked_add(length).ok_or(RegionError::NotMapped)?;
+
+        let first = self.containing(start).ok_or(RegionError::NotMapped)?;
+        let mut cursor = self.list[first].start;
+
+        let overlapping = self.list[first..]
+            .iter()
+            .take_while(|region| region.start < end);
+
+        for region in overlapping {
+            if region.start != cursor {
+                return Err(RegionError::NotMapped);
+            }
+
+            if !region.is_divisible() && (region.start < start || region.end() > end) {
+                return Err(RegionError::Indivisible);
+            }
+
+            cursor = region.end();
+        }
+
+        if cursor < end {
+            return Err(RegionError::NotMapped);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the provided range can be separated from the rest of the regions using
+    /// [`validate`](Regions::validate), and reserves room for the regions that splitting them
+    /// creates.
+    pub fn prepare_split(&mut self, start: VirtAddr, length: usize) -> Result<(), RegionError> {
+        self.validate(start, length)?;
+        self.reserve(2).map_err(|_| RegionError::OutOfMemory)
+    }
+
+    /// Splits the region that contains `addr` such that a region starts exactly at `addr`.
+    ///
+    /// Room for the new region must have been reserved.
+    fn split_at(&mut self, addr: VirtAddr) {
+        let index = match self.containing(addr) {
+            Some(index) if self.list[index].start != addr => index,
+            _ => return,
+        };
+
+        debug_assert!(self.list.len() < self.list.capacity());
+        let tail = self.list[index].split(addr);
+        self.list.insert(index + 1, tail);
+    }
+
+    /// Removes the provided range from the regions, splitting them if needed.
+    ///
+    /// # Returns
+    ///
+    /// The parts of the regions that were removed, sorted by address.
+    ///
+    /// # Errors
+    ///
+    /// See [`validate`](Regions::validate). [`RegionError::OutOfMemory`] is returned when the
+    /// regions could not be split. In case of error, nothing is removed.
+    pub fn remove(
+        &mut self,
+        start: VirtAddr,
+        length: usize,
+    ) -> Result<impl Iterator<Item = Region> + '_, RegionError> {
+        self.prepare_split(start, length)?;
+
+        let end = start + length;
+        self.split_at(start);
+        self.split_at(end);
+
+        let first = self.index_of(start);
+        let last = self.index_of(end);
+        Ok(self.list.drain(first..last))
+    }
+
+    /// Changes the protection of the provided range, splitting regions if needed.
+    ///
+    /// Whether the regions were mapped lazily is preserved.
+    ///
+    /// # Errors
+    ///
+    /// See [`validate`](Regions::validate). [`RegionError::OutOfMemory`] is returned when the
+    /// regions could not be split. In case of error, nothing is modified.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        length: usize,
+        protection: ProtectionFlags,
+    ) -> Result<(), RegionError> {
+        self.prepare_split(start, length)?;
+
+        let end = start + length;
+        self.split_at(start);
+        self.split_at(end);
+
+        let first = self.index_of(start);
+        let last = self.index_of(end);
+        for region in &mut self.list[first..last] {
+            region.protection =
+                (protection - ProtectionFlags::LAZY) | (region.protection & ProtectionFlags::LAZY);
+        }
+
+        Ok(())
+    }
+
+    /// Removes all the regions, returning them.
+    #[inline]
+    pub fn drain(&mut self) -> impl Iterator<Item = Region> {
+        core::mem::take(&mut self.list).into_iter()
+    }
+}
+
+impl Process {
+    /// Unmaps the provided range of the address space of the process, releasing the memory that
+    /// backs it.
+    ///
+    /// # Errors
+    ///
+    /// See [`Regions::validate`]. In case of error, nothing is unmapped.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the range.
+    pub fn unmap(&mut self, start: VirtAddr, length: usize) -> Result<(), RegionError> {
+        for region in self.regions.remove(start, length)? {
+            self.resident_pages -= release_region(&mut self.address_space, &region);
+        }
+
+        Ok(())
+    }
+
+    /// Releases all the memory regions of the process.
+    ///
+    /// This is called when the process is dropped.
+    pub fn release_regions(&mut self) {
+        for region in self.regions.drain() {
+            self.resident_pages -= release_region(&mut self.address_space, &region);
+        }
+    }
+
+    /// Creates a new process whose address space is a copy of the address space of this
+    /// process.
+    ///
+    /// Pages are shared between both processes rather than copied. Those that were writable
+    /// become copy-on-write in both processes, and are only copied when one of the processes
+    /// writes to them. Shared memory objects remain shared, while framebuffers, device memory and
+    /// DMA memory are not inherited.
+    ///
+    /// The exception handler, the capabilities and the shared memory grants of the process are
+    /// inherited as well.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for flushing the TLB of this process, as some of its pages
+    /// have been made read-only.
+    pub fn duplicate(&mut self, glob: GlobalToken) -> Result<Process, OutOfMemory> {
+        let mut child = Process::empty(glob)?;
+        child.regions.reserve(self.regions.iter().len())?;
+
+        for region in self.regions.iter() {
+            if let Backing::Physical(_) | Backing::Dma(_) = region.backing {
+                continue;
+            }
+
+            // If this fails, the pages that were already shared are released with the address
+            // space of the child.
+            match self.address_space.share_range(
+                &mut child.address_space,
+                region.start,
+                region.length,
+            ) {
+                Ok(shared) => child.resident_pages += shared,
+                Err(err) => {
+                    debug_assert!(matches!(err, MappingError::OutOfMemory));
+                    return Err(OutOfMemory);
+                }
+            }
+
+            if let Backing::Shared(id) = region.backing {
+                glob.shared_memory.retain(id);
+            }
+
+            child.regions.insert(*region);
+        }
+
+        child
+            .shared_memory_grants
+            .try_reserve_exact(self.shared_memory_grants.len())
+            .map_err(|_| OutOfMemory)?;
+        child
+            .shared_memory_grants
+            .extend_from_slice(&self.shared_memory_grants);
+
+        child.exception_handler = self.exception_handler;
+        child.capabilities = self.capabilities;
+
+        Ok(child)
+    }
+
+    /// Maps the provided range of device memory in the address space of the process, at an
+    /// address chosen by the kernel.
+    ///
+    /// `cache` contains the page table flags selecting the memory type of the pages.
+    ///
+    /// # Errors
+    ///
+    /// If the address space has no room left for the range, or if a page table could not be
+    /// allocated, nothing is mapped and [`OutOfMemory`] is returned.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the mapped range.
+    pub fn map_device(
+        &mut self,
+        phys: PhysAddr,
+        length: usize,
+        protection: ProtectionFlags,
+        cache: PageTableEntry,
+    ) -> Result<VirtAddr, OutOfMemory> {
+        let start = self.regions.find_free(length).ok_or(OutOfMemory)?;
+        let flags = page_flags_of(protection) | cache | NOT_OWNED_BIT;
+        self.regions.reserve(1)?;
+
+        if let Err(err) = self.address_space.map_range(start, phys, length, flags) {
+            debug_assert!(matches!(err, MappingError::OutOfMemory));
+            // The pages are mapped in order, meaning that this stops at the first page that
+            // wasn't mapped.
+            let _ = self.address_space.unmap_range(start, length);
+            return Err(OutOfMemory);
+        }
+
+        self.regions.insert(Region {
+            start,
+            length,
+            protection: protection - ProtectionFlags::LAZY,
+            kind: MappingKind::DEVICE,
+            backing: Backing::Physical(phys),
+        });
+        self.resident_pages += length / FOUR_KIB;
+
+        Ok(start)
+    }
+
+    /// Allocates `length` bytes of physically contiguous memory and maps it in the address space
+    /// of the process, at an address chosen by the kernel.
+    ///
+    /// The allocated memory is zeroed.
+    ///
+    /// # Returns
+    ///
+    /// The virtual address at which the memory was mapped, along with its physical address.
+    ///
+    /// # Errors
+    ///
+    /// If no physical memory respects the provided constraints, if the address space has no
+    /// room left for the memory, or if a page table could not be allocated, nothing is
+    /// allocated and [`OutOfMemory`] is returned.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the mapped range.
+    pub fn allocate_dma(
+        &mut self,
+        length: usize,
+        protection: ProtectionFlags,
+        constraints: AllocConstraints,
+    ) -> Result<(VirtAddr, PhysAddr), OutOfMemory> {
+        let start = self.regions.find_free(length).ok_or(OutOfMemory)?;
+        let flags = page_flags_of(protection);
+        let count = length / FOUR_KIB;
+        self.regions.reserve(1)?;
+
+        let glob = GlobalToken::get();
+        let phys = glob
+            .allocator
+            .lock()
+            .allocate_contiguous(count, constraints)?;
+
+        unsafe { core::ptr::write_bytes((phys as usize + HHDM_OFFSET) as *mut u8, 0, length) };
+
+        for i in 0..count {
+            let page = phys + (i * FOUR_KIB) as PhysAddr;
+
+            if let Err(err) = self
+                .address_space
+                .map_4kib(start + i * FOUR_KIB, page, flags)
+            {
+                debug_assert!(matches!(err, MappingError::OutOfMemory));
+
+                // The pages that were mapped are released along with their mapping.
+                let _ = self.address_space.unmap_range(start, i * FOUR_KIB);
+
+                let mut allocator = glob.allocator.lock();
+                for j in i..count {
+                    unsafe { allocator.deallocate(phys + (j * FOUR_KIB) as PhysAddr) };
+                }
+
+                return Err(OutOfMemory);
+            }
+        }
+
+        self.regions.insert(Region {
+            start,
+            length,
+            protection: protection - ProtectionFlags::LAZY,
+            kind: MappingKind::DMA,
+            backing: Backing::Dma(phys),
+        });
+        self.resident_pages += count;
+
+        Ok((start, phys))
+    }
+
+    /// Maps the pages of the shared memory object `id` in the address space of the process, at
+    /// an address chosen by the kernel.
+    ///
+    /// # Errors
+    ///
+    /// If the address space has no room left for the object, or if a page table could not be
+    /// allocated, nothing is mapped and [`OutOfMemory`] is returned. In that case, the caller
+    /// remains responsible for releasing its mapping of the object.
+    ///
+    /// # Remarks
+    ///
+    /// The caller is responsible for invalidating the TLB entries of the mapped range.
+    pub fn map_shared(
+        &mut self,
+        id: SharedMemoryId,
+        pages: &[PhysAddr],
+        protection: ProtectionFlags,
+    ) -> Result<VirtAddr, OutOfMemory> {
+        let length = pages.len() * FOUR_KIB;
+        let start = self.regions.find_free(length).ok_or(OutOfMemory)?;
+        let flags = page_flags_of(protection) | NOT_OWNED_BIT;
+        self.regions.reserve(1)?;
+
+        for (i, &page) in pages.iter().enumerate() {
+            if let Err(err) = self
+                .address_space
+                .map_4kib(start + i * FOUR_KIB, page, flags)
+            {
+                debug_assert!(matches!(err, MappingError::OutOfMemory));
+                let _ = self.address_space.unmap_range(start, i * FOUR_KIB);
+                return Err(OutOfMemory);
+            }
+        }
+
+        self.regions.insert(Region {
+            start,
+            length,
+            protection: protection - ProtectionFlags::LAZY,
+            kind: MappingKind::SHARED,
+            backing: Backing::Shared(id),
+        });
+        self.resident_pages += pages.len();
+
+        Ok(start)
+    }
+}
+
+/// Unmaps the pages of a region that has been removed from the regions of a process.
+///
+/// # Returns
+///
+/// The number of pages of physical memory that were mapped in the region.
+fn release_region(address_space: &mut AddressSpace<ASContext>, region: &Region) -> usize {
+    // Pages not owned by the process are marked as such in the page tables, meaning that they are
+    // not released.
+    let ret = address_space.unmap_range(region.start, region.length);
+    debug_assert!(ret.is_ok(), "the pages of a region were not mapped");
+
+    if let Backing::Shared(id) = region.backing {
+        let glob = GlobalToken::get();
+        glob.shared_memory.release(&glob.allocator, id);
+    }
+
+    ret.unwrap_or(0)
+}
+
+/// Returns the page table flags implementing the provided [`ProtectionFlags`] for userspace
+/// pages.
+pub fn page_flags_of(protection: ProtectionFlags) -> PageTableEntry {
+    let mut flags = PageTableEntry::USER_ACCESSIBLE;
+    if protection.intersects(ProtectionFlags::WRITE) {
+        flags.insert(PageTableEntry::WRITABLE);
+    }
+    if !protection.intersects(ProtectionFlags::EXECUTE) {
+        flags.insert(PageTableEntry::NO_EXECUTE);
+    }
+    flags
+}
+
+/// Returns the [`ProtectionFlags`] implemented by the provided userspace page table flags.
+pub fn protection_of(flags: PageTableEntry) -> ProtectionFlags {
+    let mut protection = ProtectionFlags::READ;
+    if flags.intersects(PageTableEntry::WRITABLE) {
+        protection.insert(ProtectionFlags::WRITE);
+    }
+    if !flags.intersects(PageTableEntry::NO_EXECUTE) {
+        protection.insert(ProtectionFlags::EXECUTE);
+    }
+    protection
+}
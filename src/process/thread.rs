@@ -0,0 +1,174 @@
+//! Threads of execution running within a process.
+
+use core::sync::atomic::Ordering::Relaxed;
+
+use ruel_sys::{ProcessId, SysResult, WakeUpPS2MouseFlags};
+
+use super::{ExceptionStack, IoStates, Registers, SleepingState};
+use crate::cpu::idt::pit::interval_ns;
+use crate::cpu::trap::TrapFrame;
+use crate::global::GlobalToken;
+
+/// A thread of execution, running within the address space of a process.
+///
+/// Threads are the unit of scheduling of the kernel. All the threads of a process share the same
+/// address space, but each of them has its own registers and may be sleeping independently of
+/// the others.
+pub struct Thread {
+    /// The ID of the process that the thread belongs to.
+    pub process: ProcessId,
+    /// The current state of the thread.
+    pub registers: Registers,
+    /// When the thread is waiting for some conditions to be met, this stores which conditions
+    /// are being waited on.
+    pub sleeping: Option<SleepingState>,
+    /// The state of the last sleep of the thread, once it has been woken up but before the
+    /// wake-ups have been written back to the memory of its process.
+    ///
+    /// See [`Thread::write_back_wake_ups`].
+    pub woken_up: Option<SleepingState>,
+    /// The stack on which the thread runs the exception handler of its process, if it has
+    /// registered one.
+    ///
+    /// This state is specific to the thread, such that several threads can handle exceptions
+    /// at the same time, and it goes away with the thread.
+    pub exception_stack: Option<ExceptionStack>,
+}
+
+impl Thread {
+    /// Creates a new [`Thread`] that belongs to the provided process and starts executing with
+    /// the provided registers.
+    #[inline]
+    pub fn new(process: ProcessId, registers: Registers) -> Self {
+        Self {
+            process,
+            registers,
+            sleeping: None,
+            woken_up: None,
+            exception_stack: None,
+        }
+    }
+
+    /// Returns whether the thread is ready to run.
+    #[inline]
+    pub fn is_runnable(&self) -> bool {
+        self.sleeping.is_none()
+    }
+
+    /// Ticks the thread once.
+    ///
+    /// `io_states` is the local I/O state of the process that the thread belongs to.
+    pub fn tick(&mut self, io_states: &IoStates) {
+        let upticks = GlobalToken::get().upticks.load(Relaxed);
+        let mut woken_up = false;
+
+        if let Some(sleeping) = &mut self.sleeping {
+            for wake_up in &mut sleeping.wake_ups {
+                let triggered = match wake_up.tag() {
+                    ruel_sys::WakeUpTag::NOW => true,
+                    ruel_sys::WakeUpTag::PS2_KEYBOARD => {
+                        if io_states.ps2_keyboard.total_len() > 0 {
+                            let state = unsafe { &mut wake_up.ps2_keyboard };
+                            state.length = io_states.ps2_keyboard.total_len();
+                            io_states.ps2_keyboard.copy_to_slice(&mut state.scancodes);
+
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    ruel_sys::WakeUpTag::PS2_MOUSE => {
+                        if io_states
+                            .ps2_mouse_state
+                            .intersects(WakeUpPS2MouseFlags::CHANGED)
+                        {
+                            let state = unsafe { &mut wake_up.ps2_mouse };
+                            state.flags = io_states.ps2_mouse_state;
+                            state.dx = io_states.ps2_mouse_offset[0];
+                            state.dy = io_states.ps2_mouse_offset[1];
+
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    ruel_sys::WakeUpTag::DEADLINE => {
+                        let state = unsafe { &wake_up.deadline };
+                        let now = match state.unit {
+                            ruel_sys::DeadlineUnit::NANOSECONDS => {
+                                upticks.saturating_mul(interval_ns() as u64)
+                            }
+                            _ => upticks,
+                        };
+
+                        now >= state.deadline
+                    }
+                    _ => {
+                        // `PROCESS_EXIT` wake-ups are handled by `notify_process_exit` when the
+                        // process they reference is despawned. Invalid wake-ups have already
+                        // been rejected by the `sleep` system call.
+                        false
+                    }
+                };
+
+                if triggered {
+                    wake_up.set_triggered(true);
+                    woken_up = true;
+                }
+            }
+        }
+
+        if woken_up {
+            self.woken_up = self.sleeping.take();
+        }
+    }
+
+    /// Notifies the thread that the process with the provided ID has been despawned with the
+    /// given exit code.
+    ///
+    /// If the thread was waiting for that process to terminate, it is woken up.
+    ///
+    /// # Returns
+    ///
+    /// Whether the thread has been woken up by this call.
+    pub fn notify_process_exit(&mut self, process_id: ProcessId, exit_code: usize) -> bool {
+        let mut woken_up = false;
+
+        if let Some(sleeping) = &mut self.sleeping {
+            for wake_up in &mut sleeping.wake_ups {
+                if wake_up.tag() != ruel_sys::WakeUpTag::PROCESS_EXIT {
+                    continue;
+                }
+
+                let state = unsafe { &mut wake_up.process_exit };
+                if state.process_id == process_id {
+                    state.exit_code = exit_code;
+                    wake_up.set_triggered(true);
+                    woken_up = true;
+                }
+            }
+        }
+
+        if woken_up {
+            self.woken_up = self.sleeping.take();
+        }
+
+        woken_up
+    }
+
+    /// Writes the wake-ups of the last sleep of the thread back to the memory of its process,
+    /// if it has been woken up since.
+    ///
+    /// The address space of the process must be loaded. `frame` is the state the thread is
+    /// about to resume with: if the wake-ups cannot be written, the `sleep` system call fails
+    /// with [`SysResult::INVALID_POINTER`].
+    pub fn write_back_wake_ups(&mut self, frame: &mut TrapFrame) {
+        let Some(state) = self.woken_up.take() else {
+            return;
+        };
+
+        if state.user_wake_ups.write_from(&state.wake_ups).is_err() {
+            frame.gprs.rax = SysResult::INVALID_POINTER.as_raw();
+        }
+    }
+}
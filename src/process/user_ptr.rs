@@ -0,0 +1,311 @@
+//! Access to the memory of the current process on behalf of system calls.
+
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::mem::{size_of, size_of_val, MaybeUninit};
+
+use ruel_sys::{Context, WakeUp};
+use x86_64::{clac, page_align_down, stac, VirtAddr};
+
+use super::{with_loaded_address_space, USERLAND_STOP};
+use crate::cpu::paging::FOUR_KIB;
+use crate::cpu::protection::smap_enabled;
+use crate::cpu::trap::TrapFrame;
+
+/// An error returned when userspace memory cannot be accessed through a [`UserPtr<T>`] or a
+/// [`UserSlice<T>`].
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidPointer;
+
+/// A type that can be read from userspace memory.
+///
+/// # Safety
+///
+/// Any bit pattern must be a valid instance of the type, as userspace is free to write anything
+/// to its memory.
+pub unsafe trait UserValue: Copy {}
+
+unsafe impl UserValue for u8 {}
+unsafe impl UserValue for usize {}
+unsafe impl UserValue for Context {}
+unsafe impl UserValue for WakeUp {}
+
+/// A pointer to a `T` in the address space of the current process.
+///
+/// Nothing is assumed about the pointer when it is created. Every access checks that the memory
+/// is part of userland and mapped with the required permissions, and recovers from the faults
+/// that might still occur if the process modifies its address space concurrently.
+pub struct UserPtr<T> {
+    addr: VirtAddr,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+// SAFETY:
+//  The pointer is only an address in userspace, which is always accessed through checked copies.
+unsafe impl<T> Send for UserPtr<T> {}
+unsafe impl<T> Sync for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    /// Creates a new [`UserPtr<T>`] from the provided address.
+    #[inline]
+    pub fn new(addr: VirtAddr) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns whether the pointer is null.
+    ///
+    /// Null pointers are used by system calls to indicate that an optional output is not
+    /// requested.
+    #[inline]
+    pub fn is_null(self) -> bool {
+        self.addr == 0
+    }
+
+    /// Reads the value referenced by the pointer.
+    ///
+    /// The pointer does not need to be aligned.
+    pub fn read(self) -> Result<T, InvalidPointer>
+    where
+        T: UserValue,
+    {
+        check_range(self.addr, size_of::<T>(), false)?;
+
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            copy_bytes(
+                value.as_mut_ptr() as *mut u8,
+                self.addr as *const u8,
+                size_of::<T>(),
+            )?;
+            Ok(value.assume_init())
+        }
+    }
+
+    /// Checks whether the pointer references memory that the kernel is allowed to write to.
+    ///
+    /// This is used to validate output pointers before performing an operation that would be
+    /// hard to undo. Writing to the pointer might still fail if the process modifies its
+    /// address space in the meantime.
+    #[inline]
+    pub fn check_writable(self) -> Result<(), InvalidPointer> {
+        check_range(self.addr, size_of::<T>(), true)
+    }
+
+    /// Writes the provided value to the memory referenced by the pointer.
+    ///
+    /// The pointer does not need to be aligned.
+    pub fn write(self, value: T) -> Result<(), InvalidPointer> {
+        check_range(self.addr, size_of::<T>(), true)?;
+
+        unsafe {
+            copy_bytes(
+                self.addr as *mut u8,
+                &value as *const T as *const u8,
+                size_of::<T>(),
+            )
+        }
+    }
+}
+
+/// A pointer to `len` contiguous instances of `T` in the address space of the current process.
+///
+/// See [`UserPtr<T>`] for more information.
+pub struct UserSlice<T> {
+    addr: VirtAddr,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserSlice<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserSlice<T> {}
+
+// SAFETY:
+//  Same as for `UserPtr<T>`.
+unsafe impl<T> Send for UserSlice<T> {}
+unsafe impl<T> Sync for UserSlice<T> {}
+
+impl<T> UserSlice<T> {
+    /// Creates a new [`UserSlice<T>`] from the provided address and number of elements.
+    #[inline]
+    pub fn new(addr: VirtAddr, len: usize) -> Self {
+        Self {
+            addr,
+            len,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of elements in the slice.
+    #[inline]
+    pub fn len(self) -> usize {
+        self.len
+    }
+
+    /// Returns a pointer to the element at the provided index, or [`None`] if the index is out
+    /// of bounds.
+    #[inline]
+    pub fn get(self, index: usize) -> Option<UserPtr<T>> {
+        if index < self.len {
+            Some(UserPtr::new(self.addr.wrapping_add(index * size_of::<T>())))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the slice, in bytes.
+    #[inline]
+    fn byte_len(self) -> Result<usize, InvalidPointer> {
+        self.len.checked_mul(size_of::<T>()).ok_or(InvalidPointer)
+    }
+
+    /// Copies the elements of the slice into `dst`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `dst` does not have the same length as the slice.
+    pub fn read_into(self, dst: &mut [MaybeUninit<T>]) -> Result<(), InvalidPointer>
+    where
+        T: UserValue,
+    {
+        assert_eq!(dst.len(), self.len, "mismatched slice lengths");
+
+        let len = self.byte_len()?;
+        check_range(self.addr, len, false)?;
+
+        unsafe { copy_bytes(dst.as_mut_ptr() as *mut u8, self.addr as *const u8, len) }
+    }
+
+    /// Copies the elements of `src` to the beginning of the slice.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `src` is longer than the slice.
+    pub fn write_from(self, src: &[T]) -> Result<(), InvalidPointer> {
+        assert!(src.len() <= self.len, "source slice is too long");
+
+        let len = size_of_val(src);
+        check_range(self.addr, len, true)?;
+
+        unsafe { copy_bytes(self.addr as *mut u8, src.as_ptr() as *const u8, len) }
+    }
+}
+
+/// Checks whether the current process allows the kernel to access the `len` bytes starting at
+/// `addr`, and to write to them if `write` is set.
+fn check_range(addr: VirtAddr, len: usize, write: bool) -> Result<(), InvalidPointer> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= USERLAND_STOP + 1 => end,
+        _ => return Err(InvalidPointer),
+    };
+
+    with_loaded_address_space(|address_space| {
+        let mut page = page_align_down(addr);
+        while page < end {
+            if !address_space.is_user_accessible(page, write) {
+                return Err(InvalidPointer);
+            }
+            page += FOUR_KIB;
+        }
+
+        Ok(())
+    })
+}
+
+/// Copies `len` bytes from `src` to `dst`, one of them being userspace memory.
+///
+/// # Errors
+///
+/// If a page fault that could not be resolved occurs during the copy, it is interrupted and
+/// [`InvalidPointer`] is returned. In that case, part of the bytes might have been copied
+/// already.
+///
+/// # Safety
+///
+/// The kernel memory must be valid for the copy, and the userspace memory must have been
+/// checked with [`check_range`].
+#[inline]
+unsafe fn copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> Result<(), InvalidPointer> {
+    // When SMAP is enabled, userspace memory can only be accessed while the AC flag is set.
+    let smap = smap_enabled();
+
+    unsafe {
+        if smap {
+            stac();
+        }
+
+        let remaining = raw_copy(dst, src, 0, len);
+
+        if smap {
+            clac();
+        }
+
+        match remaining {
+            0 => Ok(()),
+            _ => Err(InvalidPointer),
+        }
+    }
+}
+
+/// Copies `len` bytes from `src` to `dst`.
+///
+/// The copy is performed by the very first instruction of the function, which is how
+/// [`recover_user_access`] recognizes the faults it causes. The third parameter is unused, and
+/// only ensures that `len` is passed in the `rcx` register.
+///
+/// # Returns
+///
+/// The number of bytes that were not copied because of a page fault.
+#[naked]
+unsafe extern "C" fn raw_copy(dst: *mut u8, src: *const u8, _: usize, len: usize) -> usize {
+    unsafe {
+        asm!("rep movsb", "xor eax, eax", "ret", options(noreturn));
+    }
+}
+
+/// Returns from [`raw_copy`] after it has been interrupted by a page fault.
+///
+/// `rcx` still contains the number of bytes that were not copied.
+#[naked]
+unsafe extern "C" fn raw_copy_fault() -> usize {
+    unsafe {
+        asm!("mov rax, rcx", "ret", options(noreturn));
+    }
+}
+
+/// Attempts to recover from a page fault that occured in the kernel while it was accessing
+/// userspace memory.
+///
+/// # Returns
+///
+/// `true` if the fault was caused by such an access, in which case `frame` has been modified
+/// such that the access is aborted. Otherwise, `false` is returned.
+pub fn recover_user_access(frame: &mut TrapFrame) -> bool {
+    if frame.rip != raw_copy as usize {
+        return false;
+    }
+
+    frame.rip = raw_copy_fault as usize;
+    true
+}
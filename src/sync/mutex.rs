@@ -1,4 +1,5 @@
 use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
@@ -84,29 +85,44 @@ pub struct MutexGuard<'a, T: ?Sized> {
 impl<'a, T> MutexGuard<'a, T> {
     /// Maps the inner value to a new value.
     pub fn map<U>(self, f: impl FnOnce(&mut T) -> &mut U) -> MutexGuard<'a, U> {
-        let without_interrupts = unsafe { core::ptr::read(&self.without_interrupts) };
-        let value = unsafe { core::ptr::read(&self.value) };
+        // The lock is handed over to the new guard, so this one must not release it.
+        let mut this = ManuallyDrop::new(self);
+        let without_interrupts = this.without_interrupts.take();
+        let value = unsafe { core::ptr::read(&this.value) };
 
         MutexGuard {
-            locked: self.locked,
+            locked: this.locked,
             without_interrupts,
             value: f(value),
         }
     }
 
     /// Maps the inner value to a new value, returning an error if the closure returns an error.
+    ///
+    /// In case of error, the lock is released.
     pub fn try_map<U, E>(
         self,
         f: impl FnOnce(&mut T) -> Result<&mut U, E>,
     ) -> Result<MutexGuard<'a, U>, E> {
-        let without_interrupts = unsafe { core::ptr::read(&self.without_interrupts) };
-        let value = unsafe { core::ptr::read(&self.value) };
-
-        Ok(MutexGuard {
-            locked: self.locked,
-            without_interrupts,
-            value: f(value)?,
-        })
+        // The lock is handed over to the new guard, so this one must not release it.
+        let mut this = ManuallyDrop::new(self);
+        let without_interrupts = this.without_interrupts.take();
+        let value = unsafe { core::ptr::read(&this.value) };
+
+        match f(value) {
+            Ok(value) => Ok(MutexGuard {
+                locked: this.locked,
+                without_interrupts,
+                value,
+            }),
+            Err(err) => {
+                // Same order as when the guard is dropped: the interrupts are only restored
+                // once the lock has been released.
+                this.locked.store(false, Release);
+                drop(without_interrupts);
+                Err(err)
+            }
+        }
     }
 }
 
@@ -9,8 +9,17 @@ pub struct StableFixedVec<T: 'static> {
     /// The array of elements.
     array: &'static mut [Slot<T>],
 
-    /// The index of the first free element in the array.
-    next_free: usize,
+    /// The index at which the search for a free element starts.
+    ///
+    /// This is one past the index of the last element pushed into the array. Freed elements are
+    /// only reused once the search wraps around the end of the array, ensuring that an index
+    /// is not handed out again right after being removed.
+    next: usize,
+
+    /// One past the index of the last occupied element in the array.
+    ///
+    /// Elements past this index are all free.
+    end: usize,
 }
 
 impl<T> StableFixedVec<T> {
@@ -23,23 +32,40 @@ impl<T> StableFixedVec<T> {
 
         Ok(Self {
             array: crate::utility::init_slice_with(array, |_| Slot::empty()),
-            next_free: 0,
+            next: 0,
+            end: 0,
         })
     }
 
     /// Pushes a new value into the vector, returning the index assigned to it.
     pub fn push(&mut self, value: T) -> Result<usize, T> {
-        if let Some(slot) = self.array.get_mut(self.next_free) {
-            debug_assert!(!slot.is_present());
-            slot.write_unchecked(value);
-            let index = self.next_free;
-            while self.array.get(self.next_free).is_some_and(Slot::is_present) {
-                self.next_free += 1;
-            }
-            Ok(index)
-        } else {
-            Err(value)
+        let len = self.array.len();
+        let start = self.next.min(len);
+
+        let Some(index) = (start..len)
+            .chain(0..start)
+            .find(|&i| !self.array[i].is_present())
+        else {
+            return Err(value);
+        };
+
+        self.array[index].write_unchecked(value);
+        self.next = index + 1;
+        self.end = self.end.max(index + 1);
+        Ok(index)
+    }
+
+    /// Removes the element at the given index, returning it.
+    ///
+    /// If the index was not occupied, [`None`] is returned.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let value = self.array.get_mut(index)?.take()?;
+
+        while self.end > 0 && !self.array[self.end - 1].is_present() {
+            self.end -= 1;
         }
+
+        Some(value)
     }
 
     /// Returns whether the given index is currently occupied by an element.
@@ -55,6 +81,13 @@ impl<T> StableFixedVec<T> {
         unsafe { self.array.get_unchecked_mut(index).read_unchecked_mut() }
     }
 
+    /// Returns the value at the given index, checking whether that entry is currently occupied or
+    /// not.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.array.get(index).and_then(Slot::read)
+    }
+
     /// Returns the value at the given index, checking whether that entry is currently occupied or
     /// not.
     #[inline]
@@ -62,11 +95,38 @@ impl<T> StableFixedVec<T> {
         self.array.get_mut(index).and_then(Slot::read_mut)
     }
 
+    /// Removes all the elements for which the provided predicate returns `false`.
+    pub fn retain(&mut self, mut predicate: impl FnMut(&mut T) -> bool) {
+        for index in 0..self.end {
+            if self.array[index]
+                .read_mut()
+                .is_some_and(|val| !predicate(val))
+            {
+                self.remove(index);
+            }
+        }
+    }
+
+    /// Returns the index of the first element that comes after `index` and matches the
+    /// provided predicate.
+    ///
+    /// The search wraps around the end of the vector, meaning that the element at `index` itself
+    /// is checked last. If `index` is out of bounds, the search starts at the beginning of the
+    /// vector.
+    pub fn find_next(&self, index: usize, mut predicate: impl FnMut(&T) -> bool) -> Option<usize> {
+        let len = self.end;
+        let start = if index < len { index + 1 } else { 0 };
+
+        (start..len)
+            .chain(0..start)
+            .find(|&i| self.array[i].read().is_some_and(&mut predicate))
+    }
+
     /// Returns an iterator over the values of the vector.
     #[inline]
     pub fn iter(&self) -> Iter<T> {
         let maybe_init_slots =
-            unsafe { core::slice::from_raw_parts(self.array.as_ptr(), self.next_free) };
+            unsafe { core::slice::from_raw_parts(self.array.as_ptr(), self.end) };
         Iter(maybe_init_slots.iter())
     }
 
@@ -74,7 +134,7 @@ impl<T> StableFixedVec<T> {
     #[inline]
     pub fn iter_mut(&mut self) -> IterMut<T> {
         let maybe_init_slots =
-            unsafe { core::slice::from_raw_parts_mut(self.array.as_mut_ptr(), self.next_free) };
+            unsafe { core::slice::from_raw_parts_mut(self.array.as_mut_ptr(), self.end) };
         IterMut(maybe_init_slots.iter_mut())
     }
 }
@@ -118,6 +178,17 @@ impl<T> Slot<T> {
         self.present = true;
     }
 
+    /// Takes the value out of the slot, leaving it empty.
+    #[inline]
+    pub fn take(&mut self) -> Option<T> {
+        if self.present {
+            self.present = false;
+            Some(unsafe { self.val.assume_init_read() })
+        } else {
+            None
+        }
+    }
+
     /// Reads the value in the slot without checking whether the slot is currently occupied.
     ///
     /// # Safety